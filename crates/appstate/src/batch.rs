@@ -0,0 +1,57 @@
+// Broadcast batching for canvas operations (canvas.broadcast_batch_ms)
+// Messages queue up per room and go out together once the window is over,
+// the webserver decides when to flush. Pushing happens under the room's order
+// lock, so each queue is already in sequence order
+use crate::{ConnectionId, RoomId};
+use protocol::messages::ServerMessage;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Who in the room a queued message is for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recipients {
+    All,
+    // Everyone but this connection, e.g. the one that drew
+    AllBut(ConnectionId),
+    // Just this connection, e.g. the confirmation of its own draw
+    Only(ConnectionId),
+}
+
+impl Recipients {
+    pub fn includes(self, id: ConnectionId) -> bool {
+        match self {
+            Recipients::All => true,
+            Recipients::AllBut(except) => except != id,
+            Recipients::Only(only) => only == id,
+        }
+    }
+}
+
+// A message waiting to go out, and who it's for
+pub type PendingOp = (ServerMessage, Recipients);
+
+#[derive(Clone, Default)]
+pub struct OpBatcher {
+    pending: Arc<Mutex<HashMap<RoomId, Vec<PendingOp>>>>,
+}
+
+impl OpBatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Queue a message, true if the room's queue was empty
+    // (then it's on the caller to schedule the flush)
+    pub async fn push(&self, room: &RoomId, message: ServerMessage, to: Recipients) -> bool {
+        let mut pending = self.pending.lock().await;
+        let queue = pending.entry(room.clone()).or_default();
+        queue.push((message, to));
+        queue.len() == 1
+    }
+
+    // Everything queued for a room, in the order it was pushed
+    pub async fn take(&self, room: &RoomId) -> Vec<PendingOp> {
+        self.pending.lock().await.remove(room).unwrap_or_default()
+    }
+}
@@ -0,0 +1,626 @@
+// In-memory canvas for every room that's in use, plus the autosave that keeps
+// it in the database. Rooms are loaded from their snapshot on first join and
+// dropped from memory again once they're empty and saved.
+use crate::AppState;
+use crate::websocket::{ConnectionId, RoomId};
+use db::{DatabaseConnection, DbError};
+use protocol::messages::{CanvasObject, RoomOp};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::interval;
+use tracing::*;
+
+// Things that can go wrong moving a canvas in or out of the database
+#[derive(Debug)]
+pub enum SnapshotError {
+    Db(DbError),
+    Corrupt(serde_json::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Db(e) => write!(f, "{}", e),
+            SnapshotError::Corrupt(e) => write!(f, "Stored canvas snapshot is corrupt: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DbError> for SnapshotError {
+    fn from(e: DbError) -> Self {
+        SnapshotError::Db(e)
+    }
+}
+
+// Why an object couldn't be added to a room
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddObjectError {
+    NotLoaded,
+    // The client drew on top of an older state, `current` is where the room is now
+    StaleBase { current: u64 },
+}
+
+// Why an undo or redo couldn't be done
+#[derive(Debug, PartialEq, Eq)]
+pub enum UndoError {
+    NotLoaded,
+    NothingToUndo,
+    NothingToRedo,
+    // Not in the history, either it never existed or it has settled
+    UnknownOp(u64),
+    // Drawn by another connection, or before the room was last loaded
+    NotAuthor(u64),
+    AlreadyUndone(u64),
+    NotUndone(u64),
+}
+
+// Undo history size for CanvasStore::new, the config has its own default
+const DEFAULT_UNDO_HISTORY: usize = 100;
+
+// One operation that can still be undone or redone
+// undone_at is the sequence of the undo, so a redo can pick the latest one
+// author is the connection that drew it, None once the room was reloaded:
+// connection ids start over with every process, so a stored one could belong
+// to anybody by now
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct HistoryEntry {
+    id: u64,
+    author: Option<u64>,
+    active: bool,
+    #[serde(default)]
+    undone_at: Option<u64>,
+    object: CanvasObject,
+}
+
+// What goes into RoomSnapshots, snapshots from before undo are just the object list
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredCanvas {
+    History {
+        objects: Vec<CanvasObject>,
+        history: VecDeque<HistoryEntry>,
+        sequence: u64,
+    },
+    Objects(Vec<CanvasObject>),
+}
+
+// One room's canvas
+// objects are settled for good, history holds the latest operations that can
+// still be undone; once it's longer than the limit the oldest entries settle
+// (or disappear, if they were undone)
+// version bumps on every change, saved_version is what the database has -
+// if they match there's nothing to write
+// sequence numbers the operations clients see, draws, undos and redos alike,
+// and keeps counting across saves and reloads
+#[derive(Default)]
+struct RoomCanvas {
+    objects: Vec<CanvasObject>,
+    history: VecDeque<HistoryEntry>,
+    version: u64,
+    saved_version: u64,
+    sequence: u64,
+    order: Arc<Mutex<()>>,
+    delivery: Arc<Mutex<()>>,
+    // Kept in memory with nobody in the room until then, e.g. after a warmup
+    keep_until: Option<Instant>,
+}
+
+impl RoomCanvas {
+    fn is_dirty(&self) -> bool {
+        self.version != self.saved_version
+    }
+
+    fn visible_objects(&self) -> Vec<CanvasObject> {
+        let active = self.history.iter().filter(|entry| entry.active);
+        self.objects
+            .iter()
+            .chain(active.map(|entry| &entry.object))
+            .cloned()
+            .collect()
+    }
+
+    fn settle_beyond(&mut self, limit: usize) {
+        while self.history.len() > limit {
+            if let Some(entry) = self.history.pop_front()
+                && entry.active
+            {
+                self.objects.push(entry.object);
+            }
+        }
+    }
+
+    // Which history entry an undo (active = false) or redo is about
+    // Connections have no identity beyond themselves yet, so they only get to
+    // touch their own operations
+    fn target(&self, author: u64, op: Option<u64>, active: bool) -> Result<usize, UndoError> {
+        let author = Some(author);
+        match op {
+            Some(id) => {
+                let index = self
+                    .history
+                    .iter()
+                    .position(|entry| entry.id == id)
+                    .ok_or(UndoError::UnknownOp(id))?;
+                if self.history[index].author != author {
+                    return Err(UndoError::NotAuthor(id));
+                }
+                match (self.history[index].active, active) {
+                    (false, false) => Err(UndoError::AlreadyUndone(id)),
+                    (true, true) => Err(UndoError::NotUndone(id)),
+                    _ => Ok(index),
+                }
+            }
+            None if !active => self
+                .history
+                .iter()
+                .rposition(|entry| entry.author == author && entry.active)
+                .ok_or(UndoError::NothingToUndo),
+            None => self
+                .history
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.author == author && !entry.active)
+                .max_by_key(|(_, entry)| entry.undone_at)
+                .map(|(index, _)| index)
+                .ok_or(UndoError::NothingToRedo),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CanvasStore {
+    rooms: Arc<RwLock<HashMap<RoomId, RoomCanvas>>>,
+    // Operations per room that can still be undone
+    undo_history: usize,
+}
+
+impl Default for CanvasStore {
+    fn default() -> Self {
+        Self::with_undo_history(DEFAULT_UNDO_HISTORY)
+    }
+}
+
+impl CanvasStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_undo_history(undo_history: usize) -> Self {
+        Self {
+            rooms: Arc::default(),
+            undo_history,
+        }
+    }
+
+    // Make sure a room's canvas is in memory, loading the last snapshot if needed
+    pub async fn ensure_loaded(
+        &self,
+        room: &RoomId,
+        db: &Mutex<DatabaseConnection>,
+    ) -> Result<(), SnapshotError> {
+        if self.rooms.read().await.contains_key(room) {
+            return Ok(());
+        }
+
+        let snapshot = db.lock().await.load_room_snapshot(room.as_str())?;
+        let stored = match snapshot {
+            Some(json) => serde_json::from_str(&json).map_err(SnapshotError::Corrupt)?,
+            None => StoredCanvas::Objects(Vec::new()),
+        };
+        let mut canvas = match stored {
+            StoredCanvas::History {
+                objects,
+                history,
+                sequence,
+            } => RoomCanvas {
+                objects,
+                history: history
+                    .into_iter()
+                    .map(|entry| HistoryEntry {
+                        author: None,
+                        ..entry
+                    })
+                    .collect(),
+                sequence,
+                ..Default::default()
+            },
+            // Every operation used to add one object, so the count is the sequence
+            StoredCanvas::Objects(objects) => RoomCanvas {
+                sequence: objects.len() as u64,
+                objects,
+                ..Default::default()
+            },
+        };
+        // The limit may have been lowered since the save
+        canvas.settle_beyond(self.undo_history);
+
+        // Someone else may have loaded it while we were at the database - theirs wins
+        let mut rooms = self.rooms.write().await;
+        rooms.entry(room.clone()).or_insert(canvas);
+        Ok(())
+    }
+
+    // Everything currently visible on a room's canvas
+    pub async fn objects(&self, room: &RoomId) -> Vec<CanvasObject> {
+        let rooms = self.rooms.read().await;
+        rooms
+            .get(room)
+            .map(RoomCanvas::visible_objects)
+            .unwrap_or_default()
+    }
+
+    // Held from applying an operation until it's queued for broadcast, so a
+    // room's queue is in sequence order. None if the room isn't loaded
+    pub async fn order_lock(&self, room: &RoomId) -> Option<Arc<Mutex<()>>> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room).map(|canvas| canvas.order.clone())
+    }
+
+    // Held from taking a room's queued operations until every member has
+    // them, so one batch can't overtake another. None if the room isn't loaded
+    pub async fn delivery_lock(&self, room: &RoomId) -> Option<Arc<Mutex<()>>> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room).map(|canvas| canvas.delivery.clone())
+    }
+
+    // A room's settled objects, its undo history and the sequence it's at
+    pub async fn snapshot(&self, room: &RoomId) -> (Vec<CanvasObject>, Vec<RoomOp>, u64) {
+        let rooms = self.rooms.read().await;
+        rooms
+            .get(room)
+            .map(|canvas| {
+                let ops = canvas
+                    .history
+                    .iter()
+                    .map(|entry| RoomOp {
+                        id: entry.id,
+                        connection_id: entry.author.unwrap_or(0),
+                        active: entry.active,
+                        object: entry.object.clone(),
+                    })
+                    .collect();
+                (canvas.objects.clone(), ops, canvas.sequence)
+            })
+            .unwrap_or_default()
+    }
+
+    // Add an object to a loaded room, marking it dirty
+    // With a base sequence, it's only added if nothing happened in the room since
+    // Returns the sequence given to the new operation, which is also its id for undo
+    pub async fn add_object(
+        &self,
+        room: &RoomId,
+        object: CanvasObject,
+        author: ConnectionId,
+        base_sequence: Option<u64>,
+    ) -> Result<u64, AddObjectError> {
+        let mut rooms = self.rooms.write().await;
+        let canvas = rooms.get_mut(room).ok_or(AddObjectError::NotLoaded)?;
+        if let Some(base) = base_sequence
+            && base != canvas.sequence
+        {
+            return Err(AddObjectError::StaleBase {
+                current: canvas.sequence,
+            });
+        }
+        canvas.version += 1;
+        canvas.sequence += 1;
+        canvas.history.push_back(HistoryEntry {
+            id: canvas.sequence,
+            author: Some(author.0),
+            active: true,
+            undone_at: None,
+            object,
+        });
+        canvas.settle_beyond(self.undo_history);
+        Ok(canvas.sequence)
+    }
+
+    // Undo one of the author's operations: `op`, or the latest one that's still active
+    // Returns the id of the operation and the sequence the undo got
+    pub async fn undo(
+        &self,
+        room: &RoomId,
+        author: ConnectionId,
+        op: Option<u64>,
+    ) -> Result<(u64, u64), UndoError> {
+        self.set_active(room, author, op, false).await
+    }
+
+    // Redo one of the author's operations: `op`, or the one it undid most recently
+    pub async fn redo(
+        &self,
+        room: &RoomId,
+        author: ConnectionId,
+        op: Option<u64>,
+    ) -> Result<(u64, u64), UndoError> {
+        self.set_active(room, author, op, true).await
+    }
+
+    async fn set_active(
+        &self,
+        room: &RoomId,
+        author: ConnectionId,
+        op: Option<u64>,
+        active: bool,
+    ) -> Result<(u64, u64), UndoError> {
+        let mut rooms = self.rooms.write().await;
+        let canvas = rooms.get_mut(room).ok_or(UndoError::NotLoaded)?;
+        let index = canvas.target(author.0, op, active)?;
+        canvas.version += 1;
+        canvas.sequence += 1;
+        let sequence = canvas.sequence;
+        let entry = &mut canvas.history[index];
+        entry.active = active;
+        entry.undone_at = (!active).then_some(sequence);
+        Ok((entry.id, sequence))
+    }
+
+    // Write one room to the database if it has unsaved changes
+    pub async fn flush_room(
+        &self,
+        room: &RoomId,
+        db: &Mutex<DatabaseConnection>,
+    ) -> Result<bool, SnapshotError> {
+        let pending = {
+            let rooms = self.rooms.read().await;
+            match rooms.get(room) {
+                Some(canvas) if canvas.is_dirty() => {
+                    let stored = StoredCanvas::History {
+                        objects: canvas.objects.clone(),
+                        history: canvas.history.clone(),
+                        sequence: canvas.sequence,
+                    };
+                    Some((
+                        serde_json::to_string(&stored).map_err(SnapshotError::Corrupt)?,
+                        canvas.version,
+                    ))
+                }
+                _ => None,
+            }
+        };
+
+        let Some((json, version)) = pending else {
+            return Ok(false);
+        };
+        db.lock().await.save_room_snapshot(room.as_str(), &json)?;
+
+        // Only record what we actually wrote - anything drawn since stays dirty
+        if let Some(canvas) = self.rooms.write().await.get_mut(room) {
+            canvas.saved_version = canvas.saved_version.max(version);
+        }
+        Ok(true)
+    }
+
+    // Write every room with unsaved changes, returns how many were saved
+    // A failing room is logged and retried on the next pass
+    pub async fn flush_dirty(&self, db: &Mutex<DatabaseConnection>) -> usize {
+        let dirty: Vec<RoomId> = {
+            let rooms = self.rooms.read().await;
+            rooms
+                .iter()
+                .filter(|(_, canvas)| canvas.is_dirty())
+                .map(|(room, _)| room.clone())
+                .collect()
+        };
+
+        let mut saved = 0;
+        for room in dirty {
+            match self.flush_room(&room, db).await {
+                Ok(true) => saved += 1,
+                Ok(false) => {}
+                Err(e) => error!("Failed to save canvas for room {}: {}", room, e),
+            }
+        }
+        saved
+    }
+
+    // Drop a single room from memory, unless it still has unsaved changes
+    pub async fn evict_if_clean(&self, room: &RoomId) {
+        let mut rooms = self.rooms.write().await;
+        if rooms.get(room).is_some_and(|canvas| !canvas.is_dirty()) {
+            rooms.remove(room);
+        }
+    }
+
+    // Keep a loaded room out of evict_unoccupied until `until`
+    pub async fn keep_until(&self, room: &RoomId, until: Instant) {
+        if let Some(canvas) = self.rooms.write().await.get_mut(room) {
+            canvas.keep_until = Some(until);
+        }
+    }
+
+    // Drop rooms nobody is in from memory, as long as they're saved and not
+    // kept for a while longer
+    pub async fn evict_unoccupied(&self, occupied: &HashSet<RoomId>) {
+        let now = Instant::now();
+        let mut rooms = self.rooms.write().await;
+        rooms.retain(|room, canvas| {
+            occupied.contains(room)
+                || canvas.is_dirty()
+                || canvas.keep_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+// Background task - flush dirty rooms every few seconds
+// Runs until the app shuts down, same as the other top-level tasks
+pub async fn start_canvas_autosave(state: AppState) {
+    let secs = state.config.lock().await.canvas.autosave_interval_secs;
+    let mut interval = interval(Duration::from_secs(secs.max(1)));
+    info!("Canvas autosave running every {}s", secs.max(1));
+
+    loop {
+        interval.tick().await;
+        let saved = state.canvas.flush_dirty(&state.db).await;
+        if saved > 0 {
+            debug!("Autosaved {} room canvas(es)", saved);
+        }
+
+        // Catch rooms that emptied while they still had unsaved changes
+        let occupied: HashSet<RoomId> = state
+            .ws_connections
+            .room_occupancy()
+            .await
+            .into_iter()
+            .map(|(room, _)| room)
+            .collect();
+        state.canvas.evict_unoccupied(&occupied).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const ALICE: ConnectionId = ConnectionId(1);
+    const BOB: ConnectionId = ConnectionId(2);
+
+    fn object(kind: u32) -> CanvasObject {
+        CanvasObject {
+            kind,
+            num_args: vec![1.0, 2.0],
+            str_args: vec![],
+            color_args: vec![(255, 0, 0)],
+            bool_args: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_dirty_rooms_are_saved() {
+        let db = Mutex::new(DatabaseConnection::new(Path::new(":memory:")).unwrap());
+        let store = CanvasStore::new();
+        let room = RoomId::new("red").unwrap();
+
+        store.ensure_loaded(&room, &db).await.unwrap();
+        assert_eq!(store.flush_dirty(&db).await, 0);
+
+        assert_eq!(store.add_object(&room, object(1), ALICE, None).await, Ok(1));
+        assert_eq!(store.flush_dirty(&db).await, 1);
+        assert_eq!(store.flush_dirty(&db).await, 0);
+
+        // Once evicted, the next load comes back from the snapshot
+        store.evict_if_clean(&room).await;
+        assert!(store.objects(&room).await.is_empty());
+        store.ensure_loaded(&room, &db).await.unwrap();
+        assert_eq!(store.objects(&room).await, vec![object(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_rejects_stale_base() {
+        let db = Mutex::new(DatabaseConnection::new(Path::new(":memory:")).unwrap());
+        let store = CanvasStore::new();
+        let room = RoomId::new("red").unwrap();
+        store.ensure_loaded(&room, &db).await.unwrap();
+
+        assert_eq!(
+            store.add_object(&room, object(1), ALICE, Some(0)).await,
+            Ok(1)
+        );
+        // A second client still drawing on top of sequence 0
+        assert_eq!(
+            store.add_object(&room, object(2), BOB, Some(0)).await,
+            Err(AddObjectError::StaleBase { current: 1 })
+        );
+        assert_eq!(
+            store.add_object(&room, object(2), BOB, Some(1)).await,
+            Ok(2)
+        );
+
+        // The count carries over a save and reload
+        store.flush_dirty(&db).await;
+        store.evict_if_clean(&room).await;
+        store.ensure_loaded(&room, &db).await.unwrap();
+        assert_eq!(store.snapshot(&room).await.2, 2);
+    }
+
+    #[tokio::test]
+    async fn test_undo_redo_own_operations() {
+        let db = Mutex::new(DatabaseConnection::new(Path::new(":memory:")).unwrap());
+        let store = CanvasStore::with_undo_history(2);
+        let room = RoomId::new("red").unwrap();
+        store.ensure_loaded(&room, &db).await.unwrap();
+
+        store
+            .add_object(&room, object(1), ALICE, None)
+            .await
+            .unwrap();
+        store.add_object(&room, object(2), BOB, None).await.unwrap();
+        // Alice's undo skips Bob's newer drawing
+        assert_eq!(store.undo(&room, ALICE, None).await, Ok((1, 3)));
+        assert_eq!(store.objects(&room).await, vec![object(2)]);
+        assert_eq!(
+            store.undo(&room, ALICE, None).await,
+            Err(UndoError::NothingToUndo)
+        );
+        assert_eq!(
+            store.undo(&room, ALICE, Some(1)).await,
+            Err(UndoError::AlreadyUndone(1))
+        );
+        // Nobody gets to undo someone else's operation
+        assert_eq!(
+            store.redo(&room, BOB, Some(1)).await,
+            Err(UndoError::NotAuthor(1))
+        );
+        assert_eq!(store.redo(&room, ALICE, None).await, Ok((1, 4)));
+        assert_eq!(store.objects(&room).await, vec![object(1), object(2)]);
+
+        // The history survives a save and reload, but not who drew what:
+        // the next connection with Bob's id may be someone else entirely
+        store.undo(&room, BOB, None).await.unwrap();
+        store.flush_dirty(&db).await;
+        store.evict_if_clean(&room).await;
+        store.ensure_loaded(&room, &db).await.unwrap();
+        assert_eq!(store.objects(&room).await, vec![object(1)]);
+        assert_eq!(
+            store.redo(&room, BOB, None).await,
+            Err(UndoError::NothingToRedo)
+        );
+        assert_eq!(
+            store.redo(&room, BOB, Some(2)).await,
+            Err(UndoError::NotAuthor(2))
+        );
+        let ops = store.snapshot(&room).await.1;
+        assert!(ops.iter().all(|op| op.connection_id == 0));
+
+        // Beyond the limit the oldest operation settles and can't be undone anymore
+        store
+            .add_object(&room, object(3), ALICE, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.undo(&room, ALICE, Some(1)).await,
+            Err(UndoError::UnknownOp(1))
+        );
+        let (objects, ops, sequence) = store.snapshot(&room).await;
+        assert_eq!((objects, ops.len(), sequence), (vec![object(1)], 2, 6));
+    }
+
+    #[tokio::test]
+    async fn test_kept_rooms_survive_eviction() {
+        let db = Mutex::new(DatabaseConnection::new(Path::new(":memory:")).unwrap());
+        let store = CanvasStore::new();
+        let (red, blue) = (RoomId::new("red").unwrap(), RoomId::new("blue").unwrap());
+        store.ensure_loaded(&red, &db).await.unwrap();
+        store.ensure_loaded(&blue, &db).await.unwrap();
+        store
+            .keep_until(&red, Instant::now() + Duration::from_secs(60))
+            .await;
+
+        store.evict_unoccupied(&HashSet::new()).await;
+        assert!(store.order_lock(&red).await.is_some());
+        assert!(store.order_lock(&blue).await.is_none());
+
+        // Once that's over it goes like any other
+        store.keep_until(&red, Instant::now()).await;
+        store.evict_unoccupied(&HashSet::new()).await;
+        assert!(store.order_lock(&red).await.is_none());
+    }
+}
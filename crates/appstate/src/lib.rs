@@ -1,12 +1,20 @@
+mod batch;
+mod canvas;
 mod websocket;
 
 use axum::extract::ws::Message;
+pub use batch::{OpBatcher, PendingOp, Recipients};
+pub use canvas::{AddObjectError, CanvasStore, SnapshotError, UndoError, start_canvas_autosave};
 use config::Config;
-use db::DatabaseConnection;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+use db::{DatabaseConnection, UserStore};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use tokio::sync::Mutex;
-pub use websocket::{BinaryMessage, ConnectionId, ConnectionRegistry, MessageSender, TextMessage};
+pub use websocket::{
+    BinaryMessage, ConnectionId, ConnectionRegistry, JoinError, LagState, MessageSender, RoomId,
+    RoomLimits, TextMessage,
+};
 
 // Implement trait for axum WebSocket Message
 impl TextMessage for Message {
@@ -25,16 +33,43 @@ impl BinaryMessage for Message {
 pub struct AppState {
     pub config: Arc<Mutex<Config>>,
     pub db: Arc<Mutex<DatabaseConnection>>,
+    // Account operations go through this, so they don't depend on SQLite
+    // For now it's the same connection (and lock) as `db`
+    pub users: Arc<Mutex<dyn UserStore>>,
     pub running: Arc<AtomicBool>,
+    // Set while server.warmup runs, /ready is 503 until it's cleared
+    pub warming_up: Arc<AtomicBool>,
+    // Starts out as server.read_only, can be flipped at runtime
+    pub read_only: Arc<AtomicBool>,
     pub ws_connections: ConnectionRegistry<Message>,
+    pub canvas: CanvasStore,
+    // Draws, undos and redos waiting for their broadcast, see canvas.broadcast_batch_ms
+    pub op_batches: OpBatcher,
+    // Where the webserver actually listens, set once it has bound
+    // With server.port 0 this is the only way to learn the port the OS picked
+    pub bound_addr: Arc<OnceLock<SocketAddr>>,
 }
 impl AppState {
     pub fn new(config: Config, db: DatabaseConnection) -> Self {
+        let read_only = config.server.read_only;
+        let canvas = CanvasStore::with_undo_history(config.canvas.undo_history);
+        let db = Arc::new(Mutex::new(db));
         Self {
             config: Arc::new(Mutex::new(config)),
-            db: Arc::new(Mutex::new(db)),
+            users: db.clone(),
+            db,
             running: Arc::new(AtomicBool::new(true)),
+            warming_up: Arc::new(AtomicBool::new(false)),
+            read_only: Arc::new(AtomicBool::new(read_only)),
             ws_connections: ConnectionRegistry::new(),
+            canvas,
+            op_batches: OpBatcher::new(),
+            bound_addr: Arc::new(OnceLock::new()),
         }
     }
+
+    // Write paths check this and refuse with a "read-only mode" error
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
 }
@@ -1,9 +1,11 @@
 // Dependencies we need for the connection system
 // HashMap: track connections, Arc/Mutex: thread safety, mpsc: message channels
-use std::collections::HashMap;
+use config::LagPolicy;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, Notify, RwLock, mpsc};
 
 // Simple ID type for clients - just a wrapper around a counter
 // Using a newtype pattern here to avoid mixing up with other u64s
@@ -16,26 +18,204 @@ impl fmt::Display for ConnectionId {
     }
 }
 
+// Rooms are named by the clients themselves, so keep the names sane
+// Longest room name we accept - keeps logs and maps from getting silly
+const MAX_ROOM_ID_LEN: usize = 64;
+
+// Name of an independent canvas/channel - broadcasts are scoped to one of these
+#[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct RoomId(String);
+
+impl RoomId {
+    // Validate a client-supplied room name
+    // Only ASCII letters, digits, '-' and '_' so names are safe to log and put in URLs
+    pub fn new(name: impl Into<String>) -> Option<Self> {
+        let name = name.into();
+        let valid = !name.is_empty()
+            && name.len() <= MAX_ROOM_ID_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        valid.then_some(Self(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Room bookkeeping - both directions live behind one lock so they can't drift apart
+#[derive(Default)]
+struct RoomTable {
+    members: HashMap<RoomId, HashSet<ConnectionId>>,
+    joined: HashMap<ConnectionId, RoomId>,
+}
+
+impl RoomTable {
+    // Pull a connection out of whatever room it's in
+    // Empty rooms get dropped right away so they don't pile up
+    fn remove(&mut self, id: ConnectionId) -> Option<RoomId> {
+        let room = self.joined.remove(&id)?;
+        if let Some(members) = self.members.get_mut(&room) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.members.remove(&room);
+            }
+        }
+        Some(room)
+    }
+
+    // Whether a connection that isn't in `room` yet may join it
+    fn check_join(
+        &self,
+        id: ConnectionId,
+        room: &RoomId,
+        limits: RoomLimits,
+    ) -> Result<(), JoinError> {
+        let participants = self.members.get(room).map_or(0, HashSet::len);
+        if limits
+            .max_participants
+            .is_some_and(|max| participants >= max)
+        {
+            return Err(JoinError::RoomFull);
+        }
+        if participants == 0
+            && let Some(max) = limits.max_rooms
+        {
+            // Moving out of a room you're alone in frees up its slot
+            let frees_a_room = self
+                .joined
+                .get(&id)
+                .and_then(|current| self.members.get(current))
+                .is_some_and(|members| members.len() == 1);
+            if self.members.len() - usize::from(frees_a_room) >= max {
+                return Err(JoinError::TooManyRooms);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Caps checked when a connection joins a room, None means no limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoomLimits {
+    pub max_rooms: Option<usize>,
+    pub max_participants: Option<usize>,
+}
+
+// Why a join was turned down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    // The room doesn't exist yet and there are already max_rooms rooms
+    TooManyRooms,
+    // The room already has max_participants people in it
+    RoomFull,
+}
+
 // Message sender for talking to a specific client
 // Generic over message type so we can use different WS implementations
 #[derive(Clone)]
 pub struct MessageSender<T> {
     tx: mpsc::Sender<T>,
+    lag: Arc<LagState>,
+}
+
+// Shared with the connection's send task, which acts on a lag (server.websocket.on_lag)
+pub struct LagState {
+    policy: LagPolicy,
+    // Set when the queue overflowed, until the send task has dealt with it
+    lagged: AtomicBool,
+    // Wakes the send task for LagPolicy::Disconnect, it may be stuck on the socket
+    notify: Notify,
+}
+
+impl LagState {
+    pub fn policy(&self) -> LagPolicy {
+        self.policy
+    }
+
+    // True once per overflow, clearing it
+    pub fn take_lagged(&self) -> bool {
+        self.lagged.swap(false, Ordering::AcqRel)
+    }
+
+    // Resolves once the queue overflowed
+    pub async fn overflowed(&self) {
+        while !self.lagged.load(Ordering::Acquire) {
+            self.notify.notified().await;
+        }
+    }
 }
 
 impl<T> MessageSender<T>
 where
-    T: Clone + Send + 'static,
+    T: Send + 'static,
 {
+    // Waits for room when the queue is full, like LagPolicy::Wait
     pub fn new(tx: mpsc::Sender<T>) -> Self {
-        Self { tx }
+        Self::with_lag_policy(tx, LagPolicy::Wait)
+    }
+
+    pub fn with_lag_policy(tx: mpsc::Sender<T>, policy: LagPolicy) -> Self {
+        let lag = LagState {
+            policy,
+            lagged: AtomicBool::new(false),
+            notify: Notify::new(),
+        };
+        Self {
+            tx,
+            lag: Arc::new(lag),
+        }
+    }
+
+    pub fn lag_state(&self) -> Arc<LagState> {
+        self.lag.clone()
     }
 
     // Basic send function - just passes through to the channel
     // Returns error if the client disconnected
+    // Always waits for room, it's for control frames that must not get lost
     pub async fn send(&self, msg: T) -> Result<(), mpsc::error::SendError<T>> {
         self.tx.send(msg).await
     }
+
+    // Queue a message only if there's room right now, never waits
+    // For paths that must not stall on one slow client, like shutdown
+    pub fn try_send(&self, msg: T) -> Result<(), mpsc::error::TrySendError<T>> {
+        self.tx.try_send(msg)
+    }
+
+    // Queue a message the way the lag policy says
+    // Dropped messages come back as an error, same as for a disconnected client
+    pub async fn deliver(&self, msg: T) -> Result<(), mpsc::error::SendError<T>> {
+        // Once a client lagged, nothing more gets queued until the send task caught up
+        if self.lag.policy != LagPolicy::Wait && self.lag.lagged.load(Ordering::Acquire) {
+            return Err(mpsc::error::SendError(msg));
+        }
+        let msg = match self.tx.try_send(msg) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::TrySendError::Closed(msg)) => return Err(mpsc::error::SendError(msg)),
+            Err(mpsc::error::TrySendError::Full(msg)) => msg,
+        };
+        let policy = match self.lag.policy {
+            LagPolicy::Resync => "resync",
+            LagPolicy::Disconnect => "disconnect",
+            LagPolicy::Wait => "wait",
+        };
+        metrics::counter!("websocket_lag_events_total", "policy" => policy).increment(1);
+        if self.lag.policy == LagPolicy::Wait {
+            return self.tx.send(msg).await;
+        }
+        self.lag.lagged.store(true, Ordering::Release);
+        self.lag.notify.notify_one();
+        Err(mpsc::error::SendError(msg))
+    }
 }
 
 // Trait to abstract text message creation
@@ -61,7 +241,7 @@ where
         &self,
         text: impl Into<String>,
     ) -> Result<(), mpsc::error::SendError<T>> {
-        self.tx.send(T::create_text_message(text.into())).await
+        self.deliver(T::create_text_message(text.into())).await
     }
 }
 
@@ -75,7 +255,7 @@ where
         &self,
         data: impl Into<Vec<u8>>,
     ) -> Result<(), mpsc::error::SendError<T>> {
-        self.tx.send(T::create_binary_message(data.into())).await
+        self.deliver(T::create_binary_message(data.into())).await
     }
 }
 
@@ -84,7 +264,8 @@ where
 #[derive(Clone)]
 pub struct ConnectionRegistry<T> {
     connections: Arc<RwLock<HashMap<ConnectionId, MessageSender<T>>>>,
-    next_id: Arc<Mutex<u64>>, // Counter for generating unique IDs
+    rooms: Arc<RwLock<RoomTable>>, // Which connections are in which room
+    next_id: Arc<Mutex<u64>>,      // Counter for generating unique IDs
 }
 
 impl<T> ConnectionRegistry<T>
@@ -96,6 +277,7 @@ where
     pub fn new() -> Self {
         Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
+            rooms: Arc::new(RwLock::new(RoomTable::default())),
             next_id: Arc::new(Mutex::new(1)), // Start IDs from 1
         }
     }
@@ -115,6 +297,7 @@ where
     // Clean up when a client disconnects
     // Returns true if we actually removed something
     pub async fn unregister(&self, id: ConnectionId) -> bool {
+        self.leave_room(id).await;
         let mut connections = self.connections.write().await;
         connections.remove(&id).is_some()
     }
@@ -132,7 +315,7 @@ where
         let connections = self.connections.read().await;
         for sender in connections.values() {
             // Don't care about errors here - it's fine if some clients miss a broadcast
-            let _ = sender.send(msg.clone()).await;
+            let _ = sender.deliver(msg.clone()).await;
         }
     }
 
@@ -149,6 +332,97 @@ where
         let connections = self.connections.read().await;
         connections.keys().copied().collect()
     }
+
+    // Put a connection into a room - a connection is only ever in one room
+    // Returns the room it got moved out of, if it was somewhere else before
+    pub async fn join_room(&self, id: ConnectionId, room: RoomId) -> Option<RoomId> {
+        self.try_join_room(id, room, RoomLimits::default())
+            .await
+            .expect("joins without limits always succeed")
+    }
+
+    // Same as join_room, but refuses the join if it would go over the limits
+    // Checked under the same lock as the join itself, so concurrent joins can't overshoot
+    pub async fn try_join_room(
+        &self,
+        id: ConnectionId,
+        room: RoomId,
+        limits: RoomLimits,
+    ) -> Result<Option<RoomId>, JoinError> {
+        let mut rooms = self.rooms.write().await;
+        if rooms.joined.get(&id) == Some(&room) {
+            return Ok(None); // Already there, nothing to do
+        }
+        rooms.check_join(id, &room, limits)?;
+        let previous = rooms.remove(id);
+        rooms.members.entry(room.clone()).or_default().insert(id);
+        rooms.joined.insert(id, room);
+        Ok(previous)
+    }
+
+    // Whether try_join_room would let the connection in right now
+    // Only a hint, by the time of the join someone else may have taken the slot
+    pub async fn can_join_room(
+        &self,
+        id: ConnectionId,
+        room: &RoomId,
+        limits: RoomLimits,
+    ) -> Result<(), JoinError> {
+        let rooms = self.rooms.read().await;
+        if rooms.joined.get(&id) == Some(room) {
+            return Ok(());
+        }
+        rooms.check_join(id, room, limits)
+    }
+
+    // Take a connection out of its room (if any)
+    // Returns the room it left so callers can tell the others
+    pub async fn leave_room(&self, id: ConnectionId) -> Option<RoomId> {
+        let mut rooms = self.rooms.write().await;
+        rooms.remove(id)
+    }
+
+    // Which room is this connection in?
+    pub async fn room_of(&self, id: ConnectionId) -> Option<RoomId> {
+        let rooms = self.rooms.read().await;
+        rooms.joined.get(&id).cloned()
+    }
+
+    // Every non-empty room with how many connections are in it, sorted by name
+    // Empty rooms are collected as soon as they empty, so they never show up here
+    pub async fn room_occupancy(&self) -> Vec<(RoomId, usize)> {
+        let rooms = self.rooms.read().await;
+        let mut occupancy: Vec<(RoomId, usize)> = rooms
+            .members
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect();
+        occupancy.sort();
+        occupancy
+    }
+
+    // Everyone currently in a room
+    pub async fn room_members(&self, room: &RoomId) -> Vec<ConnectionId> {
+        let rooms = self.rooms.read().await;
+        rooms
+            .members
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // Send a message to everyone in a room, optionally skipping one connection
+    // (usually whoever caused the message in the first place)
+    pub async fn broadcast_to_room(&self, room: &RoomId, msg: T, except: Option<ConnectionId>) {
+        let members = self.room_members(room).await;
+        let connections = self.connections.read().await;
+        for id in members.into_iter().filter(|id| Some(*id) != except) {
+            if let Some(sender) = connections.get(&id) {
+                // Same deal as a global broadcast - missing a client is fine
+                let _ = sender.deliver(msg.clone()).await;
+            }
+        }
+    }
 }
 
 // Add text broadcasting if message type supports it
@@ -167,6 +441,17 @@ where
             let _ = sender.send_text(text.clone()).await;
         }
     }
+
+    // Room-scoped version of broadcast_text
+    pub async fn broadcast_text_to_room(
+        &self,
+        room: &RoomId,
+        text: impl Into<String>,
+        except: Option<ConnectionId>,
+    ) {
+        self.broadcast_to_room(room, T::create_text_message(text.into()), except)
+            .await;
+    }
 }
 
 // And the same for binary broadcasts
@@ -196,3 +481,122 @@ where
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rooms_are_scoped_and_collected() {
+        let registry: ConnectionRegistry<String> = ConnectionRegistry::new();
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        let a = registry.register(MessageSender::new(tx_a)).await;
+        let b = registry.register(MessageSender::new(tx_b)).await;
+        let red = RoomId::new("red").unwrap();
+        let blue = RoomId::new("blue").unwrap();
+
+        registry.join_room(a, red.clone()).await;
+        registry.join_room(b, blue.clone()).await;
+        registry
+            .broadcast_to_room(&red, "hi".to_string(), None)
+            .await;
+        assert_eq!(rx_a.try_recv().unwrap(), "hi");
+        assert!(rx_b.try_recv().is_err());
+
+        // Moving b into red empties blue, which should disappear
+        assert_eq!(registry.join_room(b, red.clone()).await, Some(blue.clone()));
+        assert!(registry.room_members(&blue).await.is_empty());
+        assert!(!registry.rooms.read().await.members.contains_key(&blue));
+
+        registry.unregister(a).await;
+        assert_eq!(registry.room_members(&red).await, vec![b]);
+    }
+
+    #[tokio::test]
+    async fn test_lag_policies() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let sender = MessageSender::with_lag_policy(tx, LagPolicy::Resync);
+        let lag = sender.lag_state();
+        assert!(sender.deliver("a".to_string()).await.is_ok());
+        assert!(sender.deliver("b".to_string()).await.is_err());
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        // There's room again, but nothing goes out until the lag was dealt with
+        assert!(sender.deliver("c".to_string()).await.is_err());
+        assert!(lag.take_lagged());
+        assert!(!lag.take_lagged());
+        assert!(sender.deliver("d".to_string()).await.is_ok());
+        assert_eq!(rx.try_recv().unwrap(), "d");
+
+        let (tx, _rx) = mpsc::channel(1);
+        let sender = MessageSender::with_lag_policy(tx, LagPolicy::Disconnect);
+        let lag = sender.lag_state();
+        sender.deliver("a".to_string()).await.unwrap();
+        let waiting = tokio::spawn(async move { lag.overflowed().await });
+        assert!(sender.deliver("b".to_string()).await.is_err());
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Waiting loses nothing, the send just takes until there's room
+        let (tx, mut rx) = mpsc::channel(1);
+        let sender = MessageSender::with_lag_policy(tx, LagPolicy::Wait);
+        sender.deliver("a".to_string()).await.unwrap();
+        let reader = tokio::spawn(async move {
+            let first = rx.recv().await;
+            let second = rx.recv().await;
+            (first, second)
+        });
+        sender.deliver("b".to_string()).await.unwrap();
+        let (first, second) = reader.await.unwrap();
+        assert_eq!((first.unwrap(), second.unwrap()), ("a".into(), "b".into()));
+    }
+
+    #[tokio::test]
+    async fn test_room_limits() {
+        let registry: ConnectionRegistry<String> = ConnectionRegistry::new();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let (tx, _rx) = mpsc::channel(1);
+            ids.push(registry.register(MessageSender::new(tx)).await);
+        }
+        let limits = RoomLimits {
+            max_rooms: Some(1),
+            max_participants: Some(2),
+        };
+        let red = RoomId::new("red").unwrap();
+        let blue = RoomId::new("blue").unwrap();
+
+        assert_eq!(
+            registry.try_join_room(ids[0], red.clone(), limits).await,
+            Ok(None)
+        );
+        assert_eq!(
+            registry.try_join_room(ids[1], red.clone(), limits).await,
+            Ok(None)
+        );
+        assert_eq!(
+            registry.try_join_room(ids[2], red.clone(), limits).await,
+            Err(JoinError::RoomFull)
+        );
+        assert_eq!(
+            registry.try_join_room(ids[2], blue.clone(), limits).await,
+            Err(JoinError::TooManyRooms)
+        );
+        // Once red is down to one, that one can move to a new room without going over
+        registry.leave_room(ids[1]).await;
+        assert_eq!(
+            registry.try_join_room(ids[0], blue.clone(), limits).await,
+            Ok(Some(red))
+        );
+    }
+
+    #[test]
+    fn test_room_id_validation() {
+        assert!(RoomId::new("canvas_1-a").is_some());
+        assert!(RoomId::new("").is_none());
+        assert!(RoomId::new("has space").is_none());
+        assert!(RoomId::new("x".repeat(MAX_ROOM_ID_LEN + 1)).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+//! Password hashing with a choice of algorithm.
+//!
+//! New hashes are made with the configured [`HashAlgorithm`], but verification
+//! looks at the stored hash itself (`$argon2id$`, `$scrypt$`, `$2b$`, ...), so
+//! switching algorithms never locks out existing users. Their old hashes keep
+//! working until they are replaced.
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordVerifier, SaltString};
+use scrypt::Scrypt;
+use std::fmt;
+
+/// A freshly hashed password, ready to be stored.
+pub struct HashedPassword {
+    /// The full self-describing hash string (`$argon2id$...`), which embeds the
+    /// algorithm, salt and parameters.
+    pub hash: String,
+    /// The salt that was used, kept separately for the `salt` column.
+    pub salt: String,
+}
+
+/// Errors that can occur while hashing a password.
+#[derive(Debug)]
+pub struct HashError(String);
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to hash password: {}", self.0)
+    }
+}
+
+impl std::error::Error for HashError {}
+
+impl From<password_hash::Error> for HashError {
+    fn from(e: password_hash::Error) -> Self {
+        HashError(e.to_string())
+    }
+}
+
+impl From<bcrypt::BcryptError> for HashError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        HashError(e.to_string())
+    }
+}
+
+/// One way of turning passwords into stored hashes and checking them again.
+pub trait PasswordHasher: Send + Sync {
+    /// Hash a password with a fresh random salt.
+    fn hash(&self, password: &str) -> Result<HashedPassword, HashError>;
+    /// Whether `hash` was made by this algorithm.
+    fn recognizes(&self, hash: &str) -> bool;
+    /// Check a password against a hash this algorithm recognizes.
+    fn verify(&self, password: &str, hash: &str) -> bool;
+}
+
+/// The algorithms new passwords can be hashed with. Argon2id is the recommended default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Argon2id,
+    Scrypt,
+    /// Only looks at the first 72 bytes of a password, longer ones are refused
+    /// rather than silently truncated.
+    Bcrypt,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> &'static dyn PasswordHasher {
+        match self {
+            HashAlgorithm::Argon2id => &Argon2Hasher,
+            HashAlgorithm::Scrypt => &ScryptHasher,
+            HashAlgorithm::Bcrypt => &BcryptHasher,
+        }
+    }
+}
+
+const HASHERS: [&dyn PasswordHasher; 3] = [&Argon2Hasher, &ScryptHasher, &BcryptHasher];
+
+/// Argon2id with the `argon2` crate's default parameters.
+pub struct Argon2Hasher;
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(&self, password: &str) -> Result<HashedPassword, HashError> {
+        phc_hash(&Argon2::default(), password)
+    }
+
+    // Hashes from before the algorithm was configurable are all Argon2
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        phc_verify(&Argon2::default(), password, hash)
+    }
+}
+
+/// scrypt with the `scrypt` crate's recommended parameters.
+pub struct ScryptHasher;
+
+impl PasswordHasher for ScryptHasher {
+    fn hash(&self, password: &str) -> Result<HashedPassword, HashError> {
+        phc_hash(&Scrypt, password)
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$scrypt$")
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        phc_verify(&Scrypt, password, hash)
+    }
+}
+
+/// bcrypt at the default cost, stored in the usual `$2b$<cost>$<salt+hash>` form
+/// (bcrypt has its own format rather than PHC, but it is just as self-describing).
+pub struct BcryptHasher;
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<HashedPassword, HashError> {
+        let hash = bcrypt::non_truncating_hash(password, bcrypt::DEFAULT_COST)?;
+        // $2b$12$ followed by 22 characters of salt
+        let salt = hash.get(7..29).unwrap_or_default().to_string();
+        Ok(HashedPassword { hash, salt })
+    }
+
+    fn recognizes(&self, hash: &str) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> bool {
+        bcrypt::non_truncating_verify(password, hash).unwrap_or(false)
+    }
+}
+
+fn phc_hash(
+    hasher: &impl password_hash::PasswordHasher,
+    password: &str,
+) -> Result<HashedPassword, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = hasher.hash_password(password.as_bytes(), &salt)?;
+    Ok(HashedPassword {
+        hash: hash.to_string(),
+        salt: salt.as_str().to_string(),
+    })
+}
+
+fn phc_verify(verifier: &impl PasswordVerifier, password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => verifier
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hash a password with Argon2id and a random salt.
+pub fn hash_password(password: &str) -> Result<HashedPassword, HashError> {
+    hash_password_with(HashAlgorithm::default(), password)
+}
+
+/// Hash a password with the given algorithm and a random salt.
+pub fn hash_password_with(
+    algorithm: HashAlgorithm,
+    password: &str,
+) -> Result<HashedPassword, HashError> {
+    algorithm.hasher().hash(password)
+}
+
+/// Check a password against a stored hash, whichever supported algorithm made it.
+///
+/// Returns false for a wrong password as well as for a hash that can't be parsed.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    HASHERS
+        .iter()
+        .find(|hasher| hasher.recognizes(hash))
+        .is_some_and(|hasher| hasher.verify(password, hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        for algorithm in [
+            HashAlgorithm::Argon2id,
+            HashAlgorithm::Scrypt,
+            HashAlgorithm::Bcrypt,
+        ] {
+            let hashed = hash_password_with(algorithm, "hunter2").unwrap();
+            assert!(hashed.hash.contains(&hashed.salt), "{:?}", algorithm);
+            assert!(verify_password("hunter2", &hashed.hash), "{:?}", algorithm);
+            assert!(!verify_password("hunter3", &hashed.hash), "{:?}", algorithm);
+        }
+        assert!(!verify_password("hunter2", "not a hash"));
+        assert!(hash_password_with(HashAlgorithm::Bcrypt, &"x".repeat(73)).is_err());
+    }
+}
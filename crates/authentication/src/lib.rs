@@ -0,0 +1,99 @@
+//! User authentication for RustCanvas.
+
+mod hashing;
+pub mod permissions;
+
+pub use hashing::{
+    HashAlgorithm, HashError, HashedPassword, PasswordHasher, hash_password, hash_password_with,
+    verify_password,
+};
+use std::fmt;
+
+/// Reasons a username is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    Empty,
+    /// Longer than the configured maximum, counted in characters.
+    TooLong {
+        max: usize,
+    },
+    ControlCharacter,
+    SurroundingWhitespace,
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "Username must not be empty"),
+            UsernameError::TooLong { max } => {
+                write!(f, "Username must be at most {} characters long", max)
+            }
+            UsernameError::ControlCharacter => {
+                write!(f, "Username must not contain control characters")
+            }
+            UsernameError::SurroundingWhitespace => {
+                write!(f, "Username must not start or end with whitespace")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// Check that a username is non-empty, at most `max_len` characters, free of
+/// control characters and not padded with whitespace.
+pub fn validate_username(username: &str, max_len: usize) -> Result<(), UsernameError> {
+    if username.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    if username.chars().count() > max_len {
+        return Err(UsernameError::TooLong { max: max_len });
+    }
+    if username.chars().any(char::is_control) {
+        return Err(UsernameError::ControlCharacter);
+    }
+    if username.trim() != username {
+        return Err(UsernameError::SurroundingWhitespace);
+    }
+    Ok(())
+}
+
+/// A fresh random secret: 128 bits from the OS random number generator, as 32
+/// lowercase hex characters. For one-off proofs like confirming a registration.
+pub fn generate_token() -> String {
+    use argon2::password_hash::rand_core::{OsRng, RngCore};
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_username() {
+        assert_eq!(validate_username("alice", 8), Ok(()));
+        assert_eq!(validate_username("", 8), Err(UsernameError::Empty));
+        assert_eq!(
+            validate_username("alice-the-great", 8),
+            Err(UsernameError::TooLong { max: 8 })
+        );
+        assert_eq!(
+            validate_username("al\u{7}ce", 8),
+            Err(UsernameError::ControlCharacter)
+        );
+        assert_eq!(
+            validate_username(" alice", 8),
+            Err(UsernameError::SurroundingWhitespace)
+        );
+    }
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, generate_token());
+    }
+}
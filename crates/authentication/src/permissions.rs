@@ -0,0 +1,102 @@
+//! Permission bits stored in the `permissions` column of a user.
+//!
+//! In config files and on the command line they are written by name and
+//! combined with `|`, e.g. `READ | WRITE`:
+//!
+//! | Name    | Bit      | Allows                              |
+//! |---------|----------|-------------------------------------|
+//! | `NONE`  | none     | nothing                             |
+//! | `READ`  | `1 << 0` | joining rooms and viewing canvases  |
+//! | `WRITE` | `1 << 1` | drawing on canvases                 |
+//! | `ADMIN` | `1 << 15`| administering the server and users  |
+//! | `ALL`   | all bits | everything, including future bits   |
+//!
+//! Names are case-insensitive, and a plain number is accepted as raw bits.
+
+use std::fmt;
+
+pub const NONE: u16 = 0;
+pub const READ: u16 = 1 << 0;
+pub const WRITE: u16 = 1 << 1;
+pub const ADMIN: u16 = 1 << 15;
+pub const ALL: u16 = u16::MAX;
+
+// ALL and NONE aren't single flags, they are only used for parsing and the all-set case
+const FLAGS: [(&str, u16); 3] = [("READ", READ), ("WRITE", WRITE), ("ADMIN", ADMIN)];
+
+/// A permissions expression that names an unknown flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionsError(String);
+
+impl fmt::Display for PermissionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown permission '{}', expected NONE, READ, WRITE, ADMIN, ALL or a number",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for PermissionsError {}
+
+/// Parse an expression like `READ | WRITE` into permission bits.
+pub fn parse(expression: &str) -> Result<u16, PermissionsError> {
+    expression.split('|').try_fold(NONE, |bits, name| {
+        let name = name.trim();
+        let flag = match name.to_ascii_uppercase().as_str() {
+            "NONE" => NONE,
+            "ALL" => ALL,
+            upper => FLAGS
+                .iter()
+                .find(|(flag, _)| *flag == upper)
+                .map(|(_, bit)| *bit)
+                .or_else(|| name.parse().ok())
+                .ok_or_else(|| PermissionsError(name.to_string()))?,
+        };
+        Ok(bits | flag)
+    })
+}
+
+/// Write permission bits back as an expression [`parse`] accepts.
+///
+/// Bits without a name are appended as a number.
+pub fn format(bits: u16) -> String {
+    if bits == ALL {
+        return "ALL".to_string();
+    }
+    if bits == NONE {
+        return "NONE".to_string();
+    }
+    let mut parts: Vec<String> = FLAGS
+        .iter()
+        .filter(|(_, bit)| bits & bit != 0)
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let unnamed = FLAGS.iter().fold(bits, |rest, (_, bit)| rest & !bit);
+    if unnamed != 0 {
+        parts.push(unnamed.to_string());
+    }
+    parts.join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        assert_eq!(parse("READ | WRITE"), Ok(READ | WRITE));
+        assert_eq!(parse(" read|Admin "), Ok(READ | ADMIN));
+        assert_eq!(parse("NONE"), Ok(NONE));
+        assert_eq!(parse("ALL"), Ok(ALL));
+        assert_eq!(parse("READ | 4"), Ok(READ | 4));
+        assert!(parse("READ | DRAW").is_err());
+
+        assert_eq!(format(READ | WRITE), "READ | WRITE");
+        assert_eq!(format(WRITE | 4), "WRITE | 4");
+        for bits in [NONE, READ, READ | WRITE | ADMIN, ALL, 12] {
+            assert_eq!(parse(&format(bits)), Ok(bits));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors that can occur while loading or saving the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Io { path: String, source: io::Error },
+    /// The config file isn't valid JSON/TOML, or doesn't match the expected shape.
+    Parse {
+        path: String,
+        /// 1-based line of the problem.
+        line: usize,
+        /// 1-based column of the problem.
+        column: usize,
+        /// What the parser complained about, without location info.
+        message: String,
+        /// The offending line followed by a caret under the column.
+        snippet: String,
+    },
+    /// The document is larger than [`ParseLimits::max_bytes`](crate::ParseLimits::max_bytes),
+    /// refused before parsing it.
+    TooLarge { path: String, limit: usize },
+    /// The config file couldn't be written; the previous contents, if any, are untouched.
+    Write { path: String, source: io::Error },
+    /// Like [`ConfigError::Write`], because the disk (or the user's quota) is full.
+    DiskFull { path: String },
+    /// The file parsed, but a value is out of range or malformed.
+    Invalid {
+        /// Dotted path of the field, e.g. `server.interface`.
+        field: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    /// Build a write error, telling a full disk apart from everything else.
+    pub(crate) fn write(path: &str, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ConfigError::DiskFull {
+                path: path.to_string(),
+            },
+            _ => ConfigError::Write {
+                path: path.to_string(),
+                source,
+            },
+        }
+    }
+
+    /// Build a parse error from a byte offset into the file contents.
+    pub(crate) fn parse_at_offset(
+        path: &str,
+        content: &str,
+        offset: usize,
+        message: impl Into<String>,
+    ) -> Self {
+        let offset = offset.min(content.len());
+        let before = &content[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = content[line_start..offset].chars().count() + 1;
+        Self::parse_at(path, content, line, column, message)
+    }
+
+    /// Build a parse error from a 1-based line and column.
+    pub(crate) fn parse_at(
+        path: &str,
+        content: &str,
+        line: usize,
+        column: usize,
+        message: impl Into<String>,
+    ) -> Self {
+        let source_line = content.lines().nth(line.saturating_sub(1)).unwrap_or("");
+        let caret = " ".repeat(column.saturating_sub(1));
+        ConfigError::Parse {
+            path: path.to_string(),
+            line,
+            column,
+            message: message.into(),
+            snippet: format!("{}\n{}^", source_line, caret),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "Failed to read config file {}: {}", path, source)
+            }
+            ConfigError::TooLarge { path, limit } => write!(
+                f,
+                "Config file {} is larger than the limit of {} bytes",
+                path, limit
+            ),
+            ConfigError::Write { path, source } => {
+                write!(f, "Failed to write config file {}: {}", path, source)
+            }
+            ConfigError::DiskFull { path } => {
+                write!(f, "Disk full writing {}, free up space and try again", path)
+            }
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+                snippet,
+            } => {
+                writeln!(
+                    f,
+                    "Failed to parse config file {} at line {}, column {}: {}",
+                    path, line, column, message
+                )?;
+                let gutter = line.to_string();
+                let mut lines = snippet.lines();
+                if let Some(source_line) = lines.next() {
+                    writeln!(f, "{} | {}", gutter, source_line)?;
+                }
+                if let Some(caret) = lines.next() {
+                    write!(f, "{} | {}", " ".repeat(gutter.len()), caret)?;
+                }
+                Ok(())
+            }
+            ConfigError::Invalid { field, message } => {
+                write!(f, "Invalid config value for {}: {}", field, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } | ConfigError::Write { source, .. } => Some(source),
+            ConfigError::Parse { .. }
+            | ConfigError::Invalid { .. }
+            | ConfigError::DiskFull { .. }
+            | ConfigError::TooLarge { .. } => None,
+        }
+    }
+}
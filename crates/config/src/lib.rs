@@ -1,11 +1,133 @@
+mod error;
+mod limits;
+mod overrides;
+mod source;
+mod validate;
+
+use authentication::permissions;
 use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::{fs, path::Path};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+pub use error::ConfigError;
+pub use limits::ParseLimits;
+pub use overrides::{
+    ConfigSources, ENV_PREFIX, Override, OverrideSource, ResolvedConfig, apply_overrides,
+    env_overrides, resolve,
+};
+pub use source::ConfigFormat;
+
+/// The full server configuration, grouped by subsystem.
+///
+/// Older config files used a flat layout (`network`, `database_path`, ...
+/// at the top level). Those still load: every legacy key is mapped onto its
+/// new home, see [`RawConfig`]. Saving always writes the nested layout.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(from = "RawConfig")]
 pub struct Config {
-    pub network: InterfaceConfig,
-    pub database_path: String,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+    pub canvas: CanvasConfig,
+    pub runtime: RuntimeConfig,
+}
+
+/// Value of `server.interface` that listens on IPv4 and IPv6 with a single socket.
+pub const DUAL_STACK_INTERFACE: &str = "dual";
+
+impl Config {
+    /// Every address `server.interface` and `server.port` stand for, in resolver order.
+    ///
+    /// IP addresses are used as-is, hostnames such as `localhost` are resolved
+    /// and may give several addresses (IPv4 and IPv6). `dual` is `[::]`, the
+    /// binder is expected to switch off `IPV6_V6ONLY` for it.
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        let server = &self.server;
+        if server.interface == DUAL_STACK_INTERFACE {
+            return Ok(vec![SocketAddr::from((Ipv6Addr::UNSPECIFIED, server.port))]);
+        }
+        if let Ok(ip) = server.interface.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, server.port)]);
+        }
+        let invalid = |message: String| ConfigError::Invalid {
+            field: "server.interface".to_string(),
+            message,
+        };
+        let addrs: Vec<SocketAddr> = (server.interface.as_str(), server.port)
+            .to_socket_addrs()
+            .map_err(|e| invalid(format!("could not resolve '{}': {}", server.interface, e)))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(invalid(format!(
+                "'{}' did not resolve to any address",
+                server.interface
+            )));
+        }
+        Ok(addrs)
+    }
+}
+
+/// What is actually deserialized: the nested sections plus every legacy flat key.
+///
+/// A legacy key wins over the nested default, so an old file keeps
+/// behaving exactly like it did before the split.
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    server: ServerConfig,
+    #[serde(default)]
+    database: DatabaseConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    canvas: CanvasConfig,
+    #[serde(default)]
+    runtime: RuntimeConfig,
+
+    // Legacy flat layout
+    network: Option<LegacyNetworkConfig>,
+    database_path: Option<String>,
+    database_retry: Option<LegacyRetryConfig>,
+    websocket: Option<WebSocketConfig>,
 }
+
+#[derive(Deserialize)]
+struct LegacyNetworkConfig {
+    interface: String,
+    port: u16,
+}
+
+impl From<RawConfig> for Config {
+    fn from(raw: RawConfig) -> Self {
+        let mut config = Config {
+            server: raw.server,
+            database: raw.database,
+            auth: raw.auth,
+            logging: raw.logging,
+            canvas: raw.canvas,
+            runtime: raw.runtime,
+        };
+        if let Some(network) = raw.network {
+            config.server.interface = network.interface;
+            config.server.port = network.port;
+        }
+        if let Some(websocket) = raw.websocket {
+            config.server.websocket = websocket;
+        }
+        if let Some(path) = raw.database_path {
+            config.database.path = path;
+        }
+        if let Some(retry) = raw.database_retry {
+            config.database.busy_timeout_ms = retry.busy_timeout_ms();
+        }
+        config
+    }
+}
+
 enum ConfigTypes {
     Toml,
     Json,
@@ -28,88 +150,895 @@ fn find_config_type(file_name: &str) -> ConfigTypes {
     }
 }
 
+/// Settings for the HTTP/WebSocket server.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct InterfaceConfig {
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address to bind to, `0.0.0.0` for every IPv4 interface.
+    ///
+    /// `dual` binds one socket on `[::]` with `IPV6_V6ONLY` turned off, so it
+    /// accepts IPv4 and IPv6 clients alike. Where the OS can't do that (OpenBSD,
+    /// or hosts without IPv6) it falls back to `0.0.0.0` with a warning.
     pub interface: String,
+    /// `0` lets the OS pick a free port, which is mostly useful for tests. The
+    /// port that was picked is logged on startup.
     pub port: u16,
+    /// Identifies this instance in logs (and the `X-Server-Name` header when
+    /// enabled), unset to use the machine's hostname.
+    pub name: Option<String>,
+    /// Send `X-Server-Name` on every response, so a client or load balancer can
+    /// tell which instance answered.
+    pub server_name_header: bool,
+    pub websocket: WebSocketConfig,
+    /// Proxies whose `Forwarded`/`X-Forwarded-For` headers are believed, as CIDR
+    /// ranges or single addresses. Empty means the socket peer is always the client.
+    pub trusted_proxies: Vec<String>,
+    /// Length of the queue for connections that aren't accepted yet.
+    ///
+    /// The OS may cap this silently, on Linux at `net.core.somaxconn`.
+    pub listen_backlog: u32,
+    pub tcp_keepalive: TcpKeepaliveConfig,
+    /// Close connections that have had no traffic in either direction for this
+    /// many seconds, unset to never close idle connections.
+    ///
+    /// Separate from the request and header timeouts. Upgraded WebSockets are
+    /// covered too, but their 30s heartbeat keeps them busy as long as this is
+    /// comfortably longer than that.
+    pub http_idle_timeout_secs: Option<u64>,
+    /// Seconds a client gets to send the complete request line and headers
+    /// (HTTP/1 only), answered with a 408 when exceeded. Unset for no limit.
+    ///
+    /// Counted from the first byte of the request, so the wait for the next
+    /// request on a keep-alive connection is left to `http_idle_timeout_secs`.
+    pub header_read_timeout_secs: Option<u64>,
+    /// Seconds a client gets to send the complete request body, answered with
+    /// a 408 when exceeded. Unset for no limit.
+    pub body_read_timeout_secs: Option<u64>,
+    /// Seconds a handler gets to produce its response once the body is in,
+    /// answered with a 504 when exceeded. Unset for no limit.
+    ///
+    /// `/ws` is excluded, WebSocket connections are meant to stay open.
+    pub request_timeout_secs: Option<u64>,
+    /// Start in read-only mode: reads keep working, every mutation
+    /// (registration, canvas edits) is refused. Useful during backups or migrations.
+    pub read_only: bool,
+    pub static_cache: StaticCacheConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub warmup: WarmupConfig,
+}
+
+impl ServerConfig {
+    /// `name`, or the hostname when that is unset. Falls back to `rustcanvas`
+    /// if the hostname can't be determined or isn't valid UTF-8.
+    pub fn instance_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        gethostname::gethostname()
+            .into_string()
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "rustcanvas".to_string())
+    }
+}
+
+/// Browser hardening headers added to every response, values are sent verbatim.
+///
+/// `X-Content-Type-Options: nosniff` is always sent while this is enabled, the
+/// others can be switched off one by one by unsetting them. A handler that sets
+/// one of these headers itself keeps its own value.
+/// `Strict-Transport-Security` is left to a TLS-terminating proxy, since this
+/// server only speaks plain HTTP.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub content_security_policy: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            // Everything the frontend needs comes from this server, the canvas
+            // connects back over a WebSocket
+            content_security_policy: Some(
+                "default-src 'self'; connect-src 'self' ws: wss:; img-src 'self' data: blob:; \
+                 object-src 'none'; base-uri 'self'; frame-ancestors 'none'"
+                    .to_string(),
+            ),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+        }
+    }
+}
+
+/// `Cache-Control` values for the frontend files, set verbatim.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StaticCacheConfig {
+    /// For `index.html`. Keep this short, or clients will hold on to an old frontend.
+    pub index: String,
+    /// For every other asset.
+    pub assets: String,
+    /// For fingerprinted assets (`name.<hash>.ext`), whose content never changes.
+    pub fingerprinted: String,
 }
 
-impl Default for InterfaceConfig {
+impl Default for StaticCacheConfig {
+    fn default() -> Self {
+        Self {
+            index: "no-cache".to_string(),
+            assets: "public, max-age=3600".to_string(),
+            fingerprinted: "public, max-age=31536000, immutable".to_string(),
+        }
+    }
+}
+
+/// TCP keepalive probing on accepted connections, to notice peers that vanished.
+///
+/// The interval is only applied where the OS supports setting it (Linux,
+/// macOS, the BSDs, Windows); elsewhere just the idle time is used. Windows
+/// also ignores a custom probe count, which is why there's no setting for it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TcpKeepaliveConfig {
+    pub enabled: bool,
+    /// Seconds a connection has to be idle before the first probe.
+    pub idle_secs: u64,
+    /// Seconds between unanswered probes.
+    pub interval_secs: u64,
+}
+
+impl Default for TcpKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_secs: 60,
+            interval_secs: 10,
+        }
+    }
+}
+
+/// Work done after binding but before `/ready` reports 200, so an orchestrator
+/// holds traffic until the instance is prepared. `/live` answers as usual throughout.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WarmupConfig {
+    /// Run in order, empty (the default) to be ready as soon as the port is bound.
+    pub tasks: Vec<WarmupTask>,
+    /// Report ready anyway once warmup has taken this many seconds.
+    pub timeout_secs: u64,
+    /// Seconds the rooms the `rooms` task loaded stay in memory with nobody in
+    /// them, before the autosave may drop them again.
+    pub keep_rooms_secs: u64,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            tasks: Vec::new(),
+            timeout_secs: 60,
+            keep_rooms_secs: 300,
+        }
+    }
+}
+
+/// One step of the [`WarmupConfig`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WarmupTask {
+    /// Run SQLite's quick integrity check, which reads the whole database file
+    /// into the OS cache and reports damage before any client hits it.
+    Database,
+    /// Load the saved room canvases into memory, most recently saved first and
+    /// no more than `canvas.max_rooms`. Rooms nobody joins are dropped again
+    /// by the first autosave pass after `keep_rooms_secs`.
+    Rooms,
+}
+
+impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             interface: "0.0.0.0".to_string(),
             port: 3250,
+            name: None,
+            server_name_header: false,
+            websocket: WebSocketConfig::default(),
+            trusted_proxies: Vec::new(),
+            listen_backlog: 1024,
+            tcp_keepalive: TcpKeepaliveConfig::default(),
+            http_idle_timeout_secs: Some(120),
+            header_read_timeout_secs: Some(10),
+            body_read_timeout_secs: Some(30),
+            request_timeout_secs: Some(30),
+            read_only: false,
+            static_cache: StaticCacheConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            warmup: WarmupConfig::default(),
         }
     }
 }
 
-impl Default for Config {
+/// Settings for the SQLite database.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// Off runs the server as a plain static file server: no file is opened,
+    /// and accounts (`/register`, `/setup`), rooms and drawing (`/rooms`, `/ws`)
+    /// answer 501. The frontend, health and status endpoints keep working, and the
+    /// `seed`, `create-user` and `maintenance` commands refuse to run. An empty
+    /// in-memory database stands in for the file, but nothing uses it: there's
+    /// no autosave or warmup, and `/ready` doesn't check it.
+    pub enabled: bool,
+    /// Path of the database file, created if it doesn't exist.
+    pub path: String,
+    /// Milliseconds a write waits for another connection's lock before it
+    /// fails with "database is busy". SQLite does the waiting, there are no
+    /// further retries on top.
+    pub busy_timeout_ms: u64,
+    /// When the file turns out to be corrupt (or not a database at all), move it
+    /// aside as `<path>.corrupt-<timestamp>` and start with an empty database.
+    ///
+    /// Off by default: with it off the server refuses to start, so nothing is
+    /// lost without someone looking at it first.
+    pub recover_corrupt: bool,
+    /// Seconds to wait for another instance that is initializing the same
+    /// database file before giving up on startup.
+    pub migration_lock_timeout_secs: u64,
+    /// Log database operations slower than this many milliseconds at warn
+    /// level, unset to never log them.
+    pub slow_query_threshold_ms: Option<u64>,
+}
+
+impl Default for DatabaseConfig {
     fn default() -> Self {
         Self {
-            network: InterfaceConfig::default(),
-            database_path: "database.db".to_string(),
+            enabled: true,
+            path: "database.db".to_string(),
+            busy_timeout_ms: 300,
+            recover_corrupt: false,
+            migration_lock_timeout_secs: 30,
+            slow_query_threshold_ms: Some(100),
         }
     }
 }
 
-pub fn load_config(path: &str) -> Config {
+/// Settings for user accounts and authentication.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Longest accepted username, in characters.
+    pub max_username_length: usize,
+    /// Whether anyone can create an account through `POST /register`.
+    ///
+    /// When off, `/register` answers 403 and accounts are created with
+    /// `rustcanvas create-user`, which ignores this setting.
+    pub allow_public_registration: bool,
+    /// Permissions of accounts created through `/register`, or by `create-user`
+    /// without `--admin`/`--permissions`.
+    ///
+    /// Written as flag names joined with `|`: `NONE`, `READ`, `WRITE`, `ADMIN`
+    /// or `ALL`, e.g. `"READ"` for users that may only watch.
+    #[serde(with = "permission_flags")]
+    pub default_permissions: u16,
+    /// Algorithm for newly set passwords. Changing it is safe, existing hashes
+    /// name their algorithm and keep verifying.
+    pub password_hash: PasswordHashAlgorithm,
+}
+
+/// Password hashing algorithms, Argon2id is the recommended one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordHashAlgorithm {
+    #[default]
+    Argon2id,
+    Scrypt,
+    /// Refuses passwords longer than 72 bytes, which is all bcrypt can use.
+    Bcrypt,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            max_username_length: 32,
+            allow_public_registration: true,
+            default_permissions: permissions::READ | permissions::WRITE,
+            password_hash: PasswordHashAlgorithm::default(),
+        }
+    }
+}
+
+// Permission bits as "READ | WRITE" in the file, so a bad name is reported with its location
+mod permission_flags {
+    use authentication::permissions;
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(bits: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&permissions::format(*bits))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        permissions::parse(&expression).map_err(de::Error::custom)
+    }
+}
+
+/// Settings for log output.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// A tracing `EnvFilter` directive such as `rustcanvas=debug,warn`.
+    ///
+    /// When unset the build profile decides: trace for our crates in debug
+    /// builds, info in release builds.
+    pub filter: Option<String>,
+    pub access_log: AccessLogConfig,
+    pub file: FileLogConfig,
+}
+
+/// Copy of the log output written to a rotating file, next to the console output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FileLogConfig {
+    pub enabled: bool,
+    /// Directory for the log files, created if missing.
+    pub directory: String,
+    /// Base file name, rotated files get the date (and hour) appended.
+    pub file_name: String,
+    pub rotation: LogRotation,
+    /// How many rotated files to keep before the oldest is deleted, unset keeps all of them.
+    pub max_files: Option<usize>,
+}
+
+impl Default for FileLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "logs".to_string(),
+            file_name: "rustcanvas.log".to_string(),
+            rotation: LogRotation::Daily,
+            max_files: Some(14),
+        }
+    }
+}
+
+/// How often the log file is rotated. Size based rotation isn't supported.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Classic one-line-per-request access log, separate from the tracing output.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    pub format: AccessLogFormat,
+    /// File the entries are appended to. When unset they go to stdout.
+    pub path: Option<String>,
+}
+
+/// Line format of the access log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache common log format, with the latency in microseconds appended.
+    #[default]
+    Common,
+    /// Apache combined log format (common plus referer and user agent), with the latency appended.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// The retry settings of the legacy flat layout (`database_retry`).
+///
+/// They're folded into `database.busy_timeout_ms`: SQLite's busy handler
+/// waits for as long as the retries with backoff added up to.
+#[derive(Deserialize)]
+#[serde(default)]
+struct LegacyRetryConfig {
+    max_retries: u32,
+    base_delay_ms: u64,
+}
+
+impl Default for LegacyRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_delay_ms: 10,
+        }
+    }
+}
+
+impl LegacyRetryConfig {
+    fn busy_timeout_ms(&self) -> u64 {
+        (0..self.max_retries)
+            .map(|attempt| {
+                self.base_delay_ms
+                    .saturating_mul(2u64.saturating_pow(attempt))
+            })
+            .fold(0, u64::saturating_add)
+    }
+}
+
+/// Limits applied to every `/ws` connection.
+///
+/// Anything larger than these is treated as a policy violation and the
+/// connection gets closed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// Largest complete message (after reassembling fragments) in bytes.
+    pub max_message_size: usize,
+    /// Largest single frame in bytes.
+    pub max_frame_size: usize,
+    /// Log every connect and disconnect at info level, with the client address
+    /// (after trusted proxy resolution), connection id and why it closed.
+    /// Off, they only show up at debug level.
+    pub log_connections: bool,
+    /// Messages queued per connection for a client that reads slower than
+    /// they come in. More trades memory for riding out longer hiccups.
+    pub send_buffer: usize,
+    /// What happens once that queue is full, see [`LagPolicy`].
+    pub on_lag: LagPolicy,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 1024 * 1024,
+            max_frame_size: 1024 * 1024,
+            log_connections: true,
+            send_buffer: 100,
+            on_lag: LagPolicy::default(),
+        }
+    }
+}
+
+/// How to treat a WebSocket client whose send queue is full. Every time it
+/// happens counts towards the `websocket_lag_events_total` metric.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Drop what doesn't fit and, once the client caught up, send it a
+    /// `lagged` message so it resyncs with `request_state`. The default.
+    #[default]
+    Resync,
+    /// Close the connection, the client has to reconnect.
+    Disconnect,
+    /// Hold the sender until there is room again. Nothing is lost, but one
+    /// slow client slows down everyone in its room.
+    Wait,
+}
+
+/// Settings for the per-room canvas state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CanvasConfig {
+    /// How often (in seconds) rooms with unsaved changes are written to the database.
+    pub autosave_interval_secs: u64,
+    /// Most rooms that can exist at once, unset for no limit. Joining a room
+    /// that doesn't exist yet is refused once there are this many.
+    pub max_rooms: Option<usize>,
+    /// Most connections a single room takes, unset for no limit.
+    pub max_participants_per_room: Option<usize>,
+    /// Size of the canvas in canvas units, points outside it are refused.
+    pub width: u32,
+    pub height: u32,
+    /// Most points a single draw may have; this is what bounds the size of a draw message.
+    pub max_points_per_draw: usize,
+    /// Widest stroke a draw may use.
+    pub max_stroke_width: f64,
+    /// The only colors draws may use, as `[r, g, b]`; unset allows any color.
+    pub palette: Option<Vec<(u8, u8, u8)>>,
+    /// How many of a room's latest operations can still be undone, older ones
+    /// become permanent. The history is saved with the canvas. 0 turns undo off.
+    pub undo_history: usize,
+    /// Milliseconds a room's draws, undos and redos are collected before they're
+    /// broadcast, several at once going out as one `op_batch` message. 0 sends
+    /// each one right away.
+    pub broadcast_batch_ms: u64,
+}
+
+impl Default for CanvasConfig {
+    fn default() -> Self {
+        Self {
+            autosave_interval_secs: 30,
+            max_rooms: None,
+            max_participants_per_room: None,
+            width: 4096,
+            height: 4096,
+            max_points_per_draw: 1000,
+            max_stroke_width: 64.0,
+            palette: None,
+            undo_history: 100,
+            broadcast_batch_ms: 16,
+        }
+    }
+}
+
+/// Thread counts for the tokio runtime, unset means tokio's own default.
+///
+/// Most database calls run on the worker threads while holding the database
+/// lock, so the workers are what a busy SQLite competes with.
+///
+/// The blocking pool runs the password hashing of every `/register` and
+/// `/setup`, so `max_blocking_threads` caps how many are hashed at once; the
+/// rest queue up. It also runs the `database` warmup's quick check, holding
+/// the database lock, and occasional work like hostname lookups at startup
+/// and config reloads. It is started lazily and idle threads exit after a
+/// few seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// Threads that run the async tasks, tokio uses one per CPU core by default.
+    pub worker_threads: Option<usize>,
+    /// Upper bound for the blocking pool, tokio's default is 512.
+    pub max_blocking_threads: Option<usize>,
+}
+
+fn read_config_file(file_path: &str, limits: &ParseLimits) -> Result<String, ConfigError> {
+    let file = fs::File::open(file_path).map_err(|source| ConfigError::Io {
+        path: file_path.to_string(),
+        source,
+    })?;
+    limits.read(file_path, file)
+}
+
+// Every document goes through here, files and in-memory ones alike
+fn parse(
+    file_path: &str,
+    content: &str,
+    format: ConfigFormat,
+    limits: &ParseLimits,
+) -> Result<Config, ConfigError> {
+    limits.check(file_path, content, format)?;
+    match format {
+        ConfigFormat::Json => parse_json(file_path, content),
+        ConfigFormat::Toml => parse_toml(file_path, content),
+    }
+}
+
+fn parse_json(file_path: &str, content: &str) -> Result<Config, ConfigError> {
+    let json_error = |e: serde_json::Error| {
+        // serde_json appends the location to its message, we report it separately
+        let message = e.to_string();
+        let suffix = format!(" at line {} column {}", e.line(), e.column());
+        let message = message.strip_suffix(&suffix).unwrap_or(&message);
+        ConfigError::parse_at(file_path, content, e.line(), e.column(), message)
+    };
+    // Read just the first document, so anything after it can be pointed out
+    // properly instead of as a bare "trailing characters"
+    let mut documents = serde_json::Deserializer::from_str(content).into_iter::<Config>();
+    let config = match documents.next() {
+        Some(result) => result.map_err(json_error)?,
+        // Empty or whitespace only, let from_str produce its usual error
+        None => return serde_json::from_str(content).map_err(json_error),
+    };
+    let end = documents.byte_offset();
+    if let Some(extra) = content[end..].find(|c: char| !c.is_whitespace()) {
+        let offset = end + extra;
+        return Err(ConfigError::parse_at_offset(
+            file_path,
+            content,
+            offset,
+            format!(
+                "unexpected content after the end of the config (byte offset {}), only one JSON object is allowed",
+                offset
+            ),
+        ));
+    }
+    Ok(config)
+}
+
+fn parse_toml(file_path: &str, content: &str) -> Result<Config, ConfigError> {
+    toml::from_str(content).map_err(|e| {
+        let offset = e.span().map_or(0, |span| span.start);
+        ConfigError::parse_at_offset(file_path, content, offset, e.message().trim_end())
+    })
+}
+
+/// Load and [validate](Config::validate) the config file `<path>.json` or `<path>.toml`,
+/// reporting problems as errors.
+///
+/// If neither file exists the user is asked which format to create, and the
+/// defaults are written to disk. Embedders with the config in memory can use
+/// [`Config::from_str_as`] or [`Config::from_value`] instead.
+pub fn try_load_config(path: &str) -> Result<Config, ConfigError> {
+    load(path, true)
+}
+
+/// Like [`try_load_config`], but never writes anything: without a config file
+/// the defaults are returned in memory. For tests, CI and read-only deployments.
+pub fn try_load_config_no_create(path: &str) -> Result<Config, ConfigError> {
+    load(path, false)
+}
+
+fn load(path: &str, create_missing: bool) -> Result<Config, ConfigError> {
+    let config = read(path, create_missing)?;
+    config.validate()?;
+    Ok(config)
+}
+
+// The file (or the defaults) as-is, without validation
+fn read(path: &str, create_missing: bool) -> Result<Config, ConfigError> {
     match find_config_type(path) {
         ConfigTypes::Json => {
             let file_path = format!("{}.json", path);
-            let file_content = fs::read_to_string(&file_path).expect("Failed to read config file");
-            serde_json::from_str(&file_content).expect("Failed to parse config file")
+            let limits = ParseLimits::default();
+            let file_content = read_config_file(&file_path, &limits)?;
+            parse(&file_path, &file_content, ConfigFormat::Json, &limits)
         }
         ConfigTypes::Toml => {
             let file_path = format!("{}.toml", path);
-            let file_content = fs::read_to_string(&file_path).expect("Failed to read config file");
-            toml::from_str(&file_content).expect("Failed to parse config file")
+            let limits = ParseLimits::default();
+            let file_content = read_config_file(&file_path, &limits)?;
+            parse(&file_path, &file_content, ConfigFormat::Toml, &limits)
         }
-        ConfigTypes::None => {
-            let default_config = Config::default();
-            let file_path = format!("{}.json", path);
-            let dir = Path::new(&file_path).parent().unwrap();
-            fs::create_dir_all(dir).expect("Failed to create directory structure");
-            let choice = utils::input::choice(
-                "jt",
-                false,
-                Some("No config file found, create a new one? [j]son/[t]oml: "),
-            );
-            match choice {
-                'j' | 'J' => {
-                    let json_content = serde_json::to_string_pretty(&default_config)
-                        .expect("Failed to serialize default config to JSON");
-                    fs::write(&file_path, json_content)
-                        .expect("Failed to write default config file");
-                    default_config
-                }
-                't' | 'T' => {
-                    let toml_file_path = format!("{}.toml", path);
-                    let toml_content = toml::to_string_pretty(&default_config)
-                        .expect("Failed to serialize default config to TOML");
-                    fs::write(&toml_file_path, toml_content)
-                        .expect("Failed to write default config file");
-                    default_config
-                }
-                _ => panic!("How did you get here?"),
-            }
+        ConfigTypes::None if create_missing => create_default_config(path),
+        ConfigTypes::None => Ok(Config::default()),
+    }
+}
+
+/// Like [`try_load_config`], but panics with a readable message if the file is broken.
+pub fn load_config(path: &str) -> Config {
+    try_load_config(path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Like [`try_load_config_no_create`], but panics with a readable message if the file is broken.
+pub fn load_config_no_create(path: &str) -> Config {
+    try_load_config_no_create(path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// How [`save_config_with_style`] lays out the file it writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputStyle {
+    /// Indented, for files people edit by hand.
+    #[default]
+    Pretty,
+    /// JSON on a single line. TOML has no single-line form for nested tables,
+    /// so this only drops the extra formatting there.
+    Compact,
+}
+
+fn to_json(config: &Config, style: OutputStyle) -> String {
+    match style {
+        OutputStyle::Pretty => serde_json::to_string_pretty(config),
+        OutputStyle::Compact => serde_json::to_string(config),
+    }
+    .expect("Failed to serialize config to JSON")
+}
+
+fn to_toml(config: &Config, style: OutputStyle) -> String {
+    match style {
+        OutputStyle::Pretty => toml::to_string_pretty(config),
+        OutputStyle::Compact => toml::to_string(config),
+    }
+    .expect("Failed to serialize config to TOML")
+}
+
+fn create_default_config(path: &str) -> Result<Config, ConfigError> {
+    let default_config = Config::default();
+    let file_path = format!("{}.json", path);
+    let dir = Path::new(&file_path).parent().unwrap();
+    fs::create_dir_all(dir).map_err(|source| ConfigError::write(&file_path, source))?;
+    let choice = utils::input::choice(
+        "jt",
+        false,
+        Some("No config file found, create a new one? [j]son/[t]oml: "),
+    );
+    // A freshly created file is meant to be edited, so it is always pretty
+    match choice {
+        'j' | 'J' => {
+            write_atomic(&file_path, &to_json(&default_config, OutputStyle::Pretty))?;
         }
+        't' | 'T' => {
+            let toml_file_path = format!("{}.toml", path);
+            write_atomic(
+                &toml_file_path,
+                &to_toml(&default_config, OutputStyle::Pretty),
+            )?;
+        }
+        _ => panic!("How did you get here?"),
     }
+    Ok(default_config)
+}
+
+/// Write `config` back to whichever of `<path>.json`/`<path>.toml` exists, pretty printed.
+///
+/// The file is replaced atomically, if writing fails (e.g. with
+/// [`ConfigError::DiskFull`]) the old one is left as it was.
+pub fn save_config(path: &str, config: &Config) -> Result<(), ConfigError> {
+    save_config_with_style(path, config, OutputStyle::Pretty)
 }
 
-pub fn save_config(path: &str, config: &Config) {
+/// Like [`save_config`], with a choice of [`OutputStyle`].
+pub fn save_config_with_style(
+    path: &str,
+    config: &Config,
+    style: OutputStyle,
+) -> Result<(), ConfigError> {
     match find_config_type(path) {
-        ConfigTypes::Json => {
-            let file_path = format!("{}.json", path);
-            let json_content =
-                serde_json::to_string_pretty(config).expect("Failed to serialize config to JSON");
-            fs::write(&file_path, json_content).expect("Failed to write config file");
+        ConfigTypes::Json => write_atomic(&format!("{}.json", path), &to_json(config, style)),
+        ConfigTypes::Toml => write_atomic(&format!("{}.toml", path), &to_toml(config, style)),
+        ConfigTypes::None => Err(ConfigError::Write {
+            path: path.to_string(),
+            source: io::Error::new(
+                io::ErrorKind::NotFound,
+                "there is no .json or .toml file to save to",
+            ),
+        }),
+    }
+}
+
+// Written to a temporary file next to the target and renamed over it, so a
+// failure halfway (a full disk most likely) never leaves a truncated config.
+// The temporary file is removed again when anything goes wrong
+fn write_atomic(file_path: &str, content: &str) -> Result<(), ConfigError> {
+    let tmp_path = format!("{}.tmp-{}", file_path, std::process::id());
+    let write = || -> io::Result<()> {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        // A full disk may only be reported once the data is flushed
+        file.sync_all()?;
+        fs::rename(&tmp_path, file_path)
+    };
+    write().map_err(|source| {
+        let _ = fs::remove_file(&tmp_path);
+        ConfigError::write(file_path, source)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_parse_error_location() {
+        let content =
+            "{\n  \"network\": {\n    \"interface\": \"0.0.0.0\"\n    \"port\": 3250\n  }\n}";
+        let err = parse_json("config.json", content).unwrap_err();
+        match &err {
+            ConfigError::Parse {
+                line,
+                column,
+                message,
+                snippet,
+                ..
+            } => {
+                assert_eq!((*line, *column), (4, 5));
+                assert_eq!(message, "expected `,` or `}`");
+                assert_eq!(snippet, "    \"port\": 3250\n    ^");
+            }
+            other => panic!("unexpected error: {:?}", other),
         }
-        ConfigTypes::Toml => {
-            let file_path = format!("{}.toml", path);
-            let toml_content =
-                toml::to_string_pretty(config).expect("Failed to serialize config to TOML");
-            fs::write(&file_path, toml_content).expect("Failed to write config file");
+        assert!(err.to_string().contains("line 4, column 5"));
+    }
+
+    #[test]
+    fn test_no_create_leaves_the_directory_alone() {
+        let dir = std::env::temp_dir().join(format!("rustcanvas-no-create-{}", std::process::id()));
+        let path = dir.join("config");
+        let config = try_load_config_no_create(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.server.port, ServerConfig::default().port);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_json_trailing_content_is_rejected() {
+        let content = "{ \"server\": {} }\n  { \"server\": {} }\n";
+        match parse_json("config.json", content).unwrap_err() {
+            ConfigError::Parse {
+                line,
+                column,
+                message,
+                ..
+            } => {
+                assert_eq!((line, column), (2, 3));
+                assert!(message.contains("byte offset 19"), "{}", message);
+            }
+            other => panic!("unexpected error: {:?}", other),
         }
-        ConfigTypes::None => panic!("No configuration type found"),
+        assert!(parse_json("config.json", "{}\n\n").is_ok());
+    }
+
+    #[test]
+    fn test_toml_parse_error_location() {
+        let content =
+            "database_path = \"db\"\n\n[network]\ninterface = \"0.0.0.0\"\nport = \"nope\"\n";
+        match parse_toml("config.toml", content).unwrap_err() {
+            ConfigError::Parse { line, snippet, .. } => {
+                assert_eq!(line, 5);
+                assert!(snippet.starts_with("port = \"nope\"\n"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_legacy_flat_config_migrates() {
+        let content = r#"{
+            "network": { "interface": "127.0.0.1", "port": 4000 },
+            "database_path": "old.db",
+            "database_retry": { "max_retries": 2, "base_delay_ms": 10 },
+            "websocket": { "max_message_size": 2048 },
+            "canvas": { "autosave_interval_secs": 5 }
+        }"#;
+        let config = parse_json("config.json", content).unwrap();
+        assert_eq!(config.server.interface, "127.0.0.1");
+        assert_eq!(config.server.port, 4000);
+        assert_eq!(config.server.websocket.max_message_size, 2048);
+        assert_eq!(config.database.path, "old.db");
+        assert_eq!(config.database.busy_timeout_ms, 30);
+        assert_eq!(config.canvas.autosave_interval_secs, 5);
+
+        // Saving writes the nested layout, which must load back the same
+        let saved = toml::to_string_pretty(&config).unwrap();
+        assert!(!saved.contains("database_path"));
+        let reloaded = parse_toml("config.toml", &saved).unwrap();
+        assert_eq!(reloaded.server.port, 4000);
+        assert_eq!(reloaded.database.path, "old.db");
+    }
+
+    #[test]
+    fn test_socket_addrs() {
+        let mut config = Config::default();
+        config.server.port = 4000;
+        assert_eq!(
+            config.socket_addrs().unwrap(),
+            vec!["0.0.0.0:4000".parse().unwrap()]
+        );
+        config.server.interface = DUAL_STACK_INTERFACE.to_string();
+        assert_eq!(
+            config.socket_addrs().unwrap(),
+            vec!["[::]:4000".parse().unwrap()]
+        );
+        config.server.interface = "localhost".to_string();
+        assert!(
+            config
+                .socket_addrs()
+                .unwrap()
+                .iter()
+                .all(|addr| addr.ip().is_loopback() && addr.port() == 4000)
+        );
+    }
+
+    #[test]
+    fn test_compact_json_is_one_line() {
+        let config = Config::default();
+        let compact = to_json(&config, OutputStyle::Compact);
+        assert!(!compact.contains('\n'));
+        let reloaded = parse_json("config.json", &compact).unwrap();
+        assert_eq!(reloaded.server.port, config.server.port);
+        assert!(to_json(&config, OutputStyle::Pretty).contains('\n'));
+    }
+
+    #[test]
+    fn test_save_config_replaces_the_file() {
+        let dir = std::env::temp_dir().join(format!("rustcanvas-save-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config").display().to_string();
+        fs::write(format!("{}.json", path), "{}").unwrap();
+
+        let mut config = Config::default();
+        config.server.port = 4321;
+        save_config(&path, &config).unwrap();
+        assert_eq!(try_load_config_no_create(&path).unwrap().server.port, 4321);
+        // Only the config itself is left, no temporary file next to it
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let full = ConfigError::write("config.json", io::ErrorKind::StorageFull.into());
+        assert_eq!(
+            full.to_string(),
+            "Disk full writing config.json, free up space and try again"
+        );
     }
 }
@@ -0,0 +1,171 @@
+//! Guards against pathological config documents.
+//!
+//! Both checks run before the document is deserialized at all: files are read
+//! through a reader capped at [`ParseLimits::max_bytes`], and a quick scan
+//! counts how deeply arrays and tables nest, skipping over strings (and TOML
+//! comments) so brackets inside them don't count. A real config nests a
+//! handful of levels and is a few kilobytes, the defaults leave plenty of room.
+
+use crate::{ConfigError, ConfigFormat};
+use std::io::Read;
+
+/// How large a config document may be before parsing is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Longest document in bytes.
+    pub max_bytes: usize,
+    /// Deepest nesting of arrays and objects/tables.
+    pub max_depth: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            max_depth: 32,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Read all of `reader`, but never more than `max_bytes` (plus one, to notice).
+    pub(crate) fn read(&self, path: &str, reader: impl Read) -> Result<String, ConfigError> {
+        let io_error = |source| ConfigError::Io {
+            path: path.to_string(),
+            source,
+        };
+        // Bytes first, the cut-off may fall in the middle of a character
+        let mut content = Vec::new();
+        reader
+            .take(self.max_bytes as u64 + 1)
+            .read_to_end(&mut content)
+            .map_err(io_error)?;
+        if content.len() > self.max_bytes {
+            return Err(self.too_large(path));
+        }
+        String::from_utf8(content)
+            .map_err(|e| io_error(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+
+    fn too_large(&self, path: &str) -> ConfigError {
+        ConfigError::TooLarge {
+            path: path.to_string(),
+            limit: self.max_bytes,
+        }
+    }
+
+    /// Both limits, for a document that is already in memory.
+    pub(crate) fn check(
+        &self,
+        path: &str,
+        content: &str,
+        format: ConfigFormat,
+    ) -> Result<(), ConfigError> {
+        if content.len() > self.max_bytes {
+            return Err(self.too_large(path));
+        }
+        match too_deep(content.as_bytes(), format, self.max_depth) {
+            Some(offset) => Err(ConfigError::parse_at_offset(
+                path,
+                content,
+                offset,
+                format!("nested deeper than {} levels", self.max_depth),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+// Byte offset of the first bracket beyond max_depth. The delimiters are all
+// ASCII, so scanning bytes never trips over multi-byte characters
+fn too_deep(bytes: &[u8], format: ConfigFormat, max_depth: usize) -> Option<usize> {
+    let toml = format == ConfigFormat::Toml;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Some(i);
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            b'"' => i = string_end(bytes, i, b'"', true, toml),
+            b'\'' if toml => i = string_end(bytes, i, b'\'', false, toml),
+            b'#' if toml => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Index of the last byte of the string starting at `start`, or the end of the
+// input if it never closes. TOML also has triple-quoted multi-line strings
+fn string_end(bytes: &[u8], start: usize, quote: u8, escapes: bool, toml: bool) -> usize {
+    let triple = [quote; 3];
+    let delimiter: &[u8] = if toml && bytes[start..].starts_with(&triple) {
+        &triple
+    } else {
+        &triple[..1]
+    };
+    let mut i = start + delimiter.len();
+    while i < bytes.len() {
+        if escapes && bytes[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if bytes[i..].starts_with(delimiter) {
+            return i + delimiter.len() - 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_reject_before_parsing() {
+        let limits = ParseLimits::default();
+        let deep = format!(
+            r#"{{"server": {{"trusted_proxies": {}{}}}}}"#,
+            "[".repeat(40),
+            "]".repeat(40)
+        );
+        match limits.check("<input>", &deep, ConfigFormat::Json) {
+            // The two braces around it count, so it's the 31st bracket
+            Err(ConfigError::Parse { column, .. }) => assert_eq!(column, 62),
+            other => panic!("expected a depth error, got {:?}", other),
+        }
+        // Brackets in strings and comments aren't nesting
+        let quoted = format!(
+            "# {}\n[server]\nname = \"{}\"\ninterface = '''{}'''\n",
+            "[".repeat(40),
+            "{\\\"".repeat(40),
+            "[".repeat(40)
+        );
+        assert!(limits.check("<input>", &quoted, ConfigFormat::Toml).is_ok());
+
+        let small = ParseLimits {
+            max_bytes: 8,
+            ..limits
+        };
+        assert!(matches!(
+            small.read("<input>", "{\"server\": {}}".as_bytes()),
+            Err(ConfigError::TooLarge { limit: 8, .. })
+        ));
+        assert_eq!(small.read("<input>", "{}".as_bytes()).unwrap(), "{}");
+        assert!(matches!(
+            small.read("<input>", "\"ééééé\"".as_bytes()),
+            Err(ConfigError::TooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+//! Layering the config sources: defaults < file < environment < command line.
+//!
+//! The file is read as usual, then environment variables and `--set` arguments
+//! override single fields on top of it, command line last so it always wins.
+//! Validation runs once on the final result.
+//!
+//! An override names its field by the dotted path that validation errors use,
+//! e.g. `server.port`. The value is read as JSON when it is valid JSON (`8080`,
+//! `true`, `null`, `["10.0.0.0/8"]`) and as a plain string otherwise, so
+//! `server.interface=0.0.0.0` needs no quoting.
+
+use crate::{Config, ConfigError};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Prefix of the environment variables that override config fields.
+///
+/// The rest of the name is the field path with `__` between the parts, so
+/// `RUSTCANVAS_SERVER__PORT` sets `server.port` and
+/// `RUSTCANVAS_AUTH__MAX_USERNAME_LENGTH` sets `auth.max_username_length`.
+pub const ENV_PREFIX: &str = "RUSTCANVAS_";
+
+/// Where an override came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideSource {
+    /// The named environment variable (possibly loaded from a `.env` file).
+    Env(String),
+    /// A `--set` argument.
+    Cli,
+}
+
+impl fmt::Display for OverrideSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OverrideSource::Env(name) => write!(f, "environment variable {}", name),
+            OverrideSource::Cli => write!(f, "--set"),
+        }
+    }
+}
+
+/// One field set by something other than the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    /// Dotted path of the field, e.g. `server.port`.
+    pub field: String,
+    pub value: String,
+    pub source: OverrideSource,
+}
+
+impl Override {
+    /// Parse a `--set` argument of the form `field=value`.
+    pub fn from_cli(arg: &str) -> Result<Self, ConfigError> {
+        match arg.split_once('=') {
+            Some((field, value)) if !field.trim().is_empty() => Ok(Self {
+                field: field.trim().to_string(),
+                value: value.to_string(),
+                source: OverrideSource::Cli,
+            }),
+            _ => Err(ConfigError::Invalid {
+                field: arg.to_string(),
+                message: "--set expects field=value, e.g. server.port=8080".to_string(),
+            }),
+        }
+    }
+}
+
+/// Every `RUSTCANVAS_` variable in `env` as an override, ordered by field so
+/// the result doesn't depend on the environment's iteration order.
+pub fn env_overrides(env: &HashMap<String, String>) -> Vec<Override> {
+    let mut overrides: Vec<Override> = env
+        .iter()
+        .filter_map(|(name, value)| {
+            let path = name.strip_prefix(ENV_PREFIX)?;
+            let field = path
+                .split("__")
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+                .join(".");
+            (!path.is_empty()).then(|| Override {
+                field,
+                value: value.clone(),
+                source: OverrideSource::Env(name.clone()),
+            })
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.field.cmp(&b.field));
+    overrides
+}
+
+/// Applies `overrides` in order on top of `config`, later ones win.
+///
+/// The result is not validated, [`resolve`] does that once everything is applied.
+pub fn apply_overrides(mut config: Config, overrides: &[Override]) -> Result<Config, ConfigError> {
+    for entry in overrides {
+        config = apply(&config, entry)?;
+    }
+    Ok(config)
+}
+
+fn apply(config: &Config, entry: &Override) -> Result<Config, ConfigError> {
+    let invalid = |message: String| ConfigError::Invalid {
+        field: entry.field.clone(),
+        message: format!("{} (set by {})", message, entry.source),
+    };
+    let tree = serde_json::to_value(config).expect("Failed to serialize config to JSON");
+    // "123" for a string field parses as a number, so the plain string is tried as well
+    let candidates = serde_json::from_str::<Value>(&entry.value)
+        .ok()
+        .into_iter()
+        .chain([Value::String(entry.value.clone())]);
+    let mut first_error = None;
+    for candidate in candidates {
+        let mut tree = tree.clone();
+        let slot = entry
+            .field
+            .split('.')
+            .try_fold(&mut tree, |node, key| node.get_mut(key))
+            .ok_or_else(|| invalid("there is no such config field".to_string()))?;
+        *slot = candidate;
+        match serde_json::from_value(tree) {
+            Ok(config) => return Ok(config),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(invalid(
+        first_error.map_or_else(String::new, |e| e.to_string()),
+    ))
+}
+
+/// Everything that goes into the config besides the built-in defaults.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    /// The file, `<path>.json` or `<path>.toml`.
+    pub path: String,
+    /// Offer to create a missing file, like [`try_load_config`](crate::try_load_config).
+    pub create_missing: bool,
+    /// The process environment, only `RUSTCANVAS_` variables are looked at.
+    pub env: HashMap<String, String>,
+    /// `--set` arguments in command line order.
+    pub cli: Vec<String>,
+}
+
+/// The final config plus every field that didn't come from the file, in the
+/// order they were applied.
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub config: Config,
+    pub overrides: Vec<Override>,
+}
+
+/// Loads the file, applies the environment and then the command line to it,
+/// and [validates](Config::validate) the result.
+pub fn resolve(sources: &ConfigSources) -> Result<ResolvedConfig, ConfigError> {
+    let file = crate::read(&sources.path, sources.create_missing)?;
+    let mut overrides = env_overrides(&sources.env);
+    for arg in &sources.cli {
+        overrides.push(Override::from_cli(arg)?);
+    }
+    let config = apply_overrides(file, &overrides)?;
+    config.validate()?;
+    Ok(ResolvedConfig { config, overrides })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(env: &[(&str, &str)], cli: &[&str]) -> ConfigSources {
+        ConfigSources {
+            path: std::env::temp_dir()
+                .join(format!("rustcanvas-no-such-config-{}", std::process::id()))
+                .display()
+                .to_string(),
+            create_missing: false,
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            cli: cli.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let resolved = resolve(&sources(
+            &[
+                ("RUSTCANVAS_SERVER__PORT", "4000"),
+                ("RUSTCANVAS_SERVER__INTERFACE", "0.0.0.0"),
+                ("RUSTCANVAS_SERVER__NAME", "123"),
+                ("PATH", "/usr/bin"),
+            ],
+            &["server.port=5000"],
+        ))
+        .unwrap();
+        // The command line beats the environment, which beats the defaults
+        assert_eq!(resolved.config.server.port, 5000);
+        assert_eq!(resolved.config.server.interface, "0.0.0.0");
+        assert_eq!(resolved.config.server.name.as_deref(), Some("123"));
+        assert_eq!(
+            resolved.overrides.last().unwrap().source,
+            OverrideSource::Cli
+        );
+        assert_eq!(resolved.overrides.len(), 4);
+    }
+
+    #[test]
+    fn test_resolve_rejects_bad_overrides() {
+        let field_of = |env: &[(&str, &str)], cli: &[&str]| match resolve(&sources(env, cli)) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!(
+                "expected an invalid field, got {:?}",
+                other.map(|r| r.overrides)
+            ),
+        };
+        assert_eq!(
+            field_of(&[("RUSTCANVAS_SERVER__PROT", "1")], &[]),
+            "server.prot"
+        );
+        assert_eq!(field_of(&[], &["server.port=not a port"]), "server.port");
+        assert_eq!(field_of(&[], &["server.port"]), "server.port");
+    }
+}
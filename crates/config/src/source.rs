@@ -0,0 +1,97 @@
+//! Building a [`Config`] without a config file, for embedding the server as a library.
+//!
+//! Every constructor parses exactly like a file would be (legacy flat layouts
+//! included) and [validates](Config::validate) the result, so errors look the
+//! same as for a file, just with `<input>` or `<value>` in place of its path.
+//! To build one in code instead, start from [`Config::default`], set the
+//! fields and call [`Config::validate`].
+//!
+//! Documents are held to the default [`ParseLimits`], the `_with_limits`
+//! variants take others, e.g. tighter ones for config from untrusted sources.
+
+use crate::{Config, ConfigError, ParseLimits};
+use std::io::Read;
+
+/// The formats a config can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl Config {
+    /// Parse and validate a config from the contents of a config file.
+    pub fn from_str_as(content: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        Self::from_str_with_limits(content, format, &ParseLimits::default())
+    }
+
+    /// Like [`from_str_as`](Self::from_str_as) with other [`ParseLimits`].
+    pub fn from_str_with_limits(
+        content: &str,
+        format: ConfigFormat,
+        limits: &ParseLimits,
+    ) -> Result<Self, ConfigError> {
+        let config = crate::parse("<input>", content, format, limits)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like [`from_str_as`](Self::from_str_as), reading everything from `reader` first.
+    pub fn from_reader(reader: impl Read, format: ConfigFormat) -> Result<Self, ConfigError> {
+        Self::from_reader_with_limits(reader, format, &ParseLimits::default())
+    }
+
+    /// Like [`from_reader`](Self::from_reader) with other [`ParseLimits`]. Reading
+    /// stops as soon as the document turns out to be too large.
+    pub fn from_reader_with_limits(
+        reader: impl Read,
+        format: ConfigFormat,
+        limits: &ParseLimits,
+    ) -> Result<Self, ConfigError> {
+        let content = limits.read("<input>", reader)?;
+        Self::from_str_with_limits(&content, format, limits)
+    }
+
+    /// Parse and validate an already parsed JSON value.
+    ///
+    /// Not held to any [`ParseLimits`], the value has been parsed already.
+    pub fn from_value(value: serde_json::Value) -> Result<Self, ConfigError> {
+        // Through text, so a bad field is reported with a line number and snippet
+        let content = serde_json::to_string_pretty(&value).expect("Failed to serialize JSON value");
+        let config = crate::parse_json("<value>", &content)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_config_from_memory() {
+        let json =
+            Config::from_str_as(r#"{"server": {"port": 4000}}"#, ConfigFormat::Json).unwrap();
+        let toml =
+            Config::from_reader("[server]\nport = 4000\n".as_bytes(), ConfigFormat::Toml).unwrap();
+        let value = Config::from_value(json!({"server": {"port": 4000}})).unwrap();
+        for config in [json, toml, value] {
+            assert_eq!(config.server.port, 4000);
+            assert_eq!(config.database.path, Config::default().database.path);
+        }
+
+        match Config::from_value(json!({"server": {"port": "high"}})) {
+            Err(ConfigError::Parse { path, line, .. }) => {
+                assert_eq!(path, "<value>");
+                assert_eq!(line, 3);
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        // Validation runs too, not just parsing
+        assert!(matches!(
+            Config::from_value(json!({"server": {"interface": "300.1.2.3"}})),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{Config, ConfigError, DUAL_STACK_INTERFACE};
+use std::net::IpAddr;
+
+impl Config {
+    /// Check the values the file format itself can't rule out, like a malformed
+    /// address or a timeout of zero.
+    ///
+    /// Reports the first offending field by its dotted path, e.g. `server.interface`.
+    /// Loading runs this already; anything that swaps in a new config at runtime
+    /// should run it too and keep the old config when it fails.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let server = &self.server;
+        check_interface(&server.interface)?;
+        if let Some(name) = &server.name {
+            check(
+                !name.is_empty(),
+                "server.name",
+                "must not be empty, or unset for the hostname",
+            )?;
+            check_header_value(name, "server.name")?;
+        }
+        check(
+            server.listen_backlog > 0,
+            "server.listen_backlog",
+            "must be at least 1",
+        )?;
+        check_timeout(
+            server.http_idle_timeout_secs,
+            "server.http_idle_timeout_secs",
+        )?;
+        check_timeout(
+            server.header_read_timeout_secs,
+            "server.header_read_timeout_secs",
+        )?;
+        check_timeout(
+            server.body_read_timeout_secs,
+            "server.body_read_timeout_secs",
+        )?;
+        check_timeout(server.request_timeout_secs, "server.request_timeout_secs")?;
+        check(
+            server.websocket.max_message_size > 0,
+            "server.websocket.max_message_size",
+            "must be at least 1",
+        )?;
+        check(
+            server.websocket.max_frame_size > 0,
+            "server.websocket.max_frame_size",
+            "must be at least 1",
+        )?;
+        check(
+            server.websocket.send_buffer > 0,
+            "server.websocket.send_buffer",
+            "must be at least 1",
+        )?;
+        check(
+            server.warmup.timeout_secs > 0,
+            "server.warmup.timeout_secs",
+            "must be at least 1 second",
+        )?;
+        let cache = &server.static_cache;
+        check_header_value(&cache.index, "server.static_cache.index")?;
+        check_header_value(&cache.assets, "server.static_cache.assets")?;
+        check_header_value(&cache.fingerprinted, "server.static_cache.fingerprinted")?;
+        let security = &server.security_headers;
+        for (value, field) in [
+            (
+                &security.content_security_policy,
+                "server.security_headers.content_security_policy",
+            ),
+            (
+                &security.frame_options,
+                "server.security_headers.frame_options",
+            ),
+            (
+                &security.referrer_policy,
+                "server.security_headers.referrer_policy",
+            ),
+        ] {
+            if let Some(value) = value {
+                check_header_value(value, field)?;
+            }
+        }
+        check(
+            self.auth.max_username_length > 0,
+            "auth.max_username_length",
+            "must be at least 1",
+        )?;
+        check(
+            self.logging.file.max_files != Some(0),
+            "logging.file.max_files",
+            "must be at least 1, or unset to keep every file",
+        )?;
+        check(
+            self.canvas.max_rooms != Some(0),
+            "canvas.max_rooms",
+            "must be at least 1, or unset for no limit",
+        )?;
+        check(
+            self.canvas.max_participants_per_room != Some(0),
+            "canvas.max_participants_per_room",
+            "must be at least 1, or unset for no limit",
+        )?;
+        check(self.canvas.width > 0, "canvas.width", "must be at least 1")?;
+        check(
+            self.canvas.height > 0,
+            "canvas.height",
+            "must be at least 1",
+        )?;
+        check(
+            self.canvas.max_points_per_draw >= 2,
+            "canvas.max_points_per_draw",
+            "must be at least 2, every shape needs two points",
+        )?;
+        check(
+            self.canvas.max_stroke_width > 0.0 && self.canvas.max_stroke_width.is_finite(),
+            "canvas.max_stroke_width",
+            "must be a positive number",
+        )?;
+        check(
+            self.canvas.palette.as_ref().is_none_or(|p| !p.is_empty()),
+            "canvas.palette",
+            "must list at least one color, or be unset to allow any",
+        )?;
+        check(
+            self.runtime.worker_threads != Some(0),
+            "runtime.worker_threads",
+            "must be at least 1, or unset for the default",
+        )?;
+        check(
+            self.runtime.max_blocking_threads != Some(0),
+            "runtime.max_blocking_threads",
+            "must be at least 1, or unset for the default",
+        )?;
+        Ok(())
+    }
+}
+
+fn check(ok: bool, field: &str, message: &str) -> Result<(), ConfigError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            field: field.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+// Zero would time out every connection at once, disabling is spelled as unset
+fn check_timeout(value: Option<u64>, field: &str) -> Result<(), ConfigError> {
+    check(
+        value != Some(0),
+        field,
+        "must be at least 1 second, or unset to disable it",
+    )
+}
+
+// Hostnames are fine (they're resolved when binding), but something that is
+// clearly meant as an IP address has to be a valid one
+fn check_interface(interface: &str) -> Result<(), ConfigError> {
+    if interface == DUAL_STACK_INTERFACE || interface.parse::<IpAddr>().is_ok() {
+        return Ok(());
+    }
+    let looks_like_ip =
+        interface.contains(':') || interface.chars().all(|c| c.is_ascii_digit() || c == '.');
+    check(
+        !interface.is_empty() && !looks_like_ip && !interface.contains(char::is_whitespace),
+        "server.interface",
+        &format!(
+            "'{}' is not a valid IP address, hostname or \"dual\"",
+            interface
+        ),
+    )
+}
+
+// Sent verbatim as a header, so only visible ASCII and spaces
+fn check_header_value(value: &str, field: &str) -> Result<(), ConfigError> {
+    check(
+        value.chars().all(|c| c == ' ' || c.is_ascii_graphic()),
+        field,
+        "must only contain printable ASCII characters",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_names_the_bad_field() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.server.interface = "300.1.2.3".to_string();
+        match config.validate() {
+            Err(ConfigError::Invalid { field, .. }) => assert_eq!(field, "server.interface"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut config = Config::default();
+        config.server.interface = "localhost".to_string();
+        config.server.header_read_timeout_secs = Some(0);
+        match config.validate() {
+            Err(ConfigError::Invalid { field, .. }) => {
+                assert_eq!(field, "server.header_read_timeout_secs")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors surfaced by the database layer.
+#[derive(Debug)]
+pub enum DbError {
+    /// An error reported by SQLite itself.
+    Sqlite(rusqlite::Error),
+    /// A user with this username already exists.
+    DuplicateUsername(String),
+    /// There is no user with this username.
+    UserNotFound(String),
+    /// There is no unexpired reservation for this username to confirm.
+    NotReserved(String),
+    /// The password could not be hashed.
+    Hashing(authentication::HashError),
+    /// The username failed validation (length, control characters, whitespace).
+    InvalidUsername(authentication::UsernameError),
+    /// The database was created by a build with a different schema version.
+    SchemaVersionMismatch { found: i64, supported: i64 },
+    /// Another connection held the database's write lock (most likely another
+    /// instance initializing it) for longer than the configured timeout.
+    MigrationLockTimeout {
+        path: String,
+        waited: std::time::Duration,
+    },
+    /// Upgrading the schema from version `from` to the next one failed.
+    Migration { from: i64, source: rusqlite::Error },
+    /// A table the schema needs is still missing after `init.sql` ran.
+    MissingTable(&'static str),
+    /// The file is damaged or isn't an SQLite database at all.
+    Corrupt {
+        path: String,
+        source: rusqlite::Error,
+    },
+    /// SQLite ran out of space writing to the database at `path`; the write
+    /// was rolled back, nothing of it was stored.
+    DiskFull { path: String },
+    /// A setting's value couldn't be converted to or from JSON.
+    Setting {
+        key: String,
+        source: serde_json::Error,
+    },
+    /// One user of a `create_users` batch was rejected, so none were created.
+    BatchUser {
+        username: String,
+        source: Box<DbError>,
+    },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            DbError::DuplicateUsername(username) => {
+                write!(f, "A user named '{}' already exists", username)
+            }
+            DbError::UserNotFound(username) => write!(f, "There is no user named '{}'", username),
+            DbError::NotReserved(username) => write!(
+                f,
+                "The username '{}' isn't reserved, or the reservation expired",
+                username
+            ),
+            DbError::Hashing(e) => write!(f, "{}", e),
+            DbError::InvalidUsername(e) => write!(f, "{}", e),
+            DbError::BatchUser { username, source } => {
+                write!(f, "Failed to create user '{}': {}", username, source)
+            }
+            DbError::DiskFull { path } => {
+                write!(f, "Disk full writing {}, free up space and try again", path)
+            }
+            DbError::Setting { key, source } => {
+                write!(f, "Setting '{}' has an unexpected value: {}", key, source)
+            }
+            DbError::MigrationLockTimeout { path, waited } => write!(
+                f,
+                "Gave up after {:?} waiting for another process to finish initializing the database at {}",
+                waited, path
+            ),
+            DbError::Migration { from, source } => write!(
+                f,
+                "Failed to migrate the database schema from version {} to {}: {}",
+                from,
+                from + 1,
+                source
+            ),
+            DbError::MissingTable(table) => write!(
+                f,
+                "Table '{}' is missing after initializing the database, the embedded init.sql is empty or broken",
+                table
+            ),
+            DbError::Corrupt { path, source } => write!(
+                f,
+                "The database at {} is corrupt or not an SQLite database: {}",
+                path, source
+            ),
+            DbError::SchemaVersionMismatch { found, supported } if found > supported => write!(
+                f,
+                "Database schema version {} is newer than this build supports ({}), refusing to open it with an older binary",
+                found, supported
+            ),
+            DbError::SchemaVersionMismatch { found, supported } => write!(
+                f,
+                "Database schema version {} is older than this build expects ({}) and there is no migration for it",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl Error for DbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DbError::Sqlite(e) => Some(e),
+            DbError::DuplicateUsername(_) | DbError::UserNotFound(_) | DbError::NotReserved(_) => {
+                None
+            }
+            DbError::Hashing(e) => Some(e),
+            DbError::InvalidUsername(e) => Some(e),
+            DbError::SchemaVersionMismatch { .. }
+            | DbError::MissingTable(_)
+            | DbError::MigrationLockTimeout { .. }
+            | DbError::DiskFull { .. } => None,
+            DbError::Corrupt { source, .. } | DbError::Migration { source, .. } => Some(source),
+            DbError::Setting { source, .. } => Some(source),
+            DbError::BatchUser { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
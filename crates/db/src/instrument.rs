@@ -0,0 +1,45 @@
+use crate::DbError;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Runs one database operation and records metrics for it.
+///
+/// Every public `DatabaseConnection` method goes through here, so each
+/// operation shows up under its own `operation` label:
+///
+/// - `db_queries_total`: number of calls
+/// - `db_query_errors_total`: number of calls that returned an error
+/// - `db_query_duration_seconds`: latency histogram
+///
+/// Recording is a no-op until a metrics recorder is installed.
+///
+/// An operation that takes longer than `slow_threshold` is also logged at warn
+/// level with its duration and `params`. `params` only runs for slow
+/// operations and describes the shape of the parameters (counts, sizes,
+/// setting keys), never usernames, passwords or hashes.
+pub(crate) fn instrumented<T>(
+    operation: &'static str,
+    slow_threshold: Option<Duration>,
+    params: impl FnOnce() -> String,
+    op: impl FnOnce() -> Result<T, DbError>,
+) -> Result<T, DbError> {
+    let start = Instant::now();
+    let result = op();
+    let elapsed = start.elapsed();
+    metrics::histogram!("db_query_duration_seconds", "operation" => operation)
+        .record(elapsed.as_secs_f64());
+    metrics::counter!("db_queries_total", "operation" => operation).increment(1);
+    if result.is_err() {
+        metrics::counter!("db_query_errors_total", "operation" => operation).increment(1);
+    }
+    if slow_threshold.is_some_and(|threshold| elapsed > threshold) {
+        warn!(
+            operation,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            params = %params(),
+            failed = result.is_err(),
+            "Slow database operation"
+        );
+    }
+    result
+}
@@ -1,6 +1,20 @@
+mod error;
+mod instrument;
+mod store;
+
+use rusqlite::OptionalExtension;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 #[allow(dead_code)]
+use std::cell::Cell;
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+pub use error::DbError;
+pub use store::UserStore;
 
 /// Represents a user in the database.
 pub struct User {
@@ -16,6 +30,83 @@ pub struct User {
     pub permissions: u16,
     ///lockout time of user, -1 if not locked out
     pub lockout_time: i64,
+    /// When the account was created, as a Unix timestamp (seconds).
+    pub created_at: i64,
+}
+
+/// The details needed to create a user. The password is hashed before it is stored.
+pub struct NewUser {
+    /// The username of the new user.
+    pub username: String,
+    /// The plaintext password, never stored as-is.
+    pub password: String,
+    /// The permissions level of the new user.
+    pub permissions: u16,
+}
+
+impl NewUser {
+    /// Hashes the password, the slow part of creating a user.
+    ///
+    /// CPU bound and slow on purpose (tens of milliseconds with the default
+    /// Argon2id), and it needs no connection: async callers should run it on a
+    /// blocking thread before taking the database lock, then hand the result to
+    /// [`create_hashed_user`](DatabaseConnection::create_hashed_user).
+    pub fn hash(&self, algorithm: authentication::HashAlgorithm) -> Result<HashedUser, DbError> {
+        let password = authentication::hash_password_with(algorithm, &self.password)
+            .map_err(DbError::Hashing)?;
+        Ok(HashedUser {
+            username: self.username.clone(),
+            password,
+            permissions: self.permissions,
+        })
+    }
+}
+
+/// A [`NewUser`] with the password already hashed, see [`NewUser::hash`].
+pub struct HashedUser {
+    pub username: String,
+    pub password: authentication::HashedPassword,
+    pub permissions: u16,
+}
+
+/// A user exactly as stored, for moving accounts between instances.
+///
+/// **Sensitive**: this carries the password hash and salt, which is what lets
+/// an import keep everyone's password working, and also what an attacker needs
+/// for an offline guessing attack. Treat exports like the database file itself:
+/// don't log them, don't leave them lying around, delete them after the import.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserExport {
+    pub username: String,
+    /// Self-describing hash string (`$argon2id$...`, `$2b$...`).
+    pub password_hash: String,
+    pub salt: String,
+    pub security_key: Option<String>,
+    pub permissions: u16,
+    /// -1 if not locked out.
+    pub lockout_time: i64,
+    /// Unix timestamp (seconds), missing from exports made before it was recorded,
+    /// those users get the time of the import.
+    #[serde(default)]
+    pub created_at: Option<i64>,
+}
+
+/// A counter kept on every user row, see [`DatabaseConnection::increment_user_counter`].
+///
+/// Only these columns can be incremented, the name that ends up in the SQL
+/// always comes from here and never from the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterColumn {
+    /// Objects the user has drawn, for usage tracking and quotas.
+    ObjectsDrawn,
+}
+
+impl CounterColumn {
+    fn column(self) -> &'static str {
+        match self {
+            CounterColumn::ObjectsDrawn => "objects_drawn",
+        }
+    }
 }
 
 pub struct DrawnObject {
@@ -30,15 +121,1598 @@ pub struct DrawnObject {
     //object boolean args
     pub bool_args: Vec<bool>,
 }
+/// Tuning knobs for a `DatabaseConnection`.
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    /// How long a statement waits for another connection's lock before SQLite
+    /// reports the database as busy. The waiting happens in SQLite's busy handler.
+    pub busy_timeout: Duration,
+    /// Longest username `create_user` accepts, in characters.
+    pub max_username_length: usize,
+    /// Algorithm for newly hashed passwords, existing hashes verify regardless.
+    pub password_hash: authentication::HashAlgorithm,
+    /// How long opening waits for another instance that is initializing or
+    /// migrating the same database file.
+    pub migration_lock_timeout: Duration,
+    /// Operations that take longer than this are logged at warn level, `None`
+    /// to never log them.
+    pub slow_query_threshold: Option<Duration>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::from_millis(300),
+            max_username_length: 32,
+            password_hash: authentication::HashAlgorithm::default(),
+            migration_lock_timeout: Duration::from_secs(30),
+            slow_query_threshold: Some(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Schema version this build creates and understands, bump it whenever init.sql changes
+/// existing tables in a way older builds can't handle, and add a migration script
+/// under `sql/migrations` that brings the previous version up to date.
+///
+/// Brand new tables don't need a bump: init.sql creates them on open and older
+/// builds never look at them.
+pub const SCHEMA_VERSION: i64 = 3;
+
+// (version, script) pairs, each script upgrades a database of that version to the next one
+// init.sql only creates missing tables, so changes to existing ones have to happen here
+const MIGRATIONS: [(i64, &str); 2] = [
+    (1, include_str!("sql/migrations/0002_user_created_at.sql")),
+    (2, include_str!("sql/migrations/0003_user_counters.sql")),
+];
+
 #[allow(dead_code)]
 pub struct DatabaseConnection {
     conn: rusqlite::Connection,
+    options: DbOptions,
+    path: String,
+    // Set when the last write ran out of space, cleared by the next one that succeeds
+    disk_full: Cell<bool>,
 }
 impl DatabaseConnection {
     pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
-        let conn = rusqlite::Connection::open(path)?;
+        Self::with_options(path, DbOptions::default())
+    }
+
+    /// A private database that only lives as long as the connection, for tests
+    /// and for running the server without a database.
+    pub fn in_memory() -> Result<Self, Box<dyn Error>> {
+        Self::new(Path::new(":memory:"))
+    }
+
+    /// Opens (or creates) the database at `path`.
+    ///
+    /// A damaged file, or one that isn't a database, fails with [`DbError::Corrupt`];
+    /// see [`quarantine_corrupt_file`] for getting it out of the way.
+    pub fn with_options(path: &Path, options: DbOptions) -> Result<Self, Box<dyn Error>> {
+        // SQLite opens lazily, corruption only shows up once the first statements run
+        let corrupt = |e: rusqlite::Error| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::NotADatabase | rusqlite::ErrorCode::DatabaseCorrupt) => {
+                DbError::Corrupt {
+                    path: path.display().to_string(),
+                    source: e,
+                }
+            }
+            Some(rusqlite::ErrorCode::DiskFull) => DbError::DiskFull {
+                path: path.display().to_string(),
+            },
+            _ => DbError::Sqlite(e),
+        };
+        let mut conn = rusqlite::Connection::open(path)?;
+        // The version check and init.sql run under SQLite's write lock, so
+        // instances starting at the same time take turns: the first one
+        // initializes, the others wait and then find the schema current
+        conn.busy_timeout(options.migration_lock_timeout)
+            .map_err(corrupt)?;
+        let tx = conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .map_err(|e| {
+                if is_busy(&e) {
+                    DbError::MigrationLockTimeout {
+                        path: path.display().to_string(),
+                        waited: options.migration_lock_timeout,
+                    }
+                } else {
+                    corrupt(e)
+                }
+            })?;
+        let found = check_schema_version(&tx).map_err(|e| match e {
+            DbError::Sqlite(e) => corrupt(e),
+            e => e,
+        })?;
+        if let Some(found) = found {
+            migrate(&tx, found)?;
+        }
         let sql = include_str!("sql/init.sql");
-        conn.execute_batch(sql)?;
-        Ok(Self { conn })
+        tx.execute_batch(sql).map_err(corrupt)?;
+        check_core_tables(&tx)?;
+        tx.execute(
+            "INSERT INTO SchemaVersion (id, version) VALUES (1, ?1)
+             ON CONFLICT(id) DO UPDATE SET version = excluded.version",
+            [SCHEMA_VERSION],
+        )
+        .map_err(corrupt)?;
+        tx.commit()?;
+        // Lock contention from here on is waited out by SQLite itself
+        conn.busy_timeout(options.busy_timeout)?;
+        Ok(Self {
+            conn,
+            options,
+            path: path.display().to_string(),
+            disk_full: Cell::new(false),
+        })
+    }
+
+    // Metrics and slow query logging for one operation, see instrument.rs
+    fn instrumented<T>(
+        &self,
+        operation: &'static str,
+        params: impl FnOnce() -> String,
+        op: impl FnOnce() -> Result<T, DbError>,
+    ) -> Result<T, DbError> {
+        instrument::instrumented(operation, self.options.slow_query_threshold, params, op)
+    }
+
+    /// [`instrumented`](Self::instrumented) for operations that write, turning
+    /// SQLite running out of space into [`DbError::DiskFull`].
+    ///
+    /// SQLite may or may not roll back the transaction a full disk interrupts,
+    /// so whatever is left of it is rolled back here before returning.
+    fn instrumented_write<T>(
+        &self,
+        operation: &'static str,
+        params: impl FnOnce() -> String,
+        op: impl FnOnce() -> Result<T, DbError>,
+    ) -> Result<T, DbError> {
+        let result = self.instrumented(operation, params, op);
+        match result {
+            Err(DbError::Sqlite(e))
+                if e.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull) =>
+            {
+                if !self.conn.is_autocommit() {
+                    let _ = self.conn.execute_batch("ROLLBACK");
+                }
+                self.disk_full.set(true);
+                Err(DbError::DiskFull {
+                    path: self.path.clone(),
+                })
+            }
+            result => {
+                if result.is_ok() {
+                    self.disk_full.set(false);
+                }
+                result
+            }
+        }
+    }
+
+    /// The algorithm new passwords are hashed with, for [`NewUser::hash`].
+    pub fn password_hash(&self) -> authentication::HashAlgorithm {
+        self.options.password_hash
+    }
+
+    /// Whether the last write failed because the disk (or the database's
+    /// `max_page_count`) is full. Stays set until a write succeeds again.
+    pub fn is_disk_full(&self) -> bool {
+        self.disk_full.get()
+    }
+
+    /// Runs a write, starting it over when its transaction's snapshot went stale.
+    ///
+    /// Waiting for a lock is left to SQLite's busy handler
+    /// ([`DbOptions::busy_timeout`]), there is no backoff in here: a busy error
+    /// that reaches this point means the wait already ran out, and retrying
+    /// would only wait again. The exception is a transaction that read before
+    /// another connection wrote (`SQLITE_BUSY_SNAPSHOT`), SQLite fails that one
+    /// right away because waiting can't help, but running it again can. That
+    /// happens at most `STALE_SNAPSHOT_RETRIES` times.
+    fn retry_busy<T>(
+        &self,
+        mut op: impl FnMut(&rusqlite::Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op(&self.conn) {
+                Err(e) if is_stale_snapshot(&e) && attempt < STALE_SNAPSHOT_RETRIES => {
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Cheap liveness probe that runs `SELECT 1` against the connection.
+    ///
+    /// The statement is cached on the connection so frequent calls (e.g. from
+    /// load balancer health checks) don't re-prepare it every time.
+    pub fn ping(&self) -> Result<(), DbError> {
+        self.instrumented("ping", String::new, || {
+            self.conn
+                .prepare_cached("SELECT 1")?
+                .query_row([], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+
+    /// Copies everything in the write-ahead log back into the main database file
+    /// and truncates the log.
+    ///
+    /// Without WAL mode this is a no-op, so it is always safe to call.
+    pub fn checkpoint(&self) -> Result<(), DbError> {
+        self.instrumented("checkpoint", String::new, || {
+            self.conn
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+
+    /// Checkpoints the WAL and closes the connection.
+    ///
+    /// This consumes `self`, so it can only be called once nothing else is
+    /// using the connection, typically during graceful shutdown. Just dropping
+    /// the connection also closes it, but may leave a large `-wal` file behind,
+    /// which makes a cold copy of the database file incomplete.
+    pub fn close(self) -> Result<(), DbError> {
+        let slow_threshold = self.options.slow_query_threshold;
+        instrument::instrumented("close", slow_threshold, String::new, || {
+            self.checkpoint()?;
+            self.conn.close().map_err(|(_, e)| DbError::Sqlite(e))
+        })
+    }
+
+    /// Runs SQLite's `PRAGMA quick_check` and returns the problems it found,
+    /// empty when the database is fine.
+    ///
+    /// Reads every page of the file, which also pulls all of it into the caches.
+    pub fn quick_check(&self) -> Result<Vec<String>, DbError> {
+        self.instrumented("quick_check", String::new, || {
+            let mut check = self.conn.prepare("PRAGMA quick_check")?;
+            let problems = check
+                .query_map([], |row| row.get::<_, String>(0))?
+                .filter(|row| !matches!(row.as_deref(), Ok("ok")))
+                .collect::<Result<_, _>>()?;
+            Ok(problems)
+        })
+    }
+
+    /// Returns true if there are no users and no saved canvases yet.
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        self.instrumented("is_empty", String::new, || {
+            let has_data: bool = self.conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM Users) OR EXISTS (SELECT 1 FROM RoomSnapshots)",
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(!has_data)
+        })
+    }
+
+    /// Whether at least one user exists, false on a fresh install that still
+    /// needs its first admin.
+    pub fn exists_any_user(&self) -> Result<bool, DbError> {
+        self.instrumented("exists_any_user", String::new, || {
+            let exists = self
+                .conn
+                .prepare_cached("SELECT 1 FROM Users LIMIT 1")?
+                .exists([])?;
+            Ok(exists)
+        })
+    }
+
+    /// Creates a user, hashing their password with a fresh salt.
+    ///
+    /// Fails with `DbError::InvalidUsername` if the username is empty, too long,
+    /// contains control characters or is padded with whitespace, and with
+    /// `DbError::DuplicateUsername` if the username is taken, which includes
+    /// being [reserved](Self::reserve_username) for someone else's registration.
+    pub fn create_user(&self, user: &NewUser) -> Result<(), DbError> {
+        authentication::validate_username(&user.username, self.options.max_username_length)
+            .map_err(DbError::InvalidUsername)?;
+        // Hashing is slow on purpose, keep it out of the slow query timing
+        self.create_hashed_user(&user.hash(self.options.password_hash)?)
+    }
+
+    /// [`create_user`](Self::create_user) for a password hashed beforehand with
+    /// [`NewUser::hash`], so the connection is only busy for the insert.
+    pub fn create_hashed_user(&self, user: &HashedUser) -> Result<(), DbError> {
+        authentication::validate_username(&user.username, self.options.max_username_length)
+            .map_err(DbError::InvalidUsername)?;
+        self.instrumented_write("create_user", String::new, || {
+            let result = self.retry_busy(|conn| insert_unreserved_user(conn, user));
+            match result {
+                Ok(1) => Ok(()),
+                Ok(_) => Err(DbError::DuplicateUsername(user.username.clone())),
+                Err(e) if is_unique_violation(&e) => {
+                    Err(DbError::DuplicateUsername(user.username.clone()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Holds `username` for `ttl`, for a registration that still has to be
+    /// confirmed (e.g. by email) before the account exists.
+    ///
+    /// Returns the token that [`confirm_registration`](Self::confirm_registration)
+    /// and [`release_username`](Self::release_username) ask for, so only whoever
+    /// reserved the name can use the reservation. `None` if the name is taken,
+    /// by an account or by an unexpired reservation; an expired one is simply
+    /// replaced. Validated like in [`create_user`](Self::create_user). One
+    /// statement, so of two concurrent calls for the same name at most one gets it.
+    pub fn reserve_username(
+        &self,
+        username: &str,
+        ttl: Duration,
+    ) -> Result<Option<String>, DbError> {
+        self.instrumented_write("reserve_username", String::new, || {
+            authentication::validate_username(username, self.options.max_username_length)
+                .map_err(DbError::InvalidUsername)?;
+            let ttl = i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX);
+            let token = authentication::generate_token();
+            let reserved = self.retry_busy(|conn| {
+                conn.execute(
+                    "INSERT INTO PendingRegistrations (username, token, expires_at)
+                     SELECT ?1, ?3, unixepoch() + ?2
+                     WHERE NOT EXISTS (SELECT 1 FROM Users WHERE username = ?1)
+                     ON CONFLICT(username) DO UPDATE
+                     SET token = excluded.token, expires_at = excluded.expires_at
+                     WHERE expires_at <= unixepoch()",
+                    (username, ttl, &token),
+                )
+            })?;
+            Ok((reserved == 1).then_some(token))
+        })
+    }
+
+    /// Turns the reservation for `user.username` into an account, in one transaction.
+    ///
+    /// Takes the password hashed already ([`NewUser::hash`]), so the connection
+    /// isn't held up by it, and the token [`reserve_username`](Self::reserve_username)
+    /// returned. Fails with `DbError::NotReserved` if there is no unexpired
+    /// reservation for the name with that token, nothing is created then.
+    pub fn confirm_registration(&self, user: &HashedUser, token: &str) -> Result<(), DbError> {
+        self.instrumented_write("confirm_registration", String::new, || {
+            let result = self.retry_busy(|conn| {
+                let tx = conn.unchecked_transaction()?;
+                let released = tx.execute(
+                    "DELETE FROM PendingRegistrations
+                     WHERE username = ?1 AND token = ?2 AND expires_at > unixepoch()",
+                    (&user.username, token),
+                )?;
+                if released == 0 {
+                    return Ok(false);
+                }
+                insert_unreserved_user(&tx, user)?;
+                tx.commit()?;
+                Ok(true)
+            });
+            match result {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(DbError::NotReserved(user.username.clone())),
+                Err(e) if is_unique_violation(&e) => {
+                    Err(DbError::DuplicateUsername(user.username.clone()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Gives up the reservation for `username` before it expires, e.g. when the
+    /// registration is abandoned. Needs the reservation's token like
+    /// [`confirm_registration`](Self::confirm_registration), false if there was
+    /// no reservation with that token.
+    pub fn release_username(&self, username: &str, token: &str) -> Result<bool, DbError> {
+        self.instrumented_write("release_username", String::new, || {
+            let released = self.retry_busy(|conn| {
+                conn.execute(
+                    "DELETE FROM PendingRegistrations WHERE username = ?1 AND token = ?2",
+                    (username, token),
+                )
+            })?;
+            Ok(released == 1)
+        })
+    }
+
+    /// Deletes expired reservations and returns how many there were.
+    ///
+    /// Only housekeeping: an expired reservation no longer holds its name
+    /// whether or not it has been deleted.
+    pub fn release_expired_reservations(&self) -> Result<usize, DbError> {
+        self.instrumented_write("release_expired_reservations", String::new, || {
+            let released = self.retry_busy(|conn| {
+                conn.execute(
+                    "DELETE FROM PendingRegistrations WHERE expires_at <= unixepoch()",
+                    [],
+                )
+            })?;
+            Ok(released)
+        })
+    }
+
+    /// Creates `user` only if there are no users at all yet, for first-run setup.
+    ///
+    /// Returns false, creating nothing, once any user exists. The check and the
+    /// insert are one statement, so of two concurrent calls (even from separate
+    /// instances sharing the file) at most one creates its user. While there are
+    /// no users yet, fails with `DbError::DuplicateUsername` if the name is
+    /// [reserved](Self::reserve_username).
+    pub fn create_first_user(&self, user: &NewUser) -> Result<bool, DbError> {
+        authentication::validate_username(&user.username, self.options.max_username_length)
+            .map_err(DbError::InvalidUsername)?;
+        self.create_first_hashed_user(&user.hash(self.options.password_hash)?)
+    }
+
+    /// [`create_first_user`](Self::create_first_user) for a password hashed
+    /// beforehand with [`NewUser::hash`].
+    pub fn create_first_hashed_user(&self, user: &HashedUser) -> Result<bool, DbError> {
+        authentication::validate_username(&user.username, self.options.max_username_length)
+            .map_err(DbError::InvalidUsername)?;
+        self.instrumented_write("create_first_user", String::new, || {
+            let created = self.retry_busy(|conn| {
+                let created = conn.execute(
+                    &format!(
+                        "INSERT INTO Users (username, password_hash, security_key, salt, permissions, lockout_time, created_at)
+                         SELECT ?1, ?2, NULL, ?3, ?4, -1, unixepoch()
+                         WHERE NOT EXISTS (SELECT 1 FROM Users) AND {}",
+                        NOT_RESERVED
+                    ),
+                    (
+                        &user.username,
+                        &user.password.hash,
+                        &user.password.salt,
+                        user.permissions,
+                    ),
+                )?;
+                // Nothing created, either there are users or the name is held.
+                // Setup being done wins, whether or not the name is held too
+                if created == 0 {
+                    let any_user: bool = conn.query_row(
+                        "SELECT EXISTS (SELECT 1 FROM Users)",
+                        [],
+                        |row| row.get(0),
+                    )?;
+                    if !any_user && is_reserved(conn, &user.username)? {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(created == 1))
+            })?;
+            created.ok_or_else(|| DbError::DuplicateUsername(user.username.clone()))
+        })
+    }
+
+    /// Creates many users at once, all or nothing.
+    ///
+    /// Usernames are validated up front, passwords are hashed in parallel (one
+    /// thread per CPU, hashing is by far the slowest part) and the rows are
+    /// inserted in a single transaction. If any user is rejected, for example a
+    /// duplicate or a [reserved](Self::reserve_username) name, nothing is
+    /// inserted and the error is a `DbError::BatchUser` naming the offending
+    /// username.
+    pub fn create_users(&self, users: &[NewUser]) -> Result<(), DbError> {
+        for user in users {
+            authentication::validate_username(&user.username, self.options.max_username_length)
+                .map_err(|e| batch_error(&user.username, DbError::InvalidUsername(e)))?;
+        }
+        let hashed = hash_passwords_parallel(users, self.options.password_hash)?;
+        self.instrumented_write("create_users", || format!("users={}", users.len()), || {
+            // Index of the row being inserted, so a failure can name its user
+            let current = std::cell::Cell::new(0);
+            let result = self.retry_busy(|conn| {
+                let tx = conn.unchecked_transaction()?;
+                {
+                    let mut insert = tx.prepare_cached(&format!(
+                        "INSERT INTO Users (username, password_hash, security_key, salt, permissions, lockout_time, created_at)
+                         SELECT ?1, ?2, NULL, ?3, ?4, -1, unixepoch() WHERE {}",
+                        NOT_RESERVED
+                    ))?;
+                    for (i, (user, hashed)) in users.iter().zip(&hashed).enumerate() {
+                        current.set(i);
+                        // Reserved, dropping the transaction rolls back the rows before it
+                        if insert.execute((&user.username, &hashed.hash, &hashed.salt, user.permissions))? == 0 {
+                            return Ok(false);
+                        }
+                    }
+                }
+                tx.commit()?;
+                Ok(true)
+            });
+            match result {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    let username = &users[current.get()].username;
+                    Err(batch_error(
+                        username,
+                        DbError::DuplicateUsername(username.clone()),
+                    ))
+                }
+                Err(e) if is_unique_violation(&e) => {
+                    let username = &users[current.get()].username;
+                    Err(batch_error(
+                        username,
+                        DbError::DuplicateUsername(username.clone()),
+                    ))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Renames a user, keeping their password and permissions.
+    ///
+    /// The new name is validated like in [`create_user`](Self::create_user).
+    /// Usernames are compared exactly (case-sensitive) everywhere, so `alice`
+    /// to `Alice` is a rename like any other. Fails with `DbError::UserNotFound`
+    /// if `old` doesn't exist and `DbError::DuplicateUsername` if `new` is taken.
+    ///
+    /// Runs in a transaction; `Users` is the only table referencing users by
+    /// username so far, anything that does later has to be updated in here too.
+    /// A name [reserved](Self::reserve_username) for a registration counts as taken.
+    pub fn rename_user(&self, old: &str, new: &str) -> Result<(), DbError> {
+        self.instrumented_write("rename_user", String::new, || {
+            authentication::validate_username(new, self.options.max_username_length)
+                .map_err(DbError::InvalidUsername)?;
+            let result = self.retry_busy(|conn| {
+                let tx = conn.unchecked_transaction()?;
+                if is_reserved(&tx, new)? {
+                    return Ok(None);
+                }
+                let renamed = tx.execute(
+                    "UPDATE Users SET username = ?2 WHERE username = ?1",
+                    (old, new),
+                )?;
+                tx.commit()?;
+                Ok(Some(renamed))
+            });
+            match result {
+                Ok(None) => Err(DbError::DuplicateUsername(new.to_string())),
+                Ok(Some(0)) => Err(DbError::UserNotFound(old.to_string())),
+                Ok(Some(_)) => Ok(()),
+                Err(e) if is_unique_violation(&e) => {
+                    Err(DbError::DuplicateUsername(new.to_string()))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Every user including their password hash and salt, ordered by username.
+    ///
+    /// See [`UserExport`] for why the result has to be handled with care.
+    pub fn export_users(&self) -> Result<Vec<UserExport>, DbError> {
+        self.instrumented("export_users", String::new, || {
+            let mut select = self.conn.prepare(
+                "SELECT username, password_hash, salt, security_key, permissions, lockout_time, created_at
+                 FROM Users ORDER BY username",
+            )?;
+            let users = select
+                .query_map([], |row| {
+                    Ok(UserExport {
+                        username: row.get(0)?,
+                        password_hash: row.get(1)?,
+                        salt: row.get(2)?,
+                        security_key: row.get(3)?,
+                        permissions: row.get(4)?,
+                        lockout_time: row.get(5)?,
+                        created_at: row.get(6)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(users)
+        })
+    }
+
+    /// Inserts users from [`export_users`](Self::export_users) as they are, all or nothing.
+    ///
+    /// Hashes are stored unchanged, so passwords keep working; usernames are
+    /// validated against this instance's limits. A rejected, duplicate or
+    /// [reserved](Self::reserve_username) user fails the whole import with a `DbError::BatchUser` naming it.
+    pub fn import_users(&self, users: &[UserExport]) -> Result<(), DbError> {
+        self.instrumented_write("import_users", || format!("users={}", users.len()), || {
+            for user in users {
+                authentication::validate_username(&user.username, self.options.max_username_length)
+                    .map_err(|e| batch_error(&user.username, DbError::InvalidUsername(e)))?;
+            }
+            let current = std::cell::Cell::new(0);
+            let result = self.retry_busy(|conn| {
+                let tx = conn.unchecked_transaction()?;
+                {
+                    let mut insert = tx.prepare_cached(&format!(
+                        "INSERT INTO Users (username, password_hash, security_key, salt, permissions, lockout_time, created_at)
+                         SELECT ?1, ?2, ?3, ?4, ?5, ?6, COALESCE(?7, unixepoch()) WHERE {}",
+                        NOT_RESERVED
+                    ))?;
+                    for (i, user) in users.iter().enumerate() {
+                        current.set(i);
+                        let inserted = insert.execute((
+                            &user.username,
+                            &user.password_hash,
+                            &user.security_key,
+                            &user.salt,
+                            user.permissions,
+                            user.lockout_time,
+                            user.created_at,
+                        ))?;
+                        if inserted == 0 {
+                            return Ok(false);
+                        }
+                    }
+                }
+                tx.commit()?;
+                Ok(true)
+            });
+            match result {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    let username = &users[current.get()].username;
+                    Err(batch_error(
+                        username,
+                        DbError::DuplicateUsername(username.clone()),
+                    ))
+                }
+                Err(e) if is_unique_violation(&e) => {
+                    let username = &users[current.get()].username;
+                    Err(batch_error(
+                        username,
+                        DbError::DuplicateUsername(username.clone()),
+                    ))
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    /// Adds `by` (which may be negative) to one of a user's counters and returns
+    /// the new value.
+    ///
+    /// A single `UPDATE ... RETURNING`, so concurrent increments from several
+    /// connections never lose an update. Fails with `DbError::UserNotFound` if
+    /// there is no such user.
+    pub fn increment_user_counter(
+        &self,
+        username: &str,
+        column: CounterColumn,
+        by: i64,
+    ) -> Result<i64, DbError> {
+        self.instrumented_write(
+            "increment_user_counter",
+            || format!("column={}, by={}", column.column(), by),
+            || {
+                let column = column.column();
+                let sql = format!(
+                    "UPDATE Users SET {0} = {0} + ?2 WHERE username = ?1 RETURNING {0}",
+                    column
+                );
+                let value = self
+                    .retry_busy(|conn| {
+                        conn.prepare_cached(&sql)?
+                            .query_row((username, by), |row| row.get(0))
+                            .optional()
+                    })?
+                    .ok_or_else(|| DbError::UserNotFound(username.to_string()))?;
+                Ok(value)
+            },
+        )
+    }
+
+    /// Looks up a user by username, `None` if there is no such user.
+    pub fn get_user(&self, username: &str) -> Result<Option<User>, DbError> {
+        self.instrumented("get_user", String::new, || {
+            let user = self
+                .conn
+                .prepare_cached(&format!(
+                    "SELECT {} FROM Users WHERE username = ?1",
+                    USER_COLUMNS
+                ))?
+                .query_row([username], user_from_row)
+                .optional()?;
+            Ok(user)
+        })
+    }
+
+    /// Looks up many users at once, ordered by username; unknown usernames are
+    /// left out rather than being an error.
+    ///
+    /// For list views, one `IN (...)` query per [`USERS_PER_QUERY`] usernames
+    /// instead of a query for each of them.
+    pub fn get_users(&self, usernames: &[String]) -> Result<Vec<User>, DbError> {
+        // Chunks must not share a username, or it would be found twice
+        let mut usernames: Vec<&str> = usernames.iter().map(String::as_str).collect();
+        usernames.sort_unstable();
+        usernames.dedup();
+        self.instrumented(
+            "get_users",
+            || format!("usernames={}", usernames.len()),
+            || {
+                let mut users = Vec::with_capacity(usernames.len());
+                for chunk in usernames.chunks(USERS_PER_QUERY) {
+                    let placeholders = vec!["?"; chunk.len()].join(", ");
+                    let mut select = self.conn.prepare(&format!(
+                        "SELECT {} FROM Users WHERE username IN ({}) ORDER BY username",
+                        USER_COLUMNS, placeholders
+                    ))?;
+                    let found =
+                        select.query_map(rusqlite::params_from_iter(chunk), user_from_row)?;
+                    for user in found {
+                        users.push(user?);
+                    }
+                }
+                Ok(users)
+            },
+        )
+    }
+
+    /// Looks up just the permissions of a user, `None` if there is no such user.
+    ///
+    /// For authorization checks, which run on every request and have no use for the
+    /// password hash, so only the one column is read and the statement is cached.
+    pub fn get_permissions(&self, username: &str) -> Result<Option<u16>, DbError> {
+        self.instrumented("get_permissions", String::new, || {
+            let permissions = self
+                .conn
+                .prepare_cached("SELECT permissions FROM Users WHERE username = ?1")?
+                .query_row([username], |row| row.get(0))
+                .optional()?;
+            Ok(permissions)
+        })
+    }
+
+    /// Reads a setting stored with [`set_setting`](Self::set_setting), `None` if it was never set.
+    pub fn get_setting<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, DbError> {
+        self.instrumented(
+            "get_setting",
+            || format!("key={}", key),
+            || {
+                let value: Option<String> = self
+                    .conn
+                    .prepare_cached("SELECT value FROM Settings WHERE key = ?1")?
+                    .query_row([key], |row| row.get(0))
+                    .optional()?;
+                value
+                    .map(|json| {
+                        serde_json::from_str(&json).map_err(|source| DbError::Setting {
+                            key: key.to_string(),
+                            source,
+                        })
+                    })
+                    .transpose()
+            },
+        )
+    }
+
+    /// Like [`get_setting`](Self::get_setting), falling back to `default` when the key isn't set.
+    pub fn get_setting_or<T: DeserializeOwned>(&self, key: &str, default: T) -> Result<T, DbError> {
+        Ok(self.get_setting(key)?.unwrap_or(default))
+    }
+
+    /// Stores a setting as JSON, replacing any previous value for the key.
+    pub fn set_setting<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<(), DbError> {
+        self.instrumented_write("set_setting", || format!("key={}", key), || {
+            let json = serde_json::to_string(value).map_err(|source| DbError::Setting {
+                key: key.to_string(),
+                source,
+            })?;
+            self.retry_busy(|conn| {
+                conn.prepare_cached(
+                    "INSERT INTO Settings (key, value, updated_at) VALUES (?1, ?2, unixepoch())
+                     ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+                )?
+                .execute((key, &json))
+            })?;
+            Ok(())
+        })
+    }
+
+    /// Stores the serialized canvas for a room, replacing any previous snapshot.
+    ///
+    /// Runs on every autosave tick for each dirty room, so the statement is cached.
+    pub fn save_room_snapshot(&self, room: &str, snapshot: &str) -> Result<(), DbError> {
+        self.instrumented_write("save_room_snapshot", || format!("room={}, bytes={}", room, snapshot.len()), || {
+            self.retry_busy(|conn| {
+                conn.prepare_cached(
+                    "INSERT INTO RoomSnapshots (room, snapshot, updated_at) VALUES (?1, ?2, unixepoch())
+                     ON CONFLICT(room) DO UPDATE SET snapshot = excluded.snapshot, updated_at = excluded.updated_at",
+                )?
+                .execute((room, snapshot))
+            })?;
+            Ok(())
+        })
+    }
+
+    /// Every room that has a saved canvas, most recently saved first.
+    pub fn room_snapshot_names(&self) -> Result<Vec<String>, DbError> {
+        self.instrumented("room_snapshot_names", String::new, || {
+            let mut select = self
+                .conn
+                .prepare("SELECT room FROM RoomSnapshots ORDER BY updated_at DESC, room")?;
+            let rooms = select
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(rooms)
+        })
+    }
+
+    /// Loads the latest serialized canvas for a room, `None` if it was never saved.
+    ///
+    /// Runs whenever a room is joined or drawn in while not loaded, so the statement is cached.
+    pub fn load_room_snapshot(&self, room: &str) -> Result<Option<String>, DbError> {
+        self.instrumented(
+            "load_room_snapshot",
+            || format!("room={}", room),
+            || {
+                let snapshot = self
+                    .conn
+                    .prepare_cached("SELECT snapshot FROM RoomSnapshots WHERE room = ?1")?
+                    .query_row([room], |row| row.get(0))
+                    .optional()?;
+                Ok(snapshot)
+            },
+        )
+    }
+}
+
+/// Moves a corrupt database file (and its `-wal`/`-shm` files) aside, so a fresh
+/// one can be created in its place. Returns where the database file went.
+///
+/// The file is renamed to `<path>.corrupt-<unix timestamp>`, nothing is deleted.
+pub fn quarantine_corrupt_file(path: &Path) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let moved = |suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let target = moved(&format!(".corrupt-{}", timestamp));
+    fs::rename(path, &target)?;
+    // A stale WAL would be replayed into the new database, so it has to go too
+    for journal in ["-wal", "-shm"] {
+        let file = moved(journal);
+        if file.exists() {
+            fs::rename(&file, moved(&format!(".corrupt-{}{}", timestamp, journal)))?;
+        }
+    }
+    Ok(target)
+}
+
+fn batch_error(username: &str, error: DbError) -> DbError {
+    DbError::BatchUser {
+        username: username.to_string(),
+        source: Box::new(error),
+    }
+}
+
+// Password hashing is CPU bound, so split the users over one thread per core
+fn hash_passwords_parallel(
+    users: &[NewUser],
+    algorithm: authentication::HashAlgorithm,
+) -> Result<Vec<authentication::HashedPassword>, DbError> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = users.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = users
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|user| {
+                            authentication::hash_password_with(algorithm, &user.password)
+                                .map_err(|e| batch_error(&user.username, DbError::Hashing(e)))
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+        let mut hashed = Vec::with_capacity(users.len());
+        for worker in workers {
+            hashed.extend(worker.join().expect("password hashing thread panicked")?);
+        }
+        Ok(hashed)
+    })
+}
+
+// SQL condition that holds unless username ?1 has an unexpired reservation
+// Every insert into Users goes through it, except confirm_registration's,
+// which releases the reservation first
+const NOT_RESERVED: &str = "NOT EXISTS (SELECT 1 FROM PendingRegistrations WHERE username = ?1 AND expires_at > unixepoch())";
+
+fn is_reserved(conn: &rusqlite::Connection, username: &str) -> rusqlite::Result<bool> {
+    conn.query_row(&format!("SELECT NOT {}", NOT_RESERVED), [username], |row| {
+        row.get(0)
+    })
+}
+
+// Insert a new user unless the name is held by an unexpired reservation,
+// returns the number of rows inserted (0 when it is reserved)
+fn insert_unreserved_user(
+    conn: &rusqlite::Connection,
+    user: &HashedUser,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        &format!(
+            "INSERT INTO Users (username, password_hash, security_key, salt, permissions, lockout_time, created_at)
+             SELECT ?1, ?2, NULL, ?3, ?4, -1, unixepoch() WHERE {}",
+            NOT_RESERVED
+        ),
+        (
+            &user.username,
+            &user.password.hash,
+            &user.password.salt,
+            user.permissions,
+        ),
+    )
+}
+
+/// Most usernames bound in one [`get_users`](DatabaseConnection::get_users) query,
+/// well below SQLite's limit on variables per statement (999 in older builds).
+pub const USERS_PER_QUERY: usize = 500;
+
+// Columns in the order user_from_row reads them
+const USER_COLUMNS: &str =
+    "username, password_hash, security_key, salt, permissions, lockout_time, created_at";
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        username: row.get(0)?,
+        password_hash: row.get(1)?,
+        security_key: row.get(2)?,
+        salt: row.get(3)?,
+        permissions: row.get(4)?,
+        lockout_time: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+// Refuse databases from a newer schema version, or an older one without a
+// migration path, before init.sql touches them
+// None for a brand new database; one from before versioning has the version 1 schema
+fn check_schema_version(conn: &rusqlite::Connection) -> Result<Option<i64>, DbError> {
+    let has_table = |name: &str| -> rusqlite::Result<bool> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+            [name],
+            |row| row.get(0),
+        )
+    };
+    let found = if has_table("SchemaVersion")? {
+        conn.query_row(
+            "SELECT version FROM SchemaVersion WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+    } else {
+        None
+    };
+    let found = match found {
+        Some(found) => found,
+        None if has_table("Users")? => 1,
+        None => return Ok(None),
+    };
+    let migratable = (found..SCHEMA_VERSION).all(|v| MIGRATIONS.iter().any(|(from, _)| *from == v));
+    if found > SCHEMA_VERSION || !migratable {
+        return Err(DbError::SchemaVersionMismatch {
+            found,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    Ok(Some(found))
+}
+
+// Bring a database checked by check_schema_version up to SCHEMA_VERSION, one version at a time
+fn migrate(conn: &rusqlite::Connection, found: i64) -> Result<(), DbError> {
+    for (from, script) in MIGRATIONS.iter().filter(|(from, _)| *from >= found) {
+        conn.execute_batch(script).map_err(|e| DbError::Migration {
+            from: *from,
+            source: e,
+        })?;
+    }
+    Ok(())
+}
+
+// Tables every other query relies on
+const CORE_TABLES: [&str; 5] = [
+    "SchemaVersion",
+    "Users",
+    "PendingRegistrations",
+    "Settings",
+    "RoomSnapshots",
+];
+
+// An empty or truncated init.sql runs without complaint, better to stop
+// here than to fail on the first query that needs a missing table
+fn check_core_tables(conn: &rusqlite::Connection) -> Result<(), DbError> {
+    let mut exists = conn.prepare(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+    )?;
+    for table in CORE_TABLES {
+        if !exists.query_row([table], |row| row.get::<_, bool>(0))? {
+            return Err(DbError::MissingTable(table));
+        }
+    }
+    Ok(())
+}
+
+// True for the transient lock contention errors worth retrying
+fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+/// How often a write whose snapshot went stale is started over, see `retry_busy`.
+const STALE_SNAPSHOT_RETRIES: u32 = 5;
+
+// A write that has to start over, see retry_busy
+fn is_stale_snapshot(err: &rusqlite::Error) -> bool {
+    matches!(err, rusqlite::Error::SqliteFailure(e, _)
+        if e.extended_code == rusqlite::ffi::SQLITE_BUSY_SNAPSHOT)
+}
+
+// True for primary key / UNIQUE constraint failures
+fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(e, _)
+            if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                || e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PASSWORD: &str = "password";
+
+    // Users every test can rely on: (username, permissions)
+    const TEST_USERS: [(&str, u16); 3] = [("alice", 0), ("bob", 1), ("carol", 3)];
+
+    fn test_user(username: &str, permissions: u16) -> NewUser {
+        NewUser {
+            username: username.to_string(),
+            password: TEST_PASSWORD.to_string(),
+            permissions,
+        }
+    }
+
+    // An in-memory database with TEST_USERS already created
+    fn seed_test_users() -> DatabaseConnection {
+        let db = DatabaseConnection::in_memory().unwrap();
+        let users: Vec<NewUser> = TEST_USERS
+            .iter()
+            .map(|(name, permissions)| test_user(name, *permissions))
+            .collect();
+        db.create_users(&users).unwrap();
+        db
+    }
+
+    // A fresh path in the temp directory, unique per test and process
+    fn temp_db_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rustcanvas-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_ping() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        assert!(db.ping().is_ok());
+    }
+
+    #[test]
+    fn test_rejects_other_schema_versions() {
+        let path = temp_db_path("schema");
+        let db = DatabaseConnection::new(&path).unwrap();
+        db.conn
+            .execute(
+                "UPDATE SchemaVersion SET version = ?1",
+                [SCHEMA_VERSION + 1],
+            )
+            .unwrap();
+        drop(db);
+
+        let err = DatabaseConnection::new(&path).err().unwrap();
+        let message = err.to_string();
+        assert!(message.contains(&(SCHEMA_VERSION + 1).to_string()));
+        assert!(message.contains(&SCHEMA_VERSION.to_string()));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_migrates_version_1_databases() {
+        let path = temp_db_path("migrate-v1");
+        let old = rusqlite::Connection::open(&path).unwrap();
+        old.execute_batch(
+            "CREATE TABLE SchemaVersion (id INTEGER PRIMARY KEY CHECK (id = 1), version INTEGER NOT NULL);
+             INSERT INTO SchemaVersion (id, version) VALUES (1, 1);
+             CREATE TABLE Users (
+                 username TEXT NOT NULL PRIMARY KEY,
+                 password_hash TEXT NOT NULL,
+                 security_key TEXT,
+                 salt TEXT NOT NULL,
+                 permissions UNSIGNED SMALLINT NOT NULL,
+                 lockout_time BIGINT NOT NULL
+             );
+             INSERT INTO Users VALUES ('alice', 'hash', NULL, 'salt', 0, -1);",
+        )
+        .unwrap();
+        drop(old);
+
+        let db = DatabaseConnection::new(&path).unwrap();
+        let alice = db.get_user("alice").unwrap().unwrap();
+        assert!(alice.created_at > 0);
+        let version: i64 = db
+            .conn
+            .query_row("SELECT version FROM SchemaVersion", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_missing_core_table_is_named() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE SchemaVersion (id INTEGER PRIMARY KEY)")
+            .unwrap();
+        assert!(matches!(
+            check_core_tables(&conn),
+            Err(DbError::MissingTable("Users"))
+        ));
+    }
+
+    #[test]
+    fn test_open_waits_for_the_migration_lock() {
+        let path = temp_db_path("migration-lock");
+        drop(DatabaseConnection::new(&path).unwrap());
+        let other = rusqlite::Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+        let options = DbOptions {
+            migration_lock_timeout: Duration::from_millis(50),
+            ..DbOptions::default()
+        };
+        let err = DatabaseConnection::with_options(&path, options.clone())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::MigrationLockTimeout { .. })
+        ));
+        other.execute_batch("COMMIT").unwrap();
+        assert!(DatabaseConnection::with_options(&path, options).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_corrupt_file_is_detected_and_quarantined() {
+        let path = temp_db_path("corrupt");
+        std::fs::write(&path, b"this is definitely not an sqlite database file").unwrap();
+
+        let err = DatabaseConnection::new(&path).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DbError>(),
+            Some(DbError::Corrupt { .. })
+        ));
+        let moved = quarantine_corrupt_file(&path).unwrap();
+        assert!(!path.exists());
+        assert!(DatabaseConnection::new(&path).unwrap().is_empty().unwrap());
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&moved);
+    }
+
+    #[test]
+    fn test_rename_user() {
+        let db = seed_test_users();
+        db.rename_user("bob", "Bob").unwrap();
+        assert_eq!(db.get_permissions("bob").unwrap(), None);
+        assert_eq!(db.get_permissions("Bob").unwrap(), Some(1));
+        assert!(matches!(
+            db.rename_user("Bob", "alice"),
+            Err(DbError::DuplicateUsername(name)) if name == "alice"
+        ));
+        assert!(matches!(
+            db.rename_user("bob", "dave"),
+            Err(DbError::UserNotFound(name)) if name == "bob"
+        ));
+        assert!(matches!(
+            db.rename_user("Bob", " dave"),
+            Err(DbError::InvalidUsername(_))
+        ));
+    }
+
+    #[test]
+    fn test_seeded_users_round_trip() {
+        let db = seed_test_users();
+        assert!(!db.is_empty().unwrap());
+        for (username, permissions) in TEST_USERS {
+            assert_eq!(db.get_permissions(username).unwrap(), Some(permissions));
+            let hash: String = db
+                .conn
+                .query_row(
+                    "SELECT password_hash FROM Users WHERE username = ?1",
+                    [username],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(authentication::verify_password(TEST_PASSWORD, &hash));
+            assert!(!authentication::verify_password("wrong password", &hash));
+            assert!(db.get_user(username).unwrap().unwrap().created_at > 0);
+        }
+        assert_eq!(db.get_permissions("mallory").unwrap(), None);
+        assert!(db.get_user("mallory").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_hot_lookups_use_an_index() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        let plan = |sql: &str| -> String {
+            let mut explain = db
+                .conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+                .unwrap();
+            // The plan doesn't depend on the values, NULLs will do
+            let params = vec![rusqlite::types::Null; explain.parameter_count()];
+            let details = explain
+                .query_map(rusqlite::params_from_iter(params), |row| {
+                    row.get::<_, String>(3)
+                })
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            details.join("; ")
+        };
+        for sql in [
+            "SELECT password_hash FROM Users WHERE username = ?1",
+            "SELECT permissions FROM Users WHERE username = ?1",
+            "UPDATE Users SET objects_drawn = objects_drawn + ?2 WHERE username = ?1",
+            "SELECT snapshot FROM RoomSnapshots WHERE room = ?1",
+            "SELECT value FROM Settings WHERE key = ?1",
+        ] {
+            let plan = plan(sql);
+            assert!(
+                plan.contains("USING"),
+                "{} doesn't use an index: {}",
+                sql,
+                plan
+            );
+            assert!(!plan.contains("SCAN"), "{} scans the table: {}", sql, plan);
+        }
+        let plan = plan("SELECT room FROM RoomSnapshots ORDER BY updated_at DESC, room");
+        assert!(plan.contains("RoomSnapshotsByUpdate"), "{}", plan);
+        assert!(!plan.contains("TEMP B-TREE"), "{}", plan);
+    }
+
+    #[test]
+    fn test_reserve_username() {
+        let db = seed_test_users();
+        let hour = Duration::from_secs(3600);
+        let hashed = |username: &str, permissions| {
+            test_user(username, permissions)
+                .hash(db.password_hash())
+                .unwrap()
+        };
+        let token = db.reserve_username("dave", hour).unwrap().unwrap();
+        assert_eq!(db.reserve_username("dave", hour).unwrap(), None);
+        assert_eq!(db.reserve_username("alice", hour).unwrap(), None);
+        // Held against every other way of taking the name
+        assert!(matches!(
+            db.create_user(&test_user("dave", 0)),
+            Err(DbError::DuplicateUsername(_))
+        ));
+        assert!(matches!(
+            db.rename_user("bob", "dave"),
+            Err(DbError::DuplicateUsername(_))
+        ));
+        assert!(matches!(
+            db.create_users(&[test_user("zoe", 0), test_user("dave", 0)]),
+            Err(DbError::BatchUser { ref username, .. }) if username == "dave"
+        ));
+        assert_eq!(db.get_permissions("zoe").unwrap(), None);
+        let mut export = db.export_users().unwrap();
+        export.truncate(1);
+        export[0].username = "dave".to_string();
+        assert!(matches!(
+            db.import_users(&export),
+            Err(DbError::BatchUser { .. })
+        ));
+
+        // Only with the token
+        assert!(!db.release_username("dave", "guessed").unwrap());
+        assert!(matches!(
+            db.confirm_registration(&hashed("dave", 1), "guessed"),
+            Err(DbError::NotReserved(_))
+        ));
+        db.confirm_registration(&hashed("dave", 1), &token).unwrap();
+        assert_eq!(db.get_permissions("dave").unwrap(), Some(1));
+        assert!(matches!(
+            db.confirm_registration(&hashed("dave", 1), &token),
+            Err(DbError::NotReserved(_))
+        ));
+
+        // A zero TTL is expired right away and frees the name again
+        let token = db
+            .reserve_username("erin", Duration::ZERO)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            db.confirm_registration(&hashed("erin", 0), &token),
+            Err(DbError::NotReserved(_))
+        ));
+        let token = db.reserve_username("erin", hour).unwrap().unwrap();
+        assert!(db.release_username("erin", &token).unwrap());
+        db.reserve_username("frank", Duration::ZERO).unwrap();
+        assert_eq!(db.release_expired_reservations().unwrap(), 1);
+        db.create_user(&test_user("erin", 0)).unwrap();
+    }
+
+    #[test]
+    fn test_first_user_respects_reservations() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        db.reserve_username("admin", Duration::from_secs(3600))
+            .unwrap();
+        assert!(matches!(
+            db.create_first_user(&test_user("admin", 0)),
+            Err(DbError::DuplicateUsername(_))
+        ));
+        assert!(db.create_first_user(&test_user("root", 0)).unwrap());
+        assert!(!db.create_first_user(&test_user("other", 0)).unwrap());
+        assert!(!db.create_first_user(&test_user("admin", 0)).unwrap());
+    }
+
+    #[test]
+    fn test_get_users() {
+        let db = seed_test_users();
+        let mut usernames: Vec<String> = (0..USERS_PER_QUERY * 2)
+            .map(|i| format!("nobody{}", i))
+            .collect();
+        usernames.extend(["carol", "alice", "mallory", "alice"].map(String::from));
+        let found: Vec<String> = db
+            .get_users(&usernames)
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        assert_eq!(found, ["alice", "carol"]);
+        assert!(db.get_users(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_increment_user_counter() {
+        let db = seed_test_users();
+        let drawn = |by| db.increment_user_counter("alice", CounterColumn::ObjectsDrawn, by);
+        assert_eq!(drawn(3).unwrap(), 3);
+        assert_eq!(drawn(2).unwrap(), 5);
+        assert_eq!(drawn(-1).unwrap(), 4);
+        assert!(matches!(
+            db.increment_user_counter("mallory", CounterColumn::ObjectsDrawn, 1),
+            Err(DbError::UserNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_export_and_import_users() {
+        let source = seed_test_users();
+        let exported = source.export_users().unwrap();
+        assert_eq!(exported.len(), TEST_USERS.len());
+
+        let target = DatabaseConnection::in_memory().unwrap();
+        target.import_users(&exported).unwrap();
+        assert_eq!(target.export_users().unwrap(), exported);
+        assert!(authentication::verify_password(
+            TEST_PASSWORD,
+            &exported[0].password_hash
+        ));
+        // Importing again collides on the first user and changes nothing
+        assert!(matches!(
+            target.import_users(&exported),
+            Err(DbError::BatchUser { username, .. }) if username == "alice"
+        ));
+        assert_eq!(target.export_users().unwrap().len(), TEST_USERS.len());
+    }
+
+    #[test]
+    fn test_create_user_rejects_duplicates() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        assert!(db.is_empty().unwrap());
+        let user = test_user("alice", 0);
+        db.create_user(&user).unwrap();
+        assert!(!db.is_empty().unwrap());
+        assert_eq!(db.get_permissions("alice").unwrap(), Some(0));
+        assert_eq!(db.get_permissions("bob").unwrap(), None);
+        assert!(matches!(
+            db.create_user(&user),
+            Err(DbError::DuplicateUsername(name)) if name == "alice"
+        ));
+
+        let oversized = NewUser {
+            username: "a".repeat(1024 * 1024),
+            ..user
+        };
+        assert!(matches!(
+            db.create_user(&oversized),
+            Err(DbError::InvalidUsername(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_users_is_all_or_nothing() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        db.create_user(&test_user("carol", 0)).unwrap();
+
+        let batch = ["alice", "bob", "carol", "dave"].map(|name| test_user(name, 0));
+        match db.create_users(&batch) {
+            Err(DbError::BatchUser { username, source }) => {
+                assert_eq!(username, "carol");
+                assert!(matches!(*source, DbError::DuplicateUsername(_)));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // alice and bob went in before the failure, they must have been rolled back
+        assert!(db.create_users(&batch[..2]).is_ok());
+    }
+
+    #[test]
+    fn test_writes_retry_while_database_is_locked() {
+        let path = temp_db_path("busy");
+        let db = DatabaseConnection::new(&path).unwrap();
+
+        // Hold the write lock from a second connection for a little while
+        let locker = rusqlite::Connection::open(&path).unwrap();
+        locker.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            locker.execute_batch("COMMIT").unwrap();
+        });
+
+        db.save_room_snapshot("lobby", "[]").unwrap();
+        release.join().unwrap();
+        assert_eq!(
+            db.load_room_snapshot("lobby").unwrap().as_deref(),
+            Some("[]")
+        );
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_busy_gives_up_after_the_busy_timeout() {
+        let path = temp_db_path("busy-timeout");
+        let options = DbOptions {
+            busy_timeout: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let db = DatabaseConnection::with_options(&path, options).unwrap();
+
+        let locker = rusqlite::Connection::open(&path).unwrap();
+        locker.execute_batch("BEGIN IMMEDIATE").unwrap();
+        let start = std::time::Instant::now();
+        assert!(db.save_room_snapshot("lobby", "[]").is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        locker.execute_batch("COMMIT").unwrap();
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_close_checkpoints_wal() {
+        let path = temp_db_path("close");
+        let db = DatabaseConnection::new(&path).unwrap();
+        db.conn.execute_batch("PRAGMA journal_mode = WAL").unwrap();
+        db.save_room_snapshot("lobby", "[]").unwrap();
+        db.close().unwrap();
+
+        let wal = path.with_extension("db-wal");
+        assert!(std::fs::metadata(&wal).map_or(true, |m| m.len() == 0));
+        let db = DatabaseConnection::new(&path).unwrap();
+        assert_eq!(
+            db.load_room_snapshot("lobby").unwrap().as_deref(),
+            Some("[]")
+        );
+        drop(db);
+        for file in [path.clone(), wal, path.with_extension("db-shm")] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn test_settings_round_trip() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        assert_eq!(db.get_setting::<String>("banner").unwrap(), None);
+        assert!(!db.get_setting_or("maintenance", false).unwrap());
+
+        db.set_setting("banner", "Back at 5pm").unwrap();
+        db.set_setting("maintenance", &true).unwrap();
+        db.set_setting("banner", "Back at 6pm").unwrap();
+        assert_eq!(
+            db.get_setting::<String>("banner").unwrap().as_deref(),
+            Some("Back at 6pm")
+        );
+        assert!(db.get_setting_or("maintenance", false).unwrap());
+        // Reading it back as the wrong type is an error, not a silent default
+        assert!(matches!(
+            db.get_setting::<u32>("banner"),
+            Err(DbError::Setting { .. })
+        ));
+    }
+
+    #[test]
+    fn test_exists_any_user() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        // Saved rooms alone don't count as set up
+        db.save_room_snapshot("lobby", "[]").unwrap();
+        assert!(!db.exists_any_user().unwrap());
+        assert!(seed_test_users().exists_any_user().unwrap());
+
+        assert!(db.create_first_user(&test_user("admin", 3)).unwrap());
+        assert!(!db.create_first_user(&test_user("second", 3)).unwrap());
+        assert!(db.exists_any_user().unwrap());
+        assert_eq!(db.get_permissions("second").unwrap(), None);
+    }
+
+    #[test]
+    fn test_room_snapshot_round_trip() {
+        let db = DatabaseConnection::in_memory().unwrap();
+        assert_eq!(db.load_room_snapshot("lobby").unwrap(), None);
+        db.save_room_snapshot("lobby", "[1]").unwrap();
+        db.save_room_snapshot("lobby", "[1,2]").unwrap();
+        assert_eq!(
+            db.load_room_snapshot("lobby").unwrap().as_deref(),
+            Some("[1,2]")
+        );
+        assert_eq!(db.room_snapshot_names().unwrap(), vec!["lobby"]);
+        assert!(db.quick_check().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disk_full_rolls_back() {
+        let db = seed_test_users();
+        // Capping the file at its current size makes SQLite report SQLITE_FULL like a full disk
+        let pages: i64 = db
+            .conn
+            .query_row("PRAGMA page_count", [], |row| row.get(0))
+            .unwrap();
+        let cap = |pages: i64| {
+            db.conn
+                .query_row(
+                    &format!("PRAGMA max_page_count = {}", pages),
+                    [],
+                    |_| Ok(()),
+                )
+                .unwrap()
+        };
+        cap(pages);
+
+        let big = "x".repeat(1 << 20);
+        let users: Vec<NewUser> = (0..50)
+            .map(|i| test_user(&format!("user{}", i), 0))
+            .collect();
+        for result in [
+            db.save_room_snapshot("lobby", &big),
+            db.create_users(&users),
+        ] {
+            match result {
+                Err(DbError::DiskFull { path }) => assert_eq!(path, ":memory:"),
+                other => panic!("expected DiskFull, got {:?}", other),
+            }
+        }
+        assert!(db.is_disk_full());
+        // Nothing of either write made it in and the connection isn't stuck in a transaction
+        assert!(db.conn.is_autocommit());
+        assert_eq!(db.load_room_snapshot("lobby").unwrap(), None);
+        assert_eq!(db.export_users().unwrap().len(), TEST_USERS.len());
+
+        cap(pages * 1000);
+        db.save_room_snapshot("lobby", &big).unwrap();
+        assert!(!db.is_disk_full());
     }
 }
@@ -1,11 +1,29 @@
+-- Schema version of this database, a single row; checked before anything else runs
+CREATE TABLE IF NOT EXISTS SchemaVersion (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    version INTEGER NOT NULL
+);
+
 -- Table for the `User` struct
+-- Every account lookup (password check, permissions, counters, rename) is by
+-- exact username, served by the primary key's unique index
 CREATE TABLE IF NOT EXISTS Users (
     username TEXT NOT NULL PRIMARY KEY,
     password_hash TEXT NOT NULL,
     security_key TEXT, -- Nullable
     salt TEXT NOT NULL,
     permissions UNSIGNED SMALLINT NOT NULL, -- 16-bit unsigned integer
-    lockout_time BIGINT NOT NULL -- -1 if not locked out
+    lockout_time BIGINT NOT NULL, -- -1 if not locked out
+    created_at BIGINT NOT NULL, -- Unix timestamp (seconds) the account was created
+    objects_drawn BIGINT NOT NULL DEFAULT 0 -- Usage counter, see CounterColumn
+);
+
+-- Usernames held for registrations that aren't confirmed yet, see reserve_username
+-- A row past expires_at holds nothing anymore, whether or not it was cleaned up yet
+CREATE TABLE IF NOT EXISTS PendingRegistrations (
+    username TEXT NOT NULL PRIMARY KEY,
+    token TEXT NOT NULL, -- Secret handed to whoever reserved the name, needed to confirm or release
+    expires_at BIGINT NOT NULL -- Unix timestamp (seconds) the reservation ends
 );
 
 -- Table for the `DrawnObject` struct
@@ -17,3 +35,21 @@ CREATE TABLE IF NOT EXISTS DrawnObjects (
     color_args TEXT NOT NULL, -- Stored as a serialized JSON array of unsigned 32-bit integers
     bool_args TEXT NOT NULL -- Stored as a serialized JSON array of booleans
 );
+
+-- Runtime-adjustable settings (banners, feature toggles), values are JSON
+CREATE TABLE IF NOT EXISTS Settings (
+    key TEXT NOT NULL PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at BIGINT NOT NULL -- Unix timestamp (seconds) of the last change
+);
+
+-- Latest saved canvas for each room, loaded by its primary key
+CREATE TABLE IF NOT EXISTS RoomSnapshots (
+    room TEXT NOT NULL PRIMARY KEY,
+    snapshot TEXT NOT NULL, -- Stored as a serialized JSON array of drawn objects
+    updated_at BIGINT NOT NULL -- Unix timestamp (seconds) of the last save
+);
+
+-- Listing saved rooms most recently saved first (warmup), without sorting the table
+-- Indexes need no schema version bump either, older builds just don't use them
+CREATE INDEX IF NOT EXISTS RoomSnapshotsByUpdate ON RoomSnapshots (updated_at DESC, room);
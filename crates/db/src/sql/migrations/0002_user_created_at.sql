@@ -0,0 +1,4 @@
+-- Schema 1 -> 2: account creation time for "member since"
+-- Existing users get the time of the migration, their real creation time was never recorded
+ALTER TABLE Users ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+UPDATE Users SET created_at = unixepoch();
@@ -0,0 +1,2 @@
+-- Schema 2 -> 3: per-user usage counters, see CounterColumn
+ALTER TABLE Users ADD COLUMN objects_drawn BIGINT NOT NULL DEFAULT 0;
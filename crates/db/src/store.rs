@@ -0,0 +1,54 @@
+use crate::{DatabaseConnection, DbError, HashedUser, NewUser};
+
+/// The user account operations the server needs, independent of the database behind them.
+///
+/// [`DatabaseConnection`] (SQLite) is the only implementation so far; another
+/// backend only has to implement this trait to be used for accounts.
+/// Implementations are called with a lock held and may block. Passwords come
+/// in hashed already, so hashing never happens with the lock held.
+pub trait UserStore: Send {
+    /// The algorithm to hash new passwords with, see [`NewUser::hash`].
+    fn password_hash(&self) -> authentication::HashAlgorithm;
+    /// Creates one user, see [`DatabaseConnection::create_hashed_user`].
+    fn create_hashed_user(&self, user: &HashedUser) -> Result<(), DbError>;
+    /// Creates a user only while there are none, see [`DatabaseConnection::create_first_hashed_user`].
+    fn create_first_hashed_user(&self, user: &HashedUser) -> Result<bool, DbError>;
+    /// Creates all users or none of them, see [`DatabaseConnection::create_users`].
+    fn create_users(&self, users: &[NewUser]) -> Result<(), DbError>;
+    /// Renames a user, see [`DatabaseConnection::rename_user`].
+    fn rename_user(&self, old: &str, new: &str) -> Result<(), DbError>;
+    /// The permissions of a user, `None` if there is no such user.
+    fn get_permissions(&self, username: &str) -> Result<Option<u16>, DbError>;
+    /// Whether any user exists yet, see [`DatabaseConnection::exists_any_user`].
+    fn exists_any_user(&self) -> Result<bool, DbError>;
+}
+
+impl UserStore for DatabaseConnection {
+    fn password_hash(&self) -> authentication::HashAlgorithm {
+        DatabaseConnection::password_hash(self)
+    }
+
+    fn create_hashed_user(&self, user: &HashedUser) -> Result<(), DbError> {
+        DatabaseConnection::create_hashed_user(self, user)
+    }
+
+    fn create_first_hashed_user(&self, user: &HashedUser) -> Result<bool, DbError> {
+        DatabaseConnection::create_first_hashed_user(self, user)
+    }
+
+    fn create_users(&self, users: &[NewUser]) -> Result<(), DbError> {
+        DatabaseConnection::create_users(self, users)
+    }
+
+    fn rename_user(&self, old: &str, new: &str) -> Result<(), DbError> {
+        DatabaseConnection::rename_user(self, old, new)
+    }
+
+    fn get_permissions(&self, username: &str) -> Result<Option<u16>, DbError> {
+        DatabaseConnection::get_permissions(self, username)
+    }
+
+    fn exists_any_user(&self) -> Result<bool, DbError> {
+        DatabaseConnection::exists_any_user(self)
+    }
+}
@@ -4,7 +4,8 @@ macro_rules! spawn_tasks {
         let mut handles = Vec::new();
         $(
             let state = $state.clone();
-            handles.push(tokio::spawn($func(state)));
+            // Keep the caller's span, it carries the instance name
+            handles.push(tokio::spawn(tracing::Instrument::in_current_span($func(state))));
         )*
         let task_count = handles.len();
         tracing::info!("Spawned {} {}", task_count, if task_count == 1 { "task" } else { "tasks" });
@@ -1,6 +1,12 @@
 //! Pretty logs for RustCanvas.
 
-use tracing_subscriber::{filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use std::path::PathBuf;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    Layer, filter::EnvFilter, fmt, layer::SubscriberExt, registry::LookupSpan,
+    util::SubscriberInitExt,
+};
 
 /// Initialize the tracing subscriber with custom filtering rules.
 ///
@@ -14,35 +20,9 @@ use tracing_subscriber::{filter::EnvFilter, fmt, layer::SubscriberExt, util::Sub
 /// tracing::info!("This log from your code will be visible");
 /// ```
 pub fn init_logging() {
-    // Create an environment filter that:
-    // 1. Sets external crates to only log at WARN level or higher (always)
-    // 2. Sets our internal crates to log at appropriate level based on build profile:
-    //    - In debug: TRACE level
-    //    - In release: INFO level (skip debug and trace)
-
-    // Determine minimum log level based on build configuration
-    #[cfg(debug_assertions)]
-    let internal_level = "trace";
-    #[cfg(not(debug_assertions))]
-    let internal_level = "info";
-
-    // Build the filter directive string
-    let filter_directive = format!(
-        "rustcanvas={0},appstate={0},authentication={0},config={0},db={0},macros={0},prettylogs={0},utils={0},webserver={0},warn",
-        internal_level
-    );
-
-    let filter = EnvFilter::builder()
-        // Add any specific crates from our project here to enable appropriate logging
-        .parse(&filter_directive)
-        .expect("Invalid filter directive");
-
-    // Initialize the tracing subscriber with the filter and no time/date
     tracing_subscriber::registry()
-        .with(
-            fmt::layer().with_target(true).without_time(), // Remove timestamp from output
-        )
-        .with(filter)
+        .with(console_layer())
+        .with(default_filter())
         .init();
 
     #[cfg(debug_assertions)]
@@ -66,27 +46,142 @@ pub fn init_logging() {
 /// prettylogs::init_logging_with_filter("rustcanvas=debug,some_dependency=info,warn");
 /// ```
 pub fn init_logging_with_filter(filter_str: &str) {
-    // In release mode, we'll respect the provided filter but ensure debug logs are disabled
-    // for any crates that don't explicitly override this
+    tracing_subscriber::registry()
+        .with(console_layer())
+        .with(custom_filter(filter_str))
+        .init();
+
+    #[cfg(debug_assertions)]
+    tracing::debug!("Logging initialized with custom filter: {}", filter_str);
     #[cfg(not(debug_assertions))]
-    let filter_str = if !filter_str.contains("debug=") && !filter_str.contains("=debug") {
-        format!("{},debug=off", filter_str)
-    } else {
-        filter_str.to_string()
-    };
+    tracing::info!("Logging initialized with custom filter (debug disabled in release mode)");
+}
 
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::try_new(filter_str).expect("Invalid filter directive"));
+/// How often the log file is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Where and how to write the log file.
+#[derive(Debug, Clone)]
+pub struct FileLogOptions {
+    /// Directory the log files are written to, created if missing.
+    pub directory: PathBuf,
+    /// Base file name, rotated files get the date (and hour) appended.
+    pub file_name: String,
+    pub rotation: LogRotation,
+    /// How many rotated files to keep, older ones are deleted. `None` keeps all of them.
+    pub max_files: Option<usize>,
+}
+
+/// Keeps the background log file writer alive.
+///
+/// Hold on to this until the program exits: dropping it flushes whatever is
+/// still buffered to the file.
+#[must_use = "dropping the guard stops file logging"]
+pub struct LogGuard {
+    _worker: Option<WorkerGuard>,
+}
+
+/// Initialize logging to the console and to a rotating log file at the same time.
+///
+/// `filter` works like [`init_logging_with_filter`], `None` uses the same
+/// defaults as [`init_logging`]. The file gets the same lines as the console,
+/// with timestamps and without colors. If the log file can't be opened,
+/// console logging is still set up and the problem is logged there.
+pub fn init_logging_with_file(filter: Option<&str>, options: &FileLogOptions) -> LogGuard {
+    let appender = RollingFileAppender::builder()
+        .rotation(match options.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        })
+        .filename_prefix(&options.file_name)
+        .max_log_files(options.max_files.unwrap_or(usize::MAX))
+        .build(&options.directory);
+    let (file_layer, guard, error) = match appender {
+        Ok(appender) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = fmt::layer()
+                .with_target(true)
+                .with_ansi(false)
+                .with_writer(writer);
+            (Some(layer), Some(guard), None)
+        }
+        Err(e) => (None, None, Some(e)),
+    };
+    let filter = match filter {
+        Some(filter_str) => custom_filter(filter_str),
+        None => default_filter(),
+    };
 
     tracing_subscriber::registry()
-        .with(
-            fmt::layer().with_target(true).without_time(), // Remove timestamp from output
-        )
+        .with(console_layer())
+        .with(file_layer)
         .with(filter)
         .init();
 
+    match error {
+        None => tracing::debug!(
+            "Logging to {} in {}",
+            options.file_name,
+            options.directory.display()
+        ),
+        Some(e) => tracing::error!(
+            "Failed to open log file in {}, logging to the console only: {}",
+            options.directory.display(),
+            e
+        ),
+    }
+    LogGuard { _worker: guard }
+}
+
+// Console output, without timestamps
+fn console_layer<S>() -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt::layer().with_target(true).without_time()
+}
+
+fn default_filter() -> EnvFilter {
+    // Create an environment filter that:
+    // 1. Sets external crates to only log at WARN level or higher (always)
+    // 2. Sets our internal crates to log at appropriate level based on build profile:
+    //    - In debug: TRACE level
+    //    - In release: INFO level (skip debug and trace)
+
+    // Determine minimum log level based on build configuration
     #[cfg(debug_assertions)]
-    tracing::debug!("Logging initialized with custom filter: {}", filter_str);
+    let internal_level = "trace";
     #[cfg(not(debug_assertions))]
-    tracing::info!("Logging initialized with custom filter (debug disabled in release mode)");
+    let internal_level = "info";
+
+    // Build the filter directive string
+    let filter_directive = format!(
+        "rustcanvas={0},appstate={0},authentication={0},config={0},db={0},macros={0},prettylogs={0},utils={0},webserver={0},warn",
+        internal_level
+    );
+
+    EnvFilter::builder()
+        // Add any specific crates from our project here to enable appropriate logging
+        .parse(&filter_directive)
+        .expect("Invalid filter directive")
+}
+
+fn custom_filter(filter_str: &str) -> EnvFilter {
+    // In release mode, we'll respect the provided filter but ensure debug logs are disabled
+    // for any crates that don't explicitly override this
+    #[cfg(not(debug_assertions))]
+    let filter_str = if !filter_str.contains("debug=") && !filter_str.contains("=debug") {
+        format!("{},debug=off", filter_str)
+    } else {
+        filter_str.to_string()
+    };
+
+    EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::try_new(filter_str).expect("Invalid filter directive"))
 }
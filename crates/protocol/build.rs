@@ -57,10 +57,10 @@ fn generate_rust_code(proto_file: &str) {
 
             // Find the generated file and copy it to our source tree
             let mut found_generated = false;
-            if let Ok(entries) = fs::read_dir(&out_path) {
+            if let Ok(entries) = fs::read_dir(out_path) {
                 for entry in entries.flatten() {
                     let path = entry.path();
-                    if path.extension().map_or(false, |ext| ext == "rs") {
+                    if path.extension().is_some_and(|ext| ext == "rs") {
                         copy_rust_generated_code(&path);
                         found_generated = true;
                         break;
@@ -85,11 +85,11 @@ fn copy_rust_generated_code(generated_file: &Path) {
     let target_file = target_dir.join("mod.rs");
 
     // Create the directory if it doesn't exist
-    if !target_dir.exists() {
-        if let Err(e) = fs::create_dir_all(target_dir) {
-            println!("cargo:warning=Failed to create generated directory: {}", e);
-            return;
-        }
+    if !target_dir.exists()
+        && let Err(e) = fs::create_dir_all(target_dir)
+    {
+        println!("cargo:warning=Failed to create generated directory: {}", e);
+        return;
     }
 
     // Read the generated content
@@ -127,11 +127,11 @@ fn create_rust_placeholder() {
     let target_file = target_dir.join("mod.rs");
 
     // Create the directory if it doesn't exist
-    if !target_dir.exists() {
-        if let Err(e) = fs::create_dir_all(target_dir) {
-            println!("cargo:warning=Failed to create generated directory: {}", e);
-            return;
-        }
+    if !target_dir.exists()
+        && let Err(e) = fs::create_dir_all(target_dir)
+    {
+        println!("cargo:warning=Failed to create generated directory: {}", e);
+        return;
     }
 
     let placeholder = r#"// DO NOT EDIT! This file was automatically generated from proto/messages.proto
@@ -186,14 +186,13 @@ fn generate_javascript_code(proto_file: &str) {
     println!("cargo:warning=JS target: {}", js_target.display());
 
     // Create parent directory if it doesn't exist
-    if let Some(parent) = js_target.parent() {
-        if !parent.exists() {
-            if let Err(e) = fs::create_dir_all(parent) {
-                println!("cargo:warning=Failed to create JS target directory: {}", e);
-                create_javascript_placeholder(&js_target);
-                return;
-            }
-        }
+    if let Some(parent) = js_target.parent()
+        && !parent.exists()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        println!("cargo:warning=Failed to create JS target directory: {}", e);
+        create_javascript_placeholder(&js_target);
+        return;
     }
 
     // Check if proto file exists and is not empty
@@ -264,12 +263,12 @@ fn extract_message_names(proto_content: &str) -> Vec<String> {
 
     for line in proto_content.lines() {
         let line = line.trim();
-        if line.starts_with("message ") && line.contains('{') {
-            if let Some(name_part) = line.strip_prefix("message ") {
-                if let Some(name) = name_part.split_whitespace().next() {
-                    messages.push(name.to_string());
-                }
-            }
+        if line.starts_with("message ")
+            && line.contains('{')
+            && let Some(name_part) = line.strip_prefix("message ")
+            && let Some(name) = name_part.split_whitespace().next()
+        {
+            messages.push(name.to_string());
         }
     }
 
@@ -15,6 +15,9 @@ pub mod proto {
 // Re-export all types from the generated proto module
 pub use proto::*;
 
+// JSON control messages (rooms etc.) that travel as text frames
+pub mod messages;
+
 // Module containing helpers for working with our protocol
 pub mod helpers {
     use bytes::{Bytes, BytesMut};
@@ -91,4 +94,97 @@ mod tests {
         // Compare
         assert_eq!(original.message, decoded.message);
     }
+
+    #[test]
+    fn test_client_message_json() {
+        use messages::ClientMessage;
+
+        let parsed = ClientMessage::from_json(r#"{"type":"join_room","room":"lobby"}"#).unwrap();
+        assert_eq!(
+            parsed,
+            ClientMessage::JoinRoom {
+                room: "lobby".to_string()
+            }
+        );
+        assert!(ClientMessage::from_json(r#"{"type":"nope"}"#).is_err());
+    }
+
+    #[test]
+    fn test_draw_op_validation() {
+        use messages::{ClientMessage, DrawError, DrawLimits, Shape};
+
+        let parsed = ClientMessage::from_json(
+            r#"{"type":"draw","shape":"line","coords":[[0,0],[10,20]],"color":[255,0,0],"stroke":2}"#,
+        )
+        .unwrap();
+        let ClientMessage::Draw { op, base_sequence } = parsed else {
+            panic!("not a draw: {:?}", parsed);
+        };
+        assert_eq!((op.shape, base_sequence), (Shape::Line, None));
+        assert!(
+            ClientMessage::from_json(
+                r#"{"type":"draw","shape":"star","coords":[],"color":[0,0,0],"stroke":1}"#
+            )
+            .is_err()
+        );
+
+        let limits = DrawLimits {
+            width: 100.0,
+            height: 100.0,
+            max_points: 3,
+            max_stroke: 10.0,
+            palette: Some(vec![(255, 0, 0)]),
+        };
+        assert_eq!(op.validate(&limits), Ok(()));
+        let with = |change: fn(&mut messages::DrawOp)| {
+            let mut op = op.clone();
+            change(&mut op);
+            op.validate(&limits).unwrap_err()
+        };
+        assert!(matches!(
+            with(|op| op.coords.truncate(1)),
+            DrawError::PointCount { .. }
+        ));
+        assert!(matches!(
+            with(|op| {
+                op.shape = Shape::Path;
+                op.coords = vec![(1.0, 1.0); 4];
+            }),
+            DrawError::TooManyPoints { count: 4, max: 3 }
+        ));
+        assert!(matches!(
+            with(|op| op.coords[1] = (101.0, 5.0)),
+            DrawError::OutOfBounds { .. }
+        ));
+        assert!(matches!(
+            with(|op| op.coords[0] = (f64::NAN, 5.0)),
+            DrawError::OutOfBounds { .. }
+        ));
+        assert!(matches!(with(|op| op.stroke = 0.0), DrawError::Stroke(_)));
+        assert!(matches!(
+            with(|op| op.color = (0, 0, 0)),
+            DrawError::Color(_)
+        ));
+
+        let object = messages::CanvasObject::from(&op);
+        assert_eq!(object.kind, Shape::Line.kind());
+        assert_eq!(object.num_args, vec![2.0, 0.0, 0.0, 10.0, 20.0]);
+    }
+
+    #[test]
+    fn test_op_batch_json() {
+        use messages::ServerMessage;
+
+        let text = r#"{"type":"op_batch","room":"lobby","ops":[
+            {"type":"draw","room":"lobby","connection_id":2,"shape":"line","coords":[[0,0],[1,1]],"color":[0,0,0],"stroke":1,"sequence":4},
+            {"type":"undone","room":"lobby","connection_id":3,"op":4,"sequence":5}]}"#;
+        let parsed: ServerMessage = serde_json::from_str(text).unwrap();
+        let ServerMessage::OpBatch { ops, .. } = &parsed else {
+            panic!("expected an op batch, got {:?}", parsed);
+        };
+        assert!(matches!(ops[0], ServerMessage::Draw { sequence: 4, .. }));
+        assert!(matches!(ops[1], ServerMessage::Undone { sequence: 5, .. }));
+        let again: ServerMessage = serde_json::from_str(&parsed.to_json()).unwrap();
+        assert_eq!(again, parsed);
+    }
 }
@@ -0,0 +1,328 @@
+//! JSON control messages exchanged over WebSocket text frames
+//! Binary frames stay reserved for the protobuf messages above
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Messages a client can send to the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Join a room, leaving whatever room the connection was in before
+    JoinRoom { room: String },
+    /// Leave the current room
+    LeaveRoom,
+    /// Ask for the active rooms and how many people are in each
+    ListRooms,
+    /// Draw on the canvas of the room the connection is in
+    ///
+    /// The operation's fields sit directly in the message:
+    /// `{"type":"draw","shape":"line","coords":[[0,0],[10,10]],"color":[0,0,0],"stroke":2}`.
+    /// Operations that break the server's `DrawLimits` are answered with `Error`.
+    /// With `base_sequence` set, the draw is only applied if it is still the
+    /// room's current sequence, otherwise the server answers with `Conflict`.
+    Draw {
+        #[serde(flatten)]
+        op: DrawOp,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_sequence: Option<u64>,
+    },
+    /// Ask for the full canvas of the current room again, e.g. to re-sync after a reconnect
+    RequestState,
+    /// Take back an operation, by default your own latest one that is still active
+    ///
+    /// `op` picks a specific one by id (the `sequence` it was drawn with). Only
+    /// your own operations in the room's undo history (`StateSnapshot::ops`)
+    /// can be undone, and only from the connection that drew them.
+    Undo {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op: Option<u64>,
+    },
+    /// Bring an undone operation back, by default the one you undid most recently
+    Redo {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op: Option<u64>,
+    },
+}
+
+/// A single object on a room's canvas
+/// Same shape as the `DrawnObject` rows the database was designed around
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CanvasObject {
+    /// What kind of object this is
+    pub kind: u32,
+    /// Numeric arguments (positions, sizes, ...)
+    pub num_args: Vec<f64>,
+    /// String arguments
+    pub str_args: Vec<String>,
+    /// Color arguments as RGB triples
+    pub color_args: Vec<(u8, u8, u8)>,
+    /// Boolean arguments
+    pub bool_args: Vec<bool>,
+}
+
+/// The shapes a `DrawOp` can draw
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    /// A straight line between two points
+    Line,
+    /// An axis-aligned rectangle between two opposite corners
+    Rect,
+    /// The ellipse inside the rectangle between two opposite corners
+    Ellipse,
+    /// A freehand stroke through two or more points
+    Path,
+}
+
+impl Shape {
+    /// `kind` of the stored `CanvasObject`
+    pub fn kind(self) -> u32 {
+        match self {
+            Shape::Line => 1,
+            Shape::Rect => 2,
+            Shape::Ellipse => 3,
+            Shape::Path => 4,
+        }
+    }
+}
+
+/// One drawing operation, what clients send in `ClientMessage::Draw` and what
+/// everyone else in the room receives in `ServerMessage::Draw`
+///
+/// Stored on the canvas as a `CanvasObject` with `kind` from `Shape::kind`,
+/// `num_args` = `[stroke, x0, y0, x1, y1, ...]` and `color_args` = `[color]`,
+/// which is also how it shows up in `StateSnapshot`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DrawOp {
+    pub shape: Shape,
+    /// Points in canvas coordinates, (0, 0) is the top left corner
+    pub coords: Vec<(f64, f64)>,
+    pub color: (u8, u8, u8),
+    /// Line width in canvas units
+    pub stroke: f64,
+}
+
+/// What the server accepts in a `DrawOp`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawLimits {
+    /// Coordinates have to lie within 0..=width and 0..=height
+    pub width: f64,
+    pub height: f64,
+    /// Most points a single operation may have
+    pub max_points: usize,
+    /// Widest stroke allowed, strokes also have to be positive
+    pub max_stroke: f64,
+    /// Colors that may be used, None allows any
+    pub palette: Option<Vec<(u8, u8, u8)>>,
+}
+
+/// Why a `DrawOp` was refused, the message is meant for the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum DrawError {
+    /// Wrong number of points for the shape
+    PointCount { shape: Shape, count: usize },
+    /// More points than `DrawLimits::max_points`
+    TooManyPoints { count: usize, max: usize },
+    /// A point outside the canvas, or not a finite number
+    OutOfBounds { x: f64, y: f64 },
+    /// Not positive, or wider than `DrawLimits::max_stroke`
+    Stroke(f64),
+    /// Not in `DrawLimits::palette`
+    Color((u8, u8, u8)),
+}
+
+impl fmt::Display for DrawError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrawError::PointCount { shape, count } => match shape {
+                Shape::Path => write!(f, "A path needs at least 2 points, got {}", count),
+                _ => write!(f, "A {:?} needs exactly 2 points, got {}", shape, count),
+            },
+            DrawError::TooManyPoints { count, max } => {
+                write!(
+                    f,
+                    "Too many points ({}), at most {} are allowed",
+                    count, max
+                )
+            }
+            DrawError::OutOfBounds { x, y } => {
+                write!(f, "Point ({}, {}) is outside the canvas", x, y)
+            }
+            DrawError::Stroke(stroke) => write!(f, "Stroke width {} is not allowed", stroke),
+            DrawError::Color((r, g, b)) => {
+                write!(
+                    f,
+                    "Color #{:02x}{:02x}{:02x} is not in the palette",
+                    r, g, b
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DrawError {}
+
+impl DrawOp {
+    /// Check the operation against the server's limits before it is applied
+    pub fn validate(&self, limits: &DrawLimits) -> Result<(), DrawError> {
+        let count = self.coords.len();
+        let count_ok = match self.shape {
+            Shape::Path => count >= 2,
+            Shape::Line | Shape::Rect | Shape::Ellipse => count == 2,
+        };
+        if !count_ok {
+            return Err(DrawError::PointCount {
+                shape: self.shape,
+                count,
+            });
+        }
+        if count > limits.max_points {
+            return Err(DrawError::TooManyPoints {
+                count,
+                max: limits.max_points,
+            });
+        }
+        // NaN fails every comparison, so it is caught here as well
+        let inside = |v: f64, max: f64| (0.0..=max).contains(&v);
+        if let Some(&(x, y)) = self
+            .coords
+            .iter()
+            .find(|(x, y)| !inside(*x, limits.width) || !inside(*y, limits.height))
+        {
+            return Err(DrawError::OutOfBounds { x, y });
+        }
+        if !(self.stroke > 0.0 && self.stroke <= limits.max_stroke) {
+            return Err(DrawError::Stroke(self.stroke));
+        }
+        if let Some(palette) = &limits.palette
+            && !palette.contains(&self.color)
+        {
+            return Err(DrawError::Color(self.color));
+        }
+        Ok(())
+    }
+}
+
+impl From<&DrawOp> for CanvasObject {
+    fn from(op: &DrawOp) -> Self {
+        let mut num_args = Vec::with_capacity(1 + op.coords.len() * 2);
+        num_args.push(op.stroke);
+        num_args.extend(op.coords.iter().flat_map(|&(x, y)| [x, y]));
+        CanvasObject {
+            kind: op.shape.kind(),
+            num_args,
+            str_args: Vec::new(),
+            color_args: vec![op.color],
+            bool_args: Vec::new(),
+        }
+    }
+}
+
+/// An operation in a room's undo history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomOp {
+    /// The `sequence` the operation was drawn with, stable for its whole life
+    pub id: u64,
+    /// Who drew it, 0 once the room was reloaded from the database
+    /// (connection ids don't carry over, so the author is forgotten)
+    pub connection_id: u64,
+    /// False while it is undone, undone operations aren't shown
+    pub active: bool,
+    pub object: CanvasObject,
+}
+
+/// One entry of a room listing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub room: String,
+    pub participants: usize,
+}
+
+/// Messages the server sends to clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Confirms a join, sent only to the joining connection
+    RoomJoined { room: String, participants: usize },
+    /// Confirms a leave, sent only to the leaving connection
+    RoomLeft { room: String },
+    /// Someone else joined the room you're in
+    ParticipantJoined { room: String, connection_id: u64 },
+    /// Someone else left the room you're in
+    ParticipantLeft { room: String, connection_id: u64 },
+    /// Answer to `ListRooms`
+    RoomList { rooms: Vec<RoomInfo> },
+    /// The full canvas of a room, sent when joining it and in answer to `RequestState`
+    ///
+    /// `objects` are settled and can't be undone anymore, `ops` is the undo
+    /// history in order; the canvas is `objects` with the active `ops` drawn on
+    /// top. `sequence` is the sequence of the last operation included.
+    StateSnapshot {
+        room: String,
+        objects: Vec<CanvasObject>,
+        #[serde(default)]
+        ops: Vec<RoomOp>,
+        sequence: u64,
+    },
+    /// Someone else drew something in the room you're in
+    ///
+    /// Every operation in a room gets the next `sequence`, so a jump of more
+    /// than one means something was missed and the client should resync.
+    Draw {
+        room: String,
+        connection_id: u64,
+        #[serde(flatten)]
+        op: DrawOp,
+        sequence: u64,
+    },
+    /// Your draw was applied with this sequence
+    DrawApplied { room: String, sequence: u64 },
+    /// Your draw was based on an outdated `base_sequence` and was dropped,
+    /// send `RequestState` to resync before drawing again
+    Conflict { room: String, sequence: u64 },
+    /// Operation `op` was undone by `connection_id`, sent to everyone in the room
+    /// including whoever asked. Undo and redo get a `sequence` like draws do
+    Undone {
+        room: String,
+        connection_id: u64,
+        op: u64,
+        sequence: u64,
+    },
+    /// Operation `op` was redone by `connection_id`, sent to everyone in the room
+    Redone {
+        room: String,
+        connection_id: u64,
+        op: u64,
+        sequence: u64,
+    },
+    /// Several `Draw`, `DrawApplied`, `Undone` and `Redone` messages of a room,
+    /// in sequence order, sent as one when they came within the server's batch
+    /// window. Each is exactly what would have been sent on its own
+    OpBatch {
+        room: String,
+        ops: Vec<ServerMessage>,
+    },
+    /// The last client message couldn't be handled
+    Error { message: String },
+    /// You read slower than messages came in, so some were dropped. Send
+    /// `RequestState` to resync
+    Lagged,
+    /// The server is going down, a close frame (1001, going away) follows.
+    /// Reconnecting, possibly to another instance, is the way to continue
+    ShuttingDown,
+}
+
+impl ClientMessage {
+    /// Parse a client message from the JSON text of a frame
+    pub fn from_json(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+}
+
+impl ServerMessage {
+    /// Serialize a server message into JSON for a text frame
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize server message")
+    }
+}
@@ -0,0 +1,79 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// RustCanvas, a collaborative canvas server.
+///
+/// Running without a subcommand starts the server.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Run all startup checks (config, database, binding the port) and exit instead of serving
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Load environment variables (e.g. RUST_LOG) from this file before anything else
+    ///
+    /// Defaults to `.env` in the working directory, skipped if that doesn't exist.
+    /// Variables already set in the real environment win over the file.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub env_file: Option<PathBuf>,
+
+    /// Use the built-in defaults when there is no config file, instead of offering to create one
+    #[arg(long, global = true)]
+    pub no_create_config: bool,
+
+    /// Override one config field, e.g. `--set server.port=8080`; can be repeated
+    ///
+    /// Wins over both the config file and RUSTCANVAS_* environment variables.
+    #[arg(long = "set", global = true, value_name = "FIELD=VALUE")]
+    pub set: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Fill the database with demo users and canvas content for local development
+    Seed {
+        /// Seed even if the database already contains data
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the effective configuration as JSON and exit
+    ///
+    /// This is the config after defaults are filled in, legacy keys are migrated
+    /// and environment/--set overrides are applied.
+    ShowConfig,
+    /// Check that the configured server answers on /ready, for container health checks
+    ///
+    /// Exits with 0 when it does and 1 otherwise.
+    Healthcheck,
+    /// Create an account, reading its password from stdin
+    ///
+    /// Works even with public registration turned off.
+    CreateUser {
+        username: String,
+        /// Give the account every permission
+        #[arg(long, conflicts_with = "permissions")]
+        admin: bool,
+        /// Permissions as flag names joined with |, e.g. "READ | WRITE" [default: auth.default_permissions]
+        #[arg(long)]
+        permissions: Option<String>,
+    },
+    /// Set or clear the maintenance banner shown to users, without a restart
+    ///
+    /// Unlike read-only mode the banner only informs, nothing is refused.
+    Maintenance {
+        #[command(subcommand)]
+        action: MaintenanceAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MaintenanceAction {
+    /// Show this message until it is cleared
+    Set { message: String },
+    /// Remove the banner
+    Clear,
+}
@@ -0,0 +1,49 @@
+//! Account creation from the command line (`rustcanvas create-user`).
+//!
+//! This always works, whatever `auth.allow_public_registration` says, so it is
+//! how accounts get made on deployments with public registration turned off.
+
+use authentication::permissions;
+use db::{DatabaseConnection, NewUser};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+
+/// Create a single account, reading the password from the first line of stdin.
+///
+/// `permissions` is a flag expression like `READ | WRITE`; admins get every
+/// permission and `None` means the configured default.
+pub fn run(
+    db: &DatabaseConnection,
+    username: String,
+    admin: bool,
+    permissions: Option<&str>,
+    default_permissions: u16,
+) -> Result<(), Box<dyn Error>> {
+    let permissions = match (admin, permissions) {
+        (true, _) => permissions::ALL,
+        (false, Some(expression)) => permissions::parse(expression)?,
+        (false, None) => default_permissions,
+    };
+
+    print!("Password for '{}': ", username);
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err("Password must not be empty".into());
+    }
+
+    let user = NewUser {
+        username,
+        password,
+        permissions,
+    };
+    db.create_user(&user)?;
+    println!(
+        "Created user '{}' with permissions {}",
+        user.username,
+        permissions::format(user.permissions)
+    );
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Probe for container health checks (`rustcanvas healthcheck`).
+//!
+//! Requests `/ready` from the server configured in the config file, with a
+//! hand-written HTTP/1.1 request so images don't need curl. The server only
+//! speaks plain HTTP, HTTPS is left to a reverse proxy.
+
+use config::Config;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Ok when `/ready` answers with a 2xx status, the error says what went wrong otherwise.
+pub fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+    let addr = probe_address(config)?;
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "GET /ready HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Unexpected response from {}: '{}'", addr, status_line))?;
+    if !(200..300).contains(&status) {
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        return Err(format!("Server answered {}: {}", status, body.trim()).into());
+    }
+    Ok(())
+}
+
+// A wildcard bind address isn't something we can connect to, use loopback instead
+fn probe_address(config: &Config) -> Result<SocketAddr, Box<dyn Error>> {
+    let port = config.server.port;
+    if port == 0 {
+        return Err("server.port is 0, the actual port is only known to the running server".into());
+    }
+    // IPv4 loopback works whether or not dual-stack had to fall back to IPv4 only
+    if config.server.interface == config::DUAL_STACK_INTERFACE {
+        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+    }
+    // The server binds the first address it can, which is normally the first one
+    let addr = config.socket_addrs()?[0];
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
@@ -1,27 +1,237 @@
-use appstate::AppState;
-use config::load_config;
-use db::DatabaseConnection;
+mod cli;
+mod create_user;
+mod healthcheck;
+mod reload;
+mod seed;
+mod warmup;
+
+use appstate::{AppState, start_canvas_autosave};
+use authentication::HashAlgorithm;
+use clap::Parser;
+use cli::{CliArgs, Command, MaintenanceAction};
+use config::{Config, ConfigError, ConfigSources, LoggingConfig, ResolvedConfig, RuntimeConfig};
+use db::{DatabaseConnection, DbError, DbOptions};
 use macros::spawn_tasks;
-use prettylogs::init_logging;
-use std::{error::Error, path::Path};
+use prettylogs::{
+    FileLogOptions, LogGuard, LogRotation, init_logging, init_logging_with_file,
+    init_logging_with_filter,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, atomic::Ordering};
+use std::{error::Error, path::Path, time::Duration};
 use tokio::{select, task::JoinHandle};
+use tracing::level_filters::LevelFilter;
 use tracing::*;
 use webserver::start_webserver;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize logging first so all subsequent logs are captured
-    init_logging();
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = CliArgs::parse();
+    if let Err(e) = load_env_file(args.env_file.as_deref()) {
+        eprintln!("Failed to load the env file: {}", e);
+        std::process::exit(1);
+    }
+    // The config decides the log filter and the runtime's thread counts,
+    // so it is loaded first; loading it doesn't log anything
+    let ResolvedConfig {
+        config: conf,
+        overrides,
+    } = match resolve_config(&args, process_env()) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Plain JSON on stdout, before any logging so it can be piped
+    if let Some(Command::ShowConfig) = args.command {
+        println!("{}", serde_json::to_string_pretty(&conf)?);
+        return Ok(());
+    }
+    // Runs without logging or opening the database, the server is already doing both
+    if let Some(Command::Healthcheck) = args.command {
+        if let Err(e) = healthcheck::run(&conf) {
+            eprintln!("Health check failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // Dropped at the very end of main, which flushes the log file
+    let _log_guard = setup_logging(&conf.logging);
+    for entry in &overrides {
+        debug!("Config field {} set by {}", entry.field, entry.source);
+    }
+    // Every event below carries the instance name, tasks are spawned inside this span
+    let span = info_span!("server", name = %conf.server.instance_name());
+    build_runtime(&conf.runtime)?.block_on(run(args, conf).instrument(span))
+}
+
+// Precedence, lowest first: defaults, the config file, RUSTCANVAS_* variables
+// (a .env file included), --set arguments
+// The environment is passed in so this never has to touch the real one
+fn resolve_config(
+    cli: &CliArgs,
+    env: HashMap<String, String>,
+) -> Result<ResolvedConfig, ConfigError> {
+    config::resolve(&config_sources(cli, env))
+}
+
+fn config_sources(cli: &CliArgs, env: HashMap<String, String>) -> ConfigSources {
+    ConfigSources {
+        path: "config".to_string(),
+        create_missing: !cli.no_create_config,
+        env,
+        cli: cli.set.clone(),
+    }
+}
+
+// Variables that aren't valid UTF-8 can't be config overrides anyway
+fn process_env() -> HashMap<String, String> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+// Runs before the runtime exists, setting variables isn't safe once other threads run
+// dotenvy never overrides a variable that is already set
+fn load_env_file(path: Option<&Path>) -> Result<(), dotenvy::Error> {
+    let path = path.unwrap_or(Path::new(".env"));
+    match dotenvy::from_path(path) {
+        Err(e) if e.not_found() && path == Path::new(".env") => Ok(()),
+        result => result,
+    }
+}
+
+// Same as #[tokio::main] unless the config sets a thread count
+fn build_runtime(config: &RuntimeConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    builder.build()
+}
+
+async fn run(args: CliArgs, conf: Config) -> Result<(), Box<dyn Error>> {
     info!("RustCanvas starting up");
-    let conf = load_config("config");
+    // A reload must never stop to ask about creating a missing file
+    // (the environment is the same one the startup config was resolved from)
+    let reload_sources = ConfigSources {
+        create_missing: false,
+        ..config_sources(&args, process_env())
+    };
+    webserver::install_metrics_recorder();
     debug!("Configuration loaded");
-    info!("Attempting to load Database...");
-    let pathstr = conf.database_path.clone();
+    let pathstr = conf.database.path.clone();
     let path = Path::new(&pathstr);
-    let db = DatabaseConnection::new(path)?;
+    let options = DbOptions {
+        busy_timeout: Duration::from_millis(conf.database.busy_timeout_ms),
+        max_username_length: conf.auth.max_username_length,
+        migration_lock_timeout: Duration::from_secs(conf.database.migration_lock_timeout_secs),
+        slow_query_threshold: conf
+            .database
+            .slow_query_threshold_ms
+            .map(Duration::from_millis),
+        password_hash: match conf.auth.password_hash {
+            config::PasswordHashAlgorithm::Argon2id => HashAlgorithm::Argon2id,
+            config::PasswordHashAlgorithm::Scrypt => HashAlgorithm::Scrypt,
+            config::PasswordHashAlgorithm::Bcrypt => HashAlgorithm::Bcrypt,
+        },
+    };
+    let db = if !conf.database.enabled {
+        // An empty in-memory stand-in, so AppState always has a database. Nothing
+        // reads or writes it: the routes that would need it refuse, and the
+        // autosave, warmup and /ready's database check are skipped
+        info!("database.enabled is off, serving static files only");
+        DatabaseConnection::in_memory()?
+    } else {
+        info!("Attempting to load Database...");
+        match open_database(path, options, conf.database.recover_corrupt) {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Failed to open the database at {}: {}", pathstr, e);
+                return Err(e);
+            }
+        }
+    };
 
+    if !conf.database.enabled
+        && matches!(
+            args.command,
+            Some(Command::Seed { .. } | Command::CreateUser { .. } | Command::Maintenance { .. })
+        )
+    {
+        error!("This command needs the database, but database.enabled is off");
+        return Err("database.enabled is off".into());
+    }
+    match args.command {
+        Some(Command::Seed { force }) => return seed::run(&db, force),
+        Some(Command::CreateUser {
+            username,
+            admin,
+            permissions,
+        }) => {
+            return create_user::run(
+                &db,
+                username,
+                admin,
+                permissions.as_deref(),
+                conf.auth.default_permissions,
+            );
+        }
+        Some(Command::Maintenance { action }) => {
+            let banner = match action {
+                MaintenanceAction::Set { message } => Some(message),
+                MaintenanceAction::Clear => None,
+            };
+            db.set_setting(webserver::MAINTENANCE_BANNER_SETTING, &banner)?;
+            match banner {
+                Some(message) => println!("Maintenance banner set: {}", message),
+                None => println!("Maintenance banner cleared"),
+            }
+            return Ok(());
+        }
+        Some(Command::ShowConfig | Command::Healthcheck) | None => {}
+    }
+
+    log_startup_summary(&conf);
+    match db.exists_any_user() {
+        Ok(false) if conf.database.enabled => info!(
+            "No users yet, create the first admin with `rustcanvas create-user <username> --admin`"
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to check whether any user exists: {}", e),
+    }
     let state: AppState = AppState::new(conf, db);
-    let handles: Vec<JoinHandle<()>> = spawn_tasks!(state.clone(), start_webserver);
+    if state.is_read_only() {
+        warn!("Running in read-only mode, all changes will be refused");
+    }
+    if args.dry_run {
+        return dry_run(&state).await;
+    }
+
+    // Set before the server starts so /ready can't report 200 between binding and warmup
+    if !state.config.lock().await.server.warmup.tasks.is_empty() {
+        state.warming_up.store(true, Ordering::Relaxed);
+    }
+    let database_enabled = state.config.lock().await.database.enabled;
+    let mut handles: Vec<JoinHandle<()>> = spawn_tasks!(state.clone(), start_webserver);
+    // Without the database rooms can't be joined, so there's never anything to save
+    if database_enabled {
+        handles.push(tokio::spawn(
+            start_canvas_autosave(state.clone()).in_current_span(),
+        ));
+    }
+    let mut abort_handles: Vec<_> = handles.iter().map(|h| h.abort_handle()).collect();
+    // Not one of the tasks above, it finishing early (no SIGHUP support) is fine
+    let reload =
+        tokio::spawn(reload::reload_on_sighup(state.clone(), reload_sources).in_current_span());
+    abort_handles.push(reload.abort_handle());
+    // Finishes once it's done, so it can't be one of the tasks above either
+    let warmup = tokio::spawn(warmup::warmup(state.clone()).in_current_span());
+    abort_handles.push(warmup.abort_handle());
     // Wait for any task to complete, which means it failed, all of my tasks exit on failure only
     if !handles.is_empty() {
         select! {
@@ -35,10 +245,135 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     }
                 }
             }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received Ctrl+C, shutting down");
+            }
             else => {
                 info!("All tasks completed successfully");
             }
         }
     }
+    for handle in abort_handles {
+        handle.abort();
+    }
+    shutdown(state).await;
     Ok(())
 }
+
+// A corrupt file is only moved aside when database.recover_corrupt is on,
+// otherwise startup fails so nobody loses data without noticing
+fn open_database(
+    path: &Path,
+    options: DbOptions,
+    recover_corrupt: bool,
+) -> Result<DatabaseConnection, Box<dyn Error>> {
+    match DatabaseConnection::with_options(path, options.clone()) {
+        Err(e) if matches!(e.downcast_ref(), Some(DbError::Corrupt { .. })) => {
+            if !recover_corrupt {
+                warn!(
+                    "Restore the database from a backup, or set database.recover_corrupt to move it aside and start empty"
+                );
+                return Err(e);
+            }
+            error!("{}", e);
+            let moved = db::quarantine_corrupt_file(path)?;
+            warn!(
+                "Moved the corrupt database to {}, starting with an empty database at {}",
+                moved.display(),
+                path.display()
+            );
+            DatabaseConnection::with_options(path, options)
+        }
+        result => result,
+    }
+}
+
+// One event with everything that matters for "what is this instance running",
+// as fields so log collectors can pick them apart. Nothing secret goes in here
+fn log_startup_summary(conf: &Config) {
+    let bind = match conf.socket_addrs() {
+        Ok(addrs) => addrs
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(e) => format!("unresolved ({})", e),
+    };
+    info!(
+        server_name = %conf.server.instance_name(),
+        bind = %bind,
+        tls = false,
+        database = if conf.database.enabled { conf.database.path.as_str() } else { "none" },
+        // A single connection shared behind a lock, there is no pool
+        db_connections = 1,
+        log_level = %LevelFilter::current(),
+        log_filter = conf.logging.filter.as_deref().unwrap_or("default"),
+        public_registration = conf.auth.allow_public_registration,
+        read_only = conf.server.read_only,
+        "Startup summary"
+    );
+}
+
+fn setup_logging(config: &LoggingConfig) -> Option<LogGuard> {
+    let filter = config.filter.as_deref();
+    if config.file.enabled {
+        let options = FileLogOptions {
+            directory: config.file.directory.clone().into(),
+            file_name: config.file.file_name.clone(),
+            rotation: match config.file.rotation {
+                config::LogRotation::Hourly => LogRotation::Hourly,
+                config::LogRotation::Daily => LogRotation::Daily,
+                config::LogRotation::Never => LogRotation::Never,
+            },
+            max_files: config.file.max_files,
+        };
+        return Some(init_logging_with_file(filter, &options));
+    }
+    match filter {
+        Some(filter) => init_logging_with_filter(filter),
+        None => init_logging(),
+    }
+    None
+}
+
+// Say goodbye to connected clients, save what's still in memory and close the database cleanly
+async fn shutdown(state: AppState) {
+    state.running.store(false, Ordering::Relaxed);
+    // Save first, so nothing the clients do (or fail to do) on the way out can cost edits
+    let mut saved = state.canvas.flush_dirty(&state.db).await;
+    webserver::close_all_connections(&state).await;
+    // Draws that came in while the connections were closing
+    saved += state.canvas.flush_dirty(&state.db).await;
+    if saved > 0 {
+        info!("Saved {} room canvas(es) before exit", saved);
+    }
+    let db = state.db.clone();
+    drop(state);
+    // Connection tasks may still hold a clone of the state, in that case
+    // the best we can do is checkpoint and let the drop close it
+    let result = match Arc::try_unwrap(db) {
+        Ok(db) => db.into_inner().close(),
+        Err(db) => db.lock().await.checkpoint(),
+    };
+    match result {
+        Ok(()) => info!("Database closed"),
+        Err(e) => error!("Failed to close the database cleanly: {}", e),
+    }
+}
+
+// Everything up to here (config, database, migrations) already ran for real,
+// so all that's left is the router and the listener
+async fn dry_run(state: &AppState) -> Result<(), Box<dyn Error>> {
+    webserver::check_router(state);
+    match webserver::bind_listener(state).await {
+        Ok(listener) => {
+            drop(listener);
+            info!("Dry run succeeded, the server would start with this configuration");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Dry run failed, cannot bind the configured address: {}", e);
+            Err(e.into())
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// Config reload on SIGHUP, the usual convention for Unix daemons
+// Only fields the server reads on every use can change without a restart,
+// anything else keeps its old value and is reported as needing one
+use appstate::AppState;
+use config::{Config, ConfigSources};
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+use tracing::*;
+
+pub(crate) async fn reload_on_sighup(state: AppState, sources: ConfigSources) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!(
+                    "Can't listen for SIGHUP, config reload is unavailable: {}",
+                    e
+                );
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading the config");
+            reload(&state, &sources).await;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (state, sources);
+}
+
+async fn reload(state: &AppState, sources: &ConfigSources) {
+    let sources = sources.clone();
+    // Reads the file, keep it off the runtime threads
+    let new = match tokio::task::spawn_blocking(move || config::resolve(&sources)).await {
+        Ok(Ok(resolved)) => resolved.config,
+        Ok(Err(e)) => {
+            error!("Config reload failed, keeping the current config: {}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Config reload failed, keeping the current config: {}", e);
+            return;
+        }
+    };
+
+    let mut config = state.config.lock().await;
+    let before = tree(&config);
+    apply_reloadable(&mut config, &new);
+    if config.server.read_only != new.server.read_only {
+        config.server.read_only = new.server.read_only;
+        state
+            .read_only
+            .store(new.server.read_only, Ordering::Relaxed);
+    }
+    let applied = changed_fields(&before, &tree(&config));
+    let restart_required = changed_fields(&tree(&config), &tree(&new));
+    drop(config);
+
+    if applied.is_empty() && restart_required.is_empty() {
+        info!("Config reloaded, nothing changed");
+    }
+    if !applied.is_empty() {
+        info!("Config reloaded, applied {}", applied.join(", "));
+    }
+    if !restart_required.is_empty() {
+        warn!(
+            "Restart required for changes to {}, they keep their old values until then",
+            restart_required.join(", ")
+        );
+    }
+}
+
+// Everything read from state.config on each use rather than once at startup
+fn apply_reloadable(current: &mut Config, new: &Config) {
+    current.auth.allow_public_registration = new.auth.allow_public_registration;
+    current.auth.default_permissions = new.auth.default_permissions;
+    current.canvas.max_rooms = new.canvas.max_rooms;
+    current.canvas.max_participants_per_room = new.canvas.max_participants_per_room;
+    current.canvas.width = new.canvas.width;
+    current.canvas.height = new.canvas.height;
+    current.canvas.max_points_per_draw = new.canvas.max_points_per_draw;
+    current.canvas.max_stroke_width = new.canvas.max_stroke_width;
+    current.canvas.palette = new.canvas.palette.clone();
+    current.canvas.broadcast_batch_ms = new.canvas.broadcast_batch_ms;
+    current.server.static_cache = new.server.static_cache.clone();
+    // New connections only, open ones keep the limits they started with
+    current.server.websocket = new.server.websocket.clone();
+}
+
+fn tree(config: &Config) -> Value {
+    serde_json::to_value(config).expect("Failed to serialize config to JSON")
+}
+
+// Dotted paths of the leaf fields that differ
+fn changed_fields(old: &Value, new: &Value) -> Vec<String> {
+    fn walk(old: &Value, new: &Value, path: &str, out: &mut Vec<String>) {
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => {
+                for (key, old_value) in old {
+                    let new_value = new.get(key).unwrap_or(&Value::Null);
+                    let path = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    walk(old_value, new_value, &path, out);
+                }
+            }
+            _ if old != new => out.push(path.to_string()),
+            _ => {}
+        }
+    }
+    let mut out = Vec::new();
+    walk(old, new, "", &mut out);
+    out
+}
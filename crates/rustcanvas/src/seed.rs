@@ -0,0 +1,71 @@
+//! Demo data for local development (`rustcanvas seed`).
+
+use authentication::permissions;
+use db::{DatabaseConnection, DbError, NewUser};
+use protocol::messages::{CanvasObject, DrawOp, Shape};
+use std::error::Error;
+use tracing::*;
+
+/// Room the sample canvas gets saved under.
+const DEMO_ROOM: &str = "demo";
+
+const DEMO_ADMIN_PERMISSIONS: u16 = permissions::ALL;
+const DEMO_USER_PERMISSIONS: u16 = permissions::READ | permissions::WRITE;
+
+/// Username, password and permissions for each demo account.
+const DEMO_USERS: [(&str, &str, u16); 3] = [
+    ("admin", "admin-demo-password", DEMO_ADMIN_PERMISSIONS),
+    ("alice", "alice-demo-password", DEMO_USER_PERMISSIONS),
+    ("bob", "bob-demo-password", DEMO_USER_PERMISSIONS),
+];
+
+/// Populate the database with demo users and a sample canvas.
+///
+/// Refuses to touch a database that already has data unless `force` is set;
+/// with `force`, users that already exist are left alone.
+pub fn run(db: &DatabaseConnection, force: bool) -> Result<(), Box<dyn Error>> {
+    if !db.is_empty()? && !force {
+        return Err(
+            "Database is not empty, refusing to seed it (use --force to seed anyway)".into(),
+        );
+    }
+
+    println!("Demo users:");
+    for (username, password, permissions) in DEMO_USERS {
+        let user = NewUser {
+            username: username.to_string(),
+            password: password.to_string(),
+            permissions,
+        };
+        match db.create_user(&user) {
+            Ok(()) => println!("  {} / {}", username, password),
+            Err(DbError::DuplicateUsername(_)) => {
+                warn!("User '{}' already exists, leaving it unchanged", username);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let snapshot = serde_json::to_string(&demo_canvas())?;
+    db.save_room_snapshot(DEMO_ROOM, &snapshot)?;
+    println!("Sample canvas saved to room '{}'", DEMO_ROOM);
+    Ok(())
+}
+
+// A handful of objects so the demo room isn't blank
+fn demo_canvas() -> Vec<CanvasObject> {
+    let colors = [(230, 57, 70), (69, 123, 157), (42, 157, 143)];
+    colors
+        .iter()
+        .enumerate()
+        .map(|(i, color)| {
+            let x = 100.0 + 150.0 * i as f64;
+            CanvasObject::from(&DrawOp {
+                shape: Shape::Rect,
+                coords: vec![(x, 120.0), (x + 80.0, 200.0)],
+                color: *color,
+                stroke: 4.0,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,112 @@
+// Optional warmup (server.warmup) between binding and reporting ready
+// /live answers the whole time, /ready is 503 until this finishes or times out
+// Best effort: a failing task is logged and the next one runs
+use appstate::{AppState, RoomId};
+use config::WarmupTask;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+pub(crate) async fn warmup(state: AppState) {
+    let (warmup, database_enabled) = {
+        let config = state.config.lock().await;
+        (config.server.warmup.clone(), config.database.enabled)
+    };
+    if warmup.tasks.is_empty() {
+        return;
+    }
+    // Every task is about the database, there's nothing to warm up without it
+    if !database_enabled {
+        info!("database.enabled is off, skipping warmup");
+        state.warming_up.store(false, Ordering::Relaxed);
+        return;
+    }
+    let started = Instant::now();
+    let tasks = async {
+        // Nothing to warm up for until the port is actually taken
+        while state.bound_addr.get().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        for (index, task) in warmup.tasks.iter().enumerate() {
+            info!("Warmup {}/{}: {:?}", index + 1, warmup.tasks.len(), task);
+            run_task(&state, *task).await;
+        }
+    };
+    let timeout = Duration::from_secs(warmup.timeout_secs);
+    match tokio::time::timeout(timeout, tasks).await {
+        Ok(()) => info!(
+            "Warmup finished in {:.1}s, reporting ready",
+            started.elapsed().as_secs_f64()
+        ),
+        Err(_) => warn!(
+            "Warmup didn't finish within {}s, reporting ready anyway",
+            warmup.timeout_secs
+        ),
+    }
+    state.warming_up.store(false, Ordering::Relaxed);
+}
+
+async fn run_task(state: &AppState, task: WarmupTask) {
+    let started = Instant::now();
+    match task {
+        WarmupTask::Database => {
+            // It reads the whole file, off the runtime so the timeout can cut it short
+            let db = state.db.clone();
+            match tokio::task::spawn_blocking(move || db.blocking_lock().quick_check()).await {
+                Ok(Ok(problems)) if problems.is_empty() => {}
+                Ok(Ok(problems)) => error!(
+                    "Database quick check found problems: {}",
+                    problems.join("; ")
+                ),
+                Ok(Err(e)) => warn!("Database warmup failed: {}", e),
+                Err(e) => warn!("Database warmup failed: {}", e),
+            }
+        }
+        WarmupTask::Rooms => {
+            let mut names = match state.db.lock().await.room_snapshot_names() {
+                Ok(names) => names,
+                Err(e) => {
+                    warn!("Room warmup failed, couldn't list the saved rooms: {}", e);
+                    return;
+                }
+            };
+            let (max_rooms, keep_secs) = {
+                let config = state.config.lock().await;
+                (
+                    config.canvas.max_rooms,
+                    config.server.warmup.keep_rooms_secs,
+                )
+            };
+            // Most recently saved first, so the cap keeps the likeliest ones
+            let saved = names.len();
+            if let Some(max_rooms) = max_rooms {
+                names.truncate(max_rooms);
+            }
+            // Otherwise the next autosave drops them all again, nobody has joined yet
+            let keep_until = Instant::now() + Duration::from_secs(keep_secs);
+            let mut loaded = 0;
+            for name in &names {
+                let result = match RoomId::new(name.as_str()) {
+                    Some(room) => match state.canvas.ensure_loaded(&room, &state.db).await {
+                        Ok(()) => {
+                            state.canvas.keep_until(&room, keep_until).await;
+                            Ok(())
+                        }
+                        Err(e) => Err(e.to_string()),
+                    },
+                    None => Err("not a valid room name".to_string()),
+                };
+                match result {
+                    Ok(()) => loaded += 1,
+                    Err(e) => warn!("Failed to load room '{}' during warmup: {}", name, e),
+                }
+            }
+            info!("Loaded {} of {} saved rooms", loaded, saved);
+        }
+    }
+    debug!(
+        "Warmup task {:?} took {:.1}s",
+        task,
+        started.elapsed().as_secs_f64()
+    );
+}
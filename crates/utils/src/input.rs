@@ -17,7 +17,7 @@ use std::io::{self, Read};
 ///
 /// # Examples
 ///
-/// ```
+/// ```no_run
 /// use utils::input::choice;
 ///
 /// // Wait for user to press Y, N, or Escape
@@ -80,7 +80,7 @@ pub fn crossterm_choice(
 ) -> io::Result<char> {
     use crossterm::{
         event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
-        terminal, ExecutableCommand,
+        terminal,
     };
     use std::io::Write;
 
@@ -110,23 +110,18 @@ pub fn crossterm_choice(
             // Only process key press events (not key releases)
             if kind == KeyEventKind::Press {
                 match code {
-                    KeyCode::Char(c) => {
+                    KeyCode::Char(c)
                         if choices_vec.contains(&c)
                             || (!case_sensitive
-                                && choices_vec.contains(&c.to_lowercase().next().unwrap()))
-                        {
-                            break Ok(c);
-                        }
+                                && choices_vec.contains(&c.to_lowercase().next().unwrap())) =>
+                    {
+                        break Ok(c);
                     }
-                    KeyCode::Esc => {
-                        if choices.contains('\x1B') {
-                            break Ok('\x1B');
-                        }
+                    KeyCode::Esc if choices.contains('\x1B') => {
+                        break Ok('\x1B');
                     }
-                    KeyCode::Enter => {
-                        if choices.contains('\r') || choices.contains('\n') {
-                            break Ok('\n');
-                        }
+                    KeyCode::Enter if choices.contains('\r') || choices.contains('\n') => {
+                        break Ok('\n');
                     }
                     // Handle other special keys if needed
                     _ => {}
@@ -0,0 +1,248 @@
+// Classic access log (common/combined/json), one line per request
+// Written straight to its own file/stdout so it doesn't depend on the tracing filter
+use crate::client_ip::ClientIp;
+use axum::body::HttpBody;
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use config::{AccessLogConfig, AccessLogFormat};
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    // None when the access log is disabled
+    pub(crate) fn open(config: &AccessLogConfig) -> io::Result<Option<Arc<Self>>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let sink: Box<dyn Write + Send> = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Box::new(LineWriter::new(file))
+            }
+            None => Box::new(io::stdout()),
+        };
+        Ok(Some(Arc::new(Self {
+            format: config.format,
+            sink: Mutex::new(sink),
+        })))
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = entry.format(self.format);
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(sink, "{}", line) {
+            warn!("Failed to write access log entry: {}", e);
+        }
+    }
+}
+
+// Everything one log line needs, collected around the inner handler
+struct Entry {
+    time: SystemTime,
+    client_ip: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    bytes: Option<u64>,
+    latency: Duration,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn format(&self, format: AccessLogFormat) -> String {
+        let client_ip = self.client_ip.as_deref().unwrap_or("-");
+        let bytes = self.bytes.map_or("-".to_string(), |b| b.to_string());
+        let request_line = format!("{} {} {}", self.method, escape(&self.target), self.version);
+        match format {
+            AccessLogFormat::Common => format!(
+                "{} - - [{}] \"{}\" {} {} {}",
+                client_ip,
+                clf_time(self.time),
+                request_line,
+                self.status,
+                bytes,
+                self.latency.as_micros()
+            ),
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {}",
+                client_ip,
+                clf_time(self.time),
+                request_line,
+                self.status,
+                bytes,
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+                self.latency.as_micros()
+            ),
+            AccessLogFormat::Json => serde_json::json!({
+                "time": rfc3339_time(self.time),
+                "client_ip": self.client_ip,
+                "method": self.method,
+                "path": self.target,
+                "protocol": self.version,
+                "status": self.status,
+                "bytes": self.bytes,
+                "latency_us": self.latency.as_micros() as u64,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+        }
+    }
+}
+
+// Layered over the whole router
+// Latency is measured until the response headers are ready, streamed bodies aren't included
+pub(crate) async fn log_request(
+    State(log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let time = SystemTime::now();
+    let referer = header_value(&request, header::REFERER);
+    let user_agent = header_value(&request, header::USER_AGENT);
+    let (parts, body) = request.into_parts();
+    let client_ip = ClientIp::from_parts(&parts).map(|ip| ip.0.to_string());
+    let request = Request::from_parts(parts, body);
+    let method = request.method().to_string();
+    let target = request
+        .uri()
+        .path_and_query()
+        .map_or_else(|| request.uri().path().to_string(), |pq| pq.to_string());
+    let version = format!("{:?}", request.version());
+
+    let response = next.run(request).await;
+
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or_else(|| response.body().size_hint().exact());
+    log.write(&Entry {
+        time,
+        client_ip,
+        method,
+        target,
+        version,
+        status: response.status().as_u16(),
+        bytes,
+        latency: start.elapsed(),
+        referer,
+        user_agent,
+    });
+    response
+}
+
+fn header_value(request: &Request, name: header::HeaderName) -> Option<String> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+// Quotes and control characters would break the field boundaries
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+// e.g. 10/Oct/2000:13:55:36 +0000, always UTC
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+// e.g. 2000-10-10T13:55:36Z
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem / 60 % 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_formats() {
+        let entry = Entry {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            client_ip: Some("127.0.0.1".to_string()),
+            method: "GET".to_string(),
+            target: "/rooms?x=\"y\"".to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: Some(2326),
+            latency: Duration::from_micros(150),
+            referer: None,
+            user_agent: Some("curl/8.0".to_string()),
+        };
+        assert_eq!(
+            entry.format(AccessLogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /rooms?x=\\\"y\\\" HTTP/1.1\" 200 2326 150"
+        );
+        assert!(
+            entry
+                .format(AccessLogFormat::Combined)
+                .ends_with("2326 \"-\" \"curl/8.0\" 150")
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+    }
+}
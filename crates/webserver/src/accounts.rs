@@ -0,0 +1,178 @@
+// Account endpoints - self-service registration and first-run setup
+use crate::api_message::ApiMessage;
+use appstate::AppState;
+use authentication::permissions;
+use axum::Json;
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use db::{DbError, HashedUser, NewUser};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+#[derive(Deserialize)]
+pub(crate) struct RegisterRequest {
+    username: String,
+    password: String,
+}
+
+// POST /register
+// Turned off with auth.allow_public_registration = false, accounts then have to
+// be made with `rustcanvas create-user` (which works either way)
+pub(crate) async fn register(
+    State(state): State<AppState>,
+    request: Result<Json<RegisterRequest>, JsonRejection>,
+) -> ApiMessage {
+    // axum's own rejection is plain text, keep it negotiable like everything else
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => return ApiMessage::new(rejection.status(), rejection.body_text()),
+    };
+    if state.is_read_only() {
+        return ApiMessage::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is in read-only mode",
+        );
+    }
+    let (allowed, permissions) = {
+        let config = state.config.lock().await;
+        (
+            config.auth.allow_public_registration,
+            config.auth.default_permissions,
+        )
+    };
+    if !allowed {
+        return ApiMessage::new(StatusCode::FORBIDDEN, "Public registration is disabled");
+    }
+    if request.password.is_empty() {
+        return ApiMessage::new(StatusCode::BAD_REQUEST, "Password must not be empty");
+    }
+
+    let user = NewUser {
+        username: request.username,
+        password: request.password,
+        permissions,
+    };
+    let username = user.username.clone();
+    let result = match hash_new_user(&state, user).await {
+        Ok(user) => state.users.lock().await.create_hashed_user(&user),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => {
+            info!("Registered new user '{}'", username);
+            ApiMessage::new(StatusCode::CREATED, "Registered")
+        }
+        Err(e @ DbError::InvalidUsername(_)) => {
+            ApiMessage::new(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        Err(e @ DbError::DuplicateUsername(_)) => {
+            ApiMessage::new(StatusCode::CONFLICT, e.to_string())
+        }
+        Err(e) => {
+            error!("Failed to register user '{}': {}", username, e);
+            ApiMessage::new(StatusCode::INTERNAL_SERVER_ERROR, "Registration failed")
+        }
+    }
+}
+
+// Hashing takes tens of milliseconds of CPU on purpose, on a runtime worker
+// and under the users lock every registration would stall both, so it runs on
+// a blocking thread before the lock is taken
+async fn hash_new_user(state: &AppState, user: NewUser) -> Result<HashedUser, DbError> {
+    let algorithm = state.users.lock().await.password_hash();
+    tokio::task::spawn_blocking(move || user.hash(algorithm))
+        .await
+        .expect("Password hashing panicked")
+}
+
+#[derive(Serialize)]
+pub(crate) struct SetupStatus {
+    setup_needed: bool,
+}
+
+// GET /setup/needed
+// True until the first user exists, so the frontend can offer to create an admin
+pub(crate) async fn setup_needed(
+    State(state): State<AppState>,
+) -> Result<Json<SetupStatus>, ApiMessage> {
+    let exists = state.users.lock().await.exists_any_user();
+    match exists {
+        Ok(exists) => Ok(Json(SetupStatus {
+            setup_needed: !exists,
+        })),
+        Err(e) => {
+            warn!("Failed to check whether any user exists: {}", e);
+            Err(ApiMessage::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Database unavailable",
+            ))
+        }
+    }
+}
+
+// POST /setup, same body as /register
+// Creates the first account with every permission, only while there are no
+// users at all, so there never is a default admin password. Whoever reaches a
+// fresh instance first gets it, so finish setup before exposing one publicly
+pub(crate) async fn setup(
+    State(state): State<AppState>,
+    request: Result<Json<RegisterRequest>, JsonRejection>,
+) -> ApiMessage {
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => return ApiMessage::new(rejection.status(), rejection.body_text()),
+    };
+    if state.is_read_only() {
+        return ApiMessage::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is in read-only mode",
+        );
+    }
+    if request.password.is_empty() {
+        return ApiMessage::new(StatusCode::BAD_REQUEST, "Password must not be empty");
+    }
+
+    let already_done = || ApiMessage::new(StatusCode::CONFLICT, "Setup has already been done");
+    // Saves hashing a password for nothing, create_first_hashed_user checks again either way
+    let exists = state.users.lock().await.exists_any_user();
+    match exists {
+        Ok(true) => return already_done(),
+        Ok(false) => {}
+        Err(e) => {
+            error!("Initial setup failed: {}", e);
+            return ApiMessage::new(StatusCode::INTERNAL_SERVER_ERROR, "Setup failed");
+        }
+    }
+    let user = NewUser {
+        username: request.username,
+        password: request.password,
+        permissions: permissions::ALL,
+    };
+    let username = user.username.clone();
+    let result = match hash_new_user(&state, user).await {
+        Ok(user) => state.users.lock().await.create_first_hashed_user(&user),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(true) => {
+            warn!(
+                "Initial setup: created admin account '{}', POST /setup is disabled from now on",
+                username
+            );
+            ApiMessage::new(StatusCode::CREATED, "Admin account created")
+        }
+        Ok(false) => already_done(),
+        Err(e @ DbError::InvalidUsername(_)) => {
+            ApiMessage::new(StatusCode::BAD_REQUEST, e.to_string())
+        }
+        // Held for a registration that's still being confirmed
+        Err(e @ DbError::DuplicateUsername(_)) => {
+            ApiMessage::new(StatusCode::CONFLICT, e.to_string())
+        }
+        Err(e) => {
+            error!("Initial setup failed: {}", e);
+            ApiMessage::new(StatusCode::INTERNAL_SERVER_ERROR, "Setup failed")
+        }
+    }
+}
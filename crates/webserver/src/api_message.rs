@@ -0,0 +1,118 @@
+// Short messages (errors, "Registered", ...) that follow the Accept header
+// JSON by default, plain text for clients that prefer it (curl, scripts)
+// The response itself can't see the request, so negotiate() swaps the body afterwards
+use axum::extract::Request;
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
+use std::borrow::Cow;
+
+/// A status code with a one-line message, for errors and simple confirmations.
+///
+/// Rendered as `{"error": "..."}` (4xx/5xx) or `{"message": "..."}`, or as the bare
+/// message for clients whose `Accept` header prefers `text/plain`.
+#[derive(Debug, Clone)]
+pub struct ApiMessage {
+    status: StatusCode,
+    message: Cow<'static, str>,
+}
+
+impl ApiMessage {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+// Left on the response so negotiate() can re-render it as text
+#[derive(Clone)]
+struct PlainText(Cow<'static, str>);
+
+impl IntoResponse for ApiMessage {
+    fn into_response(self) -> Response {
+        let key = if self.status.is_client_error() || self.status.is_server_error() {
+            "error"
+        } else {
+            "message"
+        };
+        let body = serde_json::json!({ key: self.message });
+        let mut response = (self.status, Json(body)).into_response();
+        response.extensions_mut().insert(PlainText(self.message));
+        response
+    }
+}
+
+// Layered over the whole router
+pub(crate) async fn negotiate(request: Request, next: Next) -> Response {
+    let wants_text = prefers_plain_text(request.headers());
+    let response = next.run(request).await;
+    if !wants_text {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Some(PlainText(message)) = parts.extensions.remove::<PlainText>() else {
+        return Response::from_parts(parts, body);
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Response::from_parts(parts, message.into_owned().into())
+}
+
+// True when text/plain ranks above JSON in Accept, ties and no header go to JSON
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let mut text = 0.0f32;
+    let mut json = 0.0f32;
+    let mut any = false;
+    for value in headers.get_all(header::ACCEPT) {
+        let Ok(value) = value.to_str() else { continue };
+        for range in value.split(',') {
+            let mut params = range.split(';');
+            let media = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            any = true;
+            match media.as_str() {
+                "text/plain" | "text/*" => text = text.max(quality),
+                "application/json" | "application/*" => json = json.max(quality),
+                "*/*" => {
+                    text = text.max(quality);
+                    json = json.max(quality);
+                }
+                _ => {}
+            }
+        }
+    }
+    any && text > json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_prefers_plain_text() {
+        assert!(!prefers_plain_text(&HeaderMap::new()));
+        assert!(!prefers_plain_text(&accept("*/*")));
+        assert!(prefers_plain_text(&accept("text/plain")));
+        assert!(prefers_plain_text(&accept("text/plain, */*;q=0.8")));
+        assert!(!prefers_plain_text(&accept("application/json, text/plain")));
+        assert!(!prefers_plain_text(&accept("text/html")));
+    }
+}
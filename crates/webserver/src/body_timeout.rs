@@ -0,0 +1,82 @@
+// Deadline for receiving the whole request body, slow uploads get a 408
+// The body is read up front, which is fine for our small JSON bodies
+use crate::api_message::ApiMessage;
+use axum::body::{Body, HttpBody};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::time::Duration;
+
+// Same as axum's default body limit, bodies are buffered before any extractor sees them
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+pub(crate) async fn read_body_with_timeout(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Nothing to wait for on bodyless requests (GETs, WebSocket upgrades)
+    if request.body().size_hint().exact() == Some(0) {
+        return next.run(request).await;
+    }
+    let (parts, body) = request.into_parts();
+    match tokio::time::timeout(timeout, axum::body::to_bytes(body, MAX_BODY_SIZE)).await {
+        Ok(Ok(bytes)) => {
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        Ok(Err(e)) if is_length_limit(&e) => {
+            ApiMessage::new(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response()
+        }
+        // The client gave up mid-upload, or sent a malformed (e.g. chunked) body
+        Ok(Err(_)) => ApiMessage::new(StatusCode::BAD_REQUEST, "Failed to read the request body")
+            .into_response(),
+        Err(_) => ApiMessage::new(
+            StatusCode::REQUEST_TIMEOUT,
+            "Timed out reading the request body",
+        )
+        .into_response(),
+    }
+}
+
+// to_bytes reports going over MAX_BODY_SIZE like any other body error
+fn is_length_limit(err: &axum::Error) -> bool {
+    use std::error::Error;
+    err.source()
+        .is_some_and(|source| source.is::<http_body_util::LengthLimitError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::post;
+    use tower::ServiceExt;
+
+    async fn status(body: Body) -> StatusCode {
+        let router: Router = Router::new().route("/", post(|| async { "ok" })).layer(
+            axum::middleware::from_fn_with_state(Duration::from_secs(5), read_body_with_timeout),
+        );
+        let request = Request::post("/").body(body).unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_body_errors() {
+        assert_eq!(status(Body::from("{}")).await, StatusCode::OK);
+        assert_eq!(
+            status(Body::from(vec![0; MAX_BODY_SIZE + 1])).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Like a client that hung up halfway through
+        let aborted = futures::stream::iter([
+            Ok(axum::body::Bytes::from("{")),
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+        ]);
+        assert_eq!(
+            status(Body::from_stream(aborted)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
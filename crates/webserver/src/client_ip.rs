@@ -0,0 +1,232 @@
+// Works out the real client address when running behind reverse proxies
+// Forwarding headers are only believed when the direct peer is a trusted proxy,
+// otherwise anyone could spoof their address by sending X-Forwarded-For themselves
+use crate::api_message::ApiMessage;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::*;
+
+/// A network range, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address is a /32 (or /128).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = addr.trim().parse().ok()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+        Some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 peers (dual stack sockets) should match IPv4 ranges
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    let rest_bits = prefix % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// The configured set of trusted proxies, shared with every request as an extension.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<Vec<Cidr>>);
+
+impl TrustedProxies {
+    // Entries that don't parse are logged and skipped, the worst case is
+    // seeing the proxy address instead of the client's
+    pub fn from_config(entries: &[String]) -> Self {
+        let ranges = entries
+            .iter()
+            .filter_map(|entry| {
+                let cidr = Cidr::parse(entry);
+                if cidr.is_none() {
+                    error!("Ignoring invalid trusted proxy range '{}'", entry);
+                }
+                cidr
+            })
+            .collect();
+        Self(Arc::new(ranges))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+
+    /// Resolve the client address for a request that arrived from `peer`.
+    ///
+    /// Walks the forwarding chain from the closest hop outwards and returns the
+    /// first address that isn't a trusted proxy. `Forwarded` wins over
+    /// `X-Forwarded-For` when both are present.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let chain = forwarded_chain(headers);
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            // Obfuscated/unknown entries, nothing useful beyond this point
+            let Some(ip) = hop else { break };
+            client = *ip;
+            if !self.contains(*ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// Every hop listed in the forwarding headers, client first
+// None for entries that aren't an IP (e.g. "unknown" or "_hidden")
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value.trim().trim_matches('"')))
+                })
+            })
+            .collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+// Accepts 1.2.3.4, 1.2.3.4:5678, 2001:db8::1 and [2001:db8::1]:5678
+fn parse_node(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+/// The resolved client address, taking trusted proxies into account.
+///
+/// Needs the server to run with connect info, requests without it are rejected with a 500.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    pub(crate) fn from_parts(parts: &Parts) -> Option<Self> {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>()?.0.ip();
+        let ip = match parts.extensions.get::<TrustedProxies>() {
+            Some(proxies) => proxies.resolve(peer, &parts.headers),
+            None => peer,
+        };
+        Some(Self(ip))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = ApiMessage;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_parts(parts).ok_or(ApiMessage::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Client address unavailable",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let range = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(range.contains("10.1.200.3".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(
+            Cidr::parse("fd00::/8")
+                .unwrap()
+                .contains("fd12::1".parse().unwrap())
+        );
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+    }
+
+    #[test]
+    fn test_resolve_only_trusts_configured_proxies() {
+        let proxies = TrustedProxies::from_config(&["10.0.0.0/8".to_string()]);
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.9".parse().unwrap();
+        let spoofed = headers(&[("x-forwarded-for", "1.1.1.1, 198.51.100.7, 10.0.0.5")]);
+
+        // The rightmost untrusted hop is the client, anything left of it could be forged
+        assert_eq!(
+            proxies.resolve(proxy, &spoofed),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        // Headers from an untrusted peer are ignored entirely
+        assert_eq!(proxies.resolve(stranger, &spoofed), stranger);
+
+        let forwarded = headers(&[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https")]);
+        assert_eq!(
+            proxies.resolve(proxy, &forwarded),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
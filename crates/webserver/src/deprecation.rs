@@ -0,0 +1,95 @@
+// Deprecation (RFC 9745) and Sunset (RFC 8594) headers for routes on their way out
+// Opt-in per route in get_router, wrapping the route with deprecated(), so
+// every other route goes without the extra layer
+use axum::http::{HeaderName, HeaderValue, header};
+use axum::response::Response;
+use axum::routing::MethodRouter;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub(crate) struct Deprecation {
+    // Unix timestamp (seconds) from when on the route is deprecated
+    pub since: u64,
+    // Unix timestamp (seconds) from when on it may be gone, None if not decided yet
+    pub sunset: Option<u64>,
+    // Path of the route to use instead
+    pub successor: Option<&'static str>,
+}
+
+impl Deprecation {
+    fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let value =
+            |text: String| HeaderValue::from_str(&text).expect("Invalid deprecation header");
+        let mut headers = vec![(
+            HeaderName::from_static("deprecation"),
+            value(format!("@{}", self.since)),
+        )];
+        if let Some(sunset) = self.sunset {
+            let date = SystemTime::UNIX_EPOCH + Duration::from_secs(sunset);
+            headers.push((
+                HeaderName::from_static("sunset"),
+                value(httpdate::fmt_http_date(date)),
+            ));
+        }
+        if let Some(successor) = self.successor {
+            headers.push((
+                header::LINK,
+                value(format!("<{}>; rel=\"successor-version\"", successor)),
+            ));
+        }
+        headers
+    }
+}
+
+// The route, answering with the deprecation headers on every response
+pub(crate) fn deprecated<S>(route: MethodRouter<S>, deprecation: Deprecation) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let headers = Arc::new(deprecation.headers());
+    route.layer(axum::middleware::map_response(
+        move |mut response: Response| {
+            let headers = headers.clone();
+            async move {
+                for (name, value) in headers.iter() {
+                    response.headers_mut().insert(name.clone(), value.clone());
+                }
+                response
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_deprecated_route_headers() {
+        let deprecation = Deprecation {
+            since: 1_791_936_000,
+            sunset: Some(1_798_761_600),
+            successor: Some("/new"),
+        };
+        let router: Router = Router::new()
+            .route("/old", deprecated(get(|| async { "old" }), deprecation))
+            .route("/new", get(|| async { "new" }));
+        let get_headers = |uri: &'static str| {
+            let router = router.clone();
+            async move {
+                let request = axum::http::Request::get(uri)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                router.oneshot(request).await.unwrap().headers().clone()
+            }
+        };
+        let old = get_headers("/old").await;
+        assert_eq!(old["deprecation"], "@1791936000");
+        assert_eq!(old["sunset"], "Fri, 01 Jan 2027 00:00:00 GMT");
+        assert_eq!(old[header::LINK], "</new>; rel=\"successor-version\"");
+        assert!(!get_headers("/new").await.contains_key("deprecation"));
+    }
+}
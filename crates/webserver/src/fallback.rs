@@ -0,0 +1,65 @@
+// Answers for paths no route matches
+// Browsers navigating to an unknown page get the frontend, like a SPA would,
+// everything else (API clients, scripts, missing assets) gets a 404 message
+use crate::api_message::ApiMessage;
+use appstate::AppState;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+
+pub(crate) async fn not_found(
+    State(state): State<AppState>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    if !is_page_navigation(&method, &headers) {
+        return ApiMessage::new(StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    let policy = state.config.lock().await.server.static_cache.index.clone();
+    let mut response = crate::get_index().into_response();
+    if let Ok(value) = HeaderValue::from_str(&policy) {
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    response
+}
+
+// A GET/HEAD that explicitly asks for HTML is a browser loading a page,
+// fetch() and curl send */* or application/json and count as API calls
+fn is_page_navigation(method: &Method, headers: &HeaderMap) -> bool {
+    if method != Method::GET && method != Method::HEAD {
+        return false;
+    }
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| {
+            let media = range.split(';').next().unwrap_or_default().trim();
+            media.eq_ignore_ascii_case("text/html")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_only_browser_navigations_get_the_page() {
+        let browser = accept("text/html,application/xhtml+xml,*/*;q=0.8");
+        assert!(is_page_navigation(&Method::GET, &browser));
+        assert!(!is_page_navigation(&Method::POST, &browser));
+        assert!(!is_page_navigation(&Method::GET, &accept("*/*")));
+        assert!(!is_page_navigation(
+            &Method::GET,
+            &accept("application/json")
+        ));
+        assert!(!is_page_navigation(&Method::GET, &HeaderMap::new()));
+    }
+}
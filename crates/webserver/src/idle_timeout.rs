@@ -0,0 +1,161 @@
+// Closes connections that go quiet for too long
+// Wraps every accepted stream, any traffic in either direction resets the clock,
+// so slow handlers and long downloads don't get cut off
+use axum::serve::Listener;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep, sleep};
+
+pub(crate) struct IdleTimeoutListener<L> {
+    inner: L,
+    timeout: Option<Duration>,
+}
+
+impl<L> IdleTimeoutListener<L> {
+    // None turns the timeout off, streams are passed through untouched
+    pub(crate) fn new(inner: L, timeout: Option<Duration>) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<L: Listener> Listener for IdleTimeoutListener<L> {
+    type Io = IdleTimeoutStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (io, addr) = self.inner.accept().await;
+        (IdleTimeoutStream::new(io, self.timeout), addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+pub(crate) struct IdleTimeoutStream<T> {
+    inner: T,
+    // Timeout and the timer counting it down
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<T> IdleTimeoutStream<T> {
+    fn new(inner: T, timeout: Option<Duration>) -> Self {
+        let idle = timeout.map(|timeout| (timeout, Box::pin(sleep(timeout))));
+        Self { inner, idle }
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    fn reset(&mut self) {
+        if let Some((timeout, timer)) = &mut self.idle {
+            timer.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    // Called whenever the inner stream has nothing for us, errors out once the timer ran down
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let Some((_, timer)) = &mut self.idle else {
+            return Poll::Pending;
+        };
+        timer
+            .as_mut()
+            .poll(cx)
+            .map(|()| io::Error::new(io::ErrorKind::TimedOut, "connection idle for too long"))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > before {
+                    this.reset();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_expired(cx).map(Err),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => {
+                if written > 0 {
+                    this.reset();
+                }
+                Poll::Ready(Ok(written))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => this.poll_expired(cx).map(Err),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(Ok(written)) => {
+                if written > 0 {
+                    this.reset();
+                }
+                Poll::Ready(Ok(written))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => this.poll_expired(cx).map(Err),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    #[tokio::test]
+    async fn test_idle_stream_times_out() {
+        let (client, server) = duplex(64);
+        let mut server = IdleTimeoutStream::new(server, Some(Duration::from_millis(50)));
+        let mut client = client;
+
+        client.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        server.read_exact(&mut buf).await.unwrap();
+
+        // Nothing arrives after that, so the next read fails once the window is up
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,13 +1,48 @@
 #![allow(unused_imports)]
-use appstate::{AppState, ConnectionId, MessageSender};
+mod access_log;
+mod accounts;
+mod api_message;
+mod body_timeout;
+mod client_ip;
+mod deprecation;
+mod fallback;
+mod idle_timeout;
+mod pagination;
+mod prometheus;
+mod request_timeout;
+mod rooms;
+mod security_headers;
+mod serve;
+mod static_cache;
+mod status;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
+
+pub use api_message::ApiMessage;
+pub use client_ip::{Cidr, ClientIp, TrustedProxies};
+pub use pagination::{Paginated, Pagination};
+pub use prometheus::install_metrics_recorder;
+
+use appstate::{AppState, ConnectionId, LagState, MessageSender, RoomId};
 use axum::Router;
 use axum::body::Bytes;
-use axum::extract::Path;
-use axum::extract::ws::{Message, WebSocketUpgrade};
-use axum::response::Html;
+use axum::extract::ws::{CloseFrame, Message, WebSocketUpgrade, close_code};
+use axum::extract::{Path, Query};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Json};
 use axum::routing::{get, post};
+use axum::serve::ListenerExt;
 use axum_extra::response::*;
+pub use config::DUAL_STACK_INTERFACE;
+use config::LagPolicy;
 use futures::{Future, SinkExt, StreamExt};
+use protocol::messages::{RoomInfo, ServerMessage};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+pub use status::MAINTENANCE_BANNER_SETTING;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
@@ -15,52 +50,307 @@ use tokio::task::JoinHandle;
 use tokio::time::interval;
 use tracing::*;
 
+// How long a closing connection gets to flush its last queued messages
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+// How long shutdown waits for clients to acknowledge the going-away close
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub async fn start_webserver(state: AppState) {
     start_listening(state).await;
 }
 
 fn get_router(state: AppState) -> axum::Router {
-    Router::new()
+    // The frontend files, these get Cache-Control headers per server.static_cache
+    let static_routes = Router::new()
         .route("/", get(|| async { get_index() }))
         .route("/index.js", get(|| async { get_index_js() }))
         .route("/jquery.min.js", get(|| async { get_jquery() }))
         .route("/proto-client.js", get(|| async { get_proto_js() }))
         .route("/stylesheet.css", get(|| async { get_stylesheet() }))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            static_cache::set_cache_control,
+        ));
+    // Everything that needs the database, refused when database.enabled is off
+    let database_routes = Router::new()
+        .route("/register", post(accounts::register))
+        .route("/setup", post(accounts::setup))
+        .route("/setup/needed", get(accounts::setup_needed))
+        .route(
+            "/rooms",
+            get(|state: axum::extract::State<AppState>, page: Pagination| get_rooms(state, page)),
+        )
         .route(
             "/ws",
             get(
-                |ws: WebSocketUpgrade, state: axum::extract::State<AppState>| {
-                    handle_ws_upgrade(ws, state)
+                |ws: WebSocketUpgrade,
+                 state: axum::extract::State<AppState>,
+                 params: Query<WsParams>,
+                 client_ip: Result<ClientIp, ApiMessage>| {
+                    handle_ws_upgrade(ws, state, params, client_ip.ok())
+                },
+            ),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_database,
+        ));
+    Router::new()
+        .merge(static_routes)
+        .merge(database_routes)
+        // Liveness: the process is up and serving, nothing else is checked
+        .route(
+            "/live",
+            get(|| async { ApiMessage::new(StatusCode::OK, "OK") }),
+        )
+        // Readiness: see get_ready, /health is the older name for it
+        .route(
+            "/ready",
+            get(|state: axum::extract::State<AppState>| get_ready(state)),
+        )
+        .route(
+            "/health",
+            deprecation::deprecated(
+                get(|state: axum::extract::State<AppState>| get_ready(state)),
+                deprecation::Deprecation {
+                    // 2026-10-14, when /live and /ready took over
+                    since: 1_791_936_000,
+                    sunset: None,
+                    successor: Some("/ready"),
                 },
             ),
         )
+        .route("/version", get(|| async { get_version() }))
+        .route("/status", get(status::get_status))
+        .route("/metrics", get(|| async { prometheus::render() }))
+        .fallback(fallback::not_found)
         .with_state(state)
 }
 
 async fn start_listening(state: AppState) {
-    let router = get_router(state.clone());
-    let (internal, external) = parse_config(state).await;
+    let mut router = get_router(state.clone());
+    let (header_timeout, body_timeout, request_timeout) = {
+        let config = state.config.lock().await;
+        (
+            config
+                .server
+                .header_read_timeout_secs
+                .map(Duration::from_secs),
+            config
+                .server
+                .body_read_timeout_secs
+                .map(Duration::from_secs),
+            config.server.request_timeout_secs.map(Duration::from_secs),
+        )
+    };
+    // Innermost, it only times the handlers
+    if let Some(timeout) = request_timeout {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            timeout,
+            request_timeout::limit_request_time,
+        ));
+    }
+    // Inside the access log, so timed out requests still get logged
+    if let Some(timeout) = body_timeout {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            timeout,
+            body_timeout::read_body_with_timeout,
+        ));
+    }
+    // Around everything that answers with an ApiMessage, the body timeout included
+    router = router.layer(axum::middleware::from_fn(api_message::negotiate));
+    let security_config = state.config.lock().await.server.security_headers.clone();
+    if let Some(headers) = security_headers::SecurityHeaders::from_config(&security_config) {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            headers,
+            security_headers::set_security_headers,
+        ));
+    }
+    let server_name = {
+        let config = state.config.lock().await;
+        config
+            .server
+            .server_name_header
+            .then(|| config.server.instance_name())
+    };
+    if let Some(name) = server_name {
+        match HeaderValue::from_str(&name) {
+            Ok(value) => {
+                router = router.layer(axum::middleware::map_response(
+                    move |mut response: axum::response::Response| {
+                        let value = value.clone();
+                        async move {
+                            response.headers_mut().insert("x-server-name", value);
+                            response
+                        }
+                    },
+                ));
+            }
+            Err(_) => warn!(
+                "Server name '{}' can't be sent as a header, skipping X-Server-Name",
+                name
+            ),
+        }
+    }
+    let access_log_config = state.config.lock().await.logging.access_log.clone();
+    match access_log::AccessLog::open(&access_log_config) {
+        Ok(Some(log)) => {
+            router = router.layer(axum::middleware::from_fn_with_state(
+                log,
+                access_log::log_request,
+            ));
+        }
+        Ok(None) => {}
+        Err(e) => error!(
+            "Failed to open the access log, continuing without it: {}",
+            e
+        ),
+    }
+    // Outermost, so everything below (access log included) can resolve client IPs
+    let trusted_proxies = state.config.lock().await.server.trusted_proxies.clone();
+    router = router.layer(axum::Extension(TrustedProxies::from_config(
+        &trusted_proxies,
+    )));
+    let (internal, external) = parse_config(state.clone()).await;
     info!("Starting webserver on {} ({})", &external, &internal);
-    let listener = TcpListener::bind(&internal)
+    let listener = bind_listener(&state)
         .await
         .expect("Failed to bind to address");
-    let server = axum::serve(listener, router).await;
-    if let Err(e) = server {
-        error!("Failed to start web server: \n\t{}", e);
+    match listener.local_addr() {
+        Ok(addr) => {
+            if internal.ends_with(":0") {
+                info!("Listening on port {} picked by the OS", addr.port());
+            }
+            let _ = state.bound_addr.set(addr);
+        }
+        Err(e) => warn!("Couldn't read the bound address: {}", e),
+    }
+    let idle_timeout = state
+        .config
+        .lock()
+        .await
+        .server
+        .http_idle_timeout_secs
+        .map(Duration::from_secs);
+    let listener = idle_timeout::IdleTimeoutListener::new(listener, idle_timeout);
+    let keepalive = tcp_keepalive(&state).await;
+    let listener = listener.tap_io(move |stream| {
+        if let Some(keepalive) = &keepalive
+            && let Err(e) = SockRef::from(stream.get_ref()).set_tcp_keepalive(keepalive)
+        {
+            warn!("Failed to enable TCP keepalive on a connection: {}", e);
+        }
+    });
+    serve::serve(listener, router, header_timeout).await;
+}
+
+/// Binds the configured interface/port without serving anything on it yet.
+///
+/// Used by the server itself, and by `--dry-run` to check the address is usable.
+pub async fn bind_listener(state: &AppState) -> std::io::Result<TcpListener> {
+    let config = state.config.lock().await.clone();
+    // Resolving a hostname blocks, keep it off the runtime threads
+    let addrs =
+        tokio::task::spawn_blocking(move || config.socket_addrs().map(|addrs| (config, addrs)))
+            .await
+            .map_err(std::io::Error::other)?;
+    let (config, addrs) =
+        addrs.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let backlog = config.server.listen_backlog;
+    if config.server.interface == DUAL_STACK_INTERFACE {
+        return bind_dual_stack(config.server.port, backlog);
+    }
+    // Same as TcpListener::bind: try every address the name resolves to
+    let mut last_error = None;
+    for addr in addrs {
+        match bind_socket(addr, backlog, None) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
     }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+// Built by hand instead of TcpListener::bind so the backlog is configurable
+// One [::] socket for both IPv4 and IPv6 clients
+// Some systems (OpenBSD) refuse to turn IPV6_V6ONLY off, there we make do with IPv4
+fn bind_dual_stack(port: u16, backlog: u32) -> std::io::Result<TcpListener> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    match bind_socket(addr, backlog, Some(false)) {
+        Ok(listener) => Ok(listener),
+        Err(e) if e.kind() != std::io::ErrorKind::AddrInUse => {
+            warn!(
+                "Dual-stack listening isn't available ({}), only accepting IPv4 connections",
+                e
+            );
+            bind_socket(
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+                backlog,
+                None,
+            )
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// only_v6 is left at the OS default when None
+fn bind_socket(
+    addr: SocketAddr,
+    backlog: u32,
+    only_v6: Option<bool>,
+) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(only_v6) = only_v6 {
+        socket.set_only_v6(only_v6)?;
+    }
+    // tokio does this too, so restarts don't trip over sockets in TIME_WAIT
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+// None when keepalive is turned off
+async fn tcp_keepalive(state: &AppState) -> Option<TcpKeepalive> {
+    let config = state.config.lock().await.server.tcp_keepalive.clone();
+    if !config.enabled {
+        return None;
+    }
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.idle_secs));
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive.with_interval(Duration::from_secs(config.interval_secs));
+    Some(keepalive)
+}
+
+/// Builds the full router, to check route setup without starting the server.
+pub fn check_router(state: &AppState) {
+    let _ = get_router(state.clone());
 }
 
 //returns the functional and display strings for the network and interface
 async fn parse_config(state: AppState) -> (String, String) {
     let config = state.config.lock().await;
-    let network = config.network.clone();
-    let interface = network.interface.clone();
-    let port = network.port;
-    drop(network);
+    let interface = config.server.interface.clone();
+    let port = config.server.port;
     drop(config);
     let functional = format!("{}:{}", interface, port);
     let display_interface: String = match interface.as_str() {
-        "0.0.0.0" => "*".to_string(),
+        "0.0.0.0" | DUAL_STACK_INTERFACE => "*".to_string(),
         "127.0.0.1" => "localhost".to_string(),
         _ => interface.clone(),
     };
@@ -68,64 +358,244 @@ async fn parse_config(state: AppState) -> (String, String) {
     (functional, display)
 }
 
+// Query params accepted on /ws
+// e.g. /ws?room=sketches drops the client straight into that room
+#[derive(Deserialize)]
+struct WsParams {
+    room: Option<String>,
+}
+
 // NOTE TO SELF: This handles the HTTP->WS upgrade dance
 // Remember: ws.on_upgrade needs an async block inside!
 async fn handle_ws_upgrade(
     ws: WebSocketUpgrade,
     state: axum::extract::State<AppState>,
+    params: Query<WsParams>,
+    client_ip: Option<ClientIp>,
 ) -> axum::response::Response {
     let state = state.0.clone();
-    ws.on_upgrade(move |socket| async move {
-        // Handle client in this async block, which will be spawned by axum
-        handle_client(socket, state.clone()).await;
+    // Reject bad room names before upgrading, the client gets a plain 400
+    let room = match params.0.room.map(RoomId::new) {
+        Some(None) => {
+            return ApiMessage::new(StatusCode::BAD_REQUEST, "Invalid room name").into_response();
+        }
+        Some(Some(room)) => Some(room),
+        None => None,
+    };
+    let limits = state.config.lock().await.server.websocket.clone();
+    // Oversized frames/messages get rejected by the WS layer itself, we just
+    // spot the resulting error on the receive side and close with a policy code
+    let ws = ws
+        .max_message_size(limits.max_message_size)
+        .max_frame_size(limits.max_frame_size);
+    // axum spawns the upgraded connection on its own, keep our span
+    let span = Span::current();
+    let log = ConnectionLog {
+        client_ip: client_ip.map(|ip| ip.0),
+        enabled: limits.log_connections,
+    };
+    ws.on_upgrade(move |socket| {
+        async move {
+            // Handle client in this async block, which will be spawned by axum
+            handle_client(socket, state.clone(), room, log).await;
+        }
+        .instrument(span)
     })
 }
 
+// Connect/disconnect events for abuse investigations (server.websocket.log_connections)
+// There are no WS logins yet, so no username to go with them
+#[derive(Clone, Copy)]
+struct ConnectionLog {
+    client_ip: Option<IpAddr>,
+    enabled: bool,
+}
+
+impl ConnectionLog {
+    fn client_ip(&self) -> String {
+        self.client_ip
+            .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+    }
+
+    fn connected(&self, conn_id: ConnectionId, room: Option<&RoomId>) {
+        if self.enabled {
+            info!(
+                connection_id = %conn_id,
+                client_ip = %self.client_ip(),
+                room = room.map(RoomId::as_str),
+                "WebSocket connected"
+            );
+        } else {
+            debug!("Registered new WebSocket connection: {}", conn_id);
+        }
+    }
+
+    fn disconnected(&self, conn_id: ConnectionId, reason: CloseReason, duration: Duration) {
+        if self.enabled {
+            info!(
+                connection_id = %conn_id,
+                client_ip = %self.client_ip(),
+                reason = %reason,
+                duration_secs = duration.as_secs(),
+                "WebSocket disconnected"
+            );
+        } else {
+            debug!("WebSocket connection {} closed: {}", conn_id, reason);
+        }
+    }
+}
+
+// Why a connection ended, whichever of its tasks stopped first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseReason {
+    ClientClosed,
+    // The stream ended without a close frame
+    Disconnected,
+    TimedOut,
+    MessageTooLarge,
+    ProtocolError,
+    // Sending to the socket failed, or the server dropped the connection
+    SendFailed,
+    // Fell behind with server.websocket.on_lag = disconnect
+    TooSlow,
+    ShuttingDown,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::Disconnected => "disconnected",
+            CloseReason::TimedOut => "timed_out",
+            CloseReason::MessageTooLarge => "message_too_large",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::SendFailed => "send_failed",
+            CloseReason::TooSlow => "too_slow",
+            CloseReason::ShuttingDown => "shutting_down",
+        })
+    }
+}
+
 // Main entry point for WebSockets - this gets called for each connection
 // TODO: Add metrics tracking here later?
-async fn handle_client(socket: axum::extract::ws::WebSocket, state: AppState) {
+async fn handle_client(
+    socket: axum::extract::ws::WebSocket,
+    state: AppState,
+    room: Option<RoomId>,
+    log: ConnectionLog,
+) {
     debug!("New WebSocket connection established");
+    let connected = Instant::now();
 
     // Set up the connection and register it with the app state
-    let connection_id = setup_connection(socket, state.clone()).await;
+    let (connection_id, reason) = setup_connection(socket, state.clone(), room, log).await;
 
     // Once the connection is terminated, clean it up
-    state.ws_connections.unregister(connection_id).await;
-    debug!("WebSocket connection {} closed", connection_id);
+    cleanup_connection(&state, connection_id).await;
+    log.disconnected(connection_id, reason, connected.elapsed());
+}
+
+/// Tell every WebSocket client the server is going away and close their connections.
+///
+/// Each one gets a `shutting_down` message and a close frame with code 1001
+/// (going away) behind whatever is already queued for it. Clients whose queue
+/// is full get neither, they're just waited out with the rest. Returns once
+/// every connection is gone, or after a short grace period for clients that
+/// don't answer the close.
+pub async fn close_all_connections(state: &AppState) {
+    let ids = state.ws_connections.all_ids().await;
+    if ids.is_empty() {
+        return;
+    }
+    let notice = ServerMessage::ShuttingDown.to_json();
+    for id in &ids {
+        if let Some(sender) = state.ws_connections.get(*id).await {
+            // Never wait for room here, one stalled client would hold up the whole shutdown
+            if sender
+                .try_send(Message::Text(notice.clone().into()))
+                .is_err()
+            {
+                continue;
+            }
+            let frame = CloseFrame {
+                code: close_code::AWAY,
+                reason: "Server shutting down".into(),
+            };
+            let _ = sender.try_send(Message::Close(Some(frame)));
+        }
+    }
+    info!("Closing {} WebSocket connection(s)", ids.len());
+    let drained = tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, async {
+        while state.ws_connections.count().await > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "{} WebSocket connection(s) didn't close in time",
+            state.ws_connections.count().await
+        );
+    }
+}
+
+// Leave any room (so the others hear about it) and drop out of the registry
+// Safe to call more than once
+async fn cleanup_connection(state: &AppState, conn_id: ConnectionId) {
+    rooms::leave_room(state, conn_id).await;
+    state.ws_connections.unregister(conn_id).await;
 }
 
 // Split the connection into the parts we need and set everything up
 // This was tricky to get right - don't mess with the order of operations
-async fn setup_connection(socket: axum::extract::ws::WebSocket, state: AppState) -> ConnectionId {
+async fn setup_connection(
+    socket: axum::extract::ws::WebSocket,
+    state: AppState,
+    room: Option<RoomId>,
+    log: ConnectionLog,
+) -> (ConnectionId, CloseReason) {
     // Split the socket into sender and receiver
     let (sender, receiver) = socket.split();
 
     // Set up the message plumbing and get this connection registered
-    let (connection_id, rx) = register_connection(state.clone()).await;
-    info!("Registered new WebSocket connection: {}", connection_id);
+    let (connection_id, rx, lag) = register_connection(state.clone()).await;
+    log.connected(connection_id, room.as_ref());
+
+    // Join the room from the query string, if there was one
+    if let Some(room) = room {
+        rooms::join_room(&state, connection_id, room).await;
+    }
 
     // Spin up the worker tasks - each one does a specific job
-    let tasks = spawn_connection_tasks(sender, receiver, rx, state, connection_id);
+    let tasks = spawn_connection_tasks(sender, receiver, rx, lag, state.clone(), connection_id);
 
     // Wait until something breaks, then clean everything up
     // Could add reconnect logic here later if needed
-    wait_for_tasks_completion(tasks).await;
+    let reason = wait_for_tasks_completion(tasks, state, connection_id).await;
 
     // Return the connection ID for cleanup
-    connection_id
+    (connection_id, reason)
 }
 
 // Create a channel and register the connection with our global state
 // IMPORTANT: This is how clients get their unique IDs
-async fn register_connection(state: AppState) -> (ConnectionId, mpsc::Receiver<Message>) {
+async fn register_connection(
+    state: AppState,
+) -> (ConnectionId, mpsc::Receiver<Message>, Arc<LagState>) {
+    let (send_buffer, on_lag) = {
+        let config = state.config.lock().await;
+        let websocket = &config.server.websocket;
+        (websocket.send_buffer, websocket.on_lag)
+    };
     // Channel for sending messages from various tasks to the WebSocket
-    let (tx, rx) = mpsc::channel::<Message>(100);
+    let (tx, rx) = mpsc::channel::<Message>(send_buffer);
 
     // Make a sender and register it - this lets other parts of the app message this client
-    let message_sender = MessageSender::new(tx);
+    let message_sender = MessageSender::with_lag_policy(tx, on_lag);
+    let lag = message_sender.lag_state();
     let connection_id = state.ws_connections.register(message_sender).await;
 
-    (connection_id, rx)
+    (connection_id, rx, lag)
 }
 
 // Fire up the three tasks we need for each connection
@@ -134,14 +604,15 @@ fn spawn_connection_tasks(
     sender: futures::stream::SplitSink<axum::extract::ws::WebSocket, Message>,
     receiver: futures::stream::SplitStream<axum::extract::ws::WebSocket>,
     rx: mpsc::Receiver<Message>,
+    lag: Arc<LagState>,
     state: AppState,
     conn_id: ConnectionId,
 ) -> (
-    tokio::task::JoinHandle<()>,
-    tokio::task::JoinHandle<()>,
-    tokio::task::JoinHandle<()>,
+    tokio::task::JoinHandle<CloseReason>,
+    tokio::task::JoinHandle<CloseReason>,
+    tokio::task::JoinHandle<CloseReason>,
 ) {
-    let send_task = spawn_send_task(sender, rx, conn_id);
+    let send_task = spawn_send_task(sender, rx, lag, conn_id);
     let heartbeat_task = spawn_heartbeat_task(state.clone(), conn_id);
     let receive_task = spawn_receive_task(receiver, state, conn_id);
 
@@ -152,21 +623,33 @@ fn spawn_connection_tasks(
 // This prevents resource leaks - learned this the hard way...
 async fn wait_for_tasks_completion(
     (mut send_task, mut heartbeat_task, mut receive_task): (
-        tokio::task::JoinHandle<()>,
-        tokio::task::JoinHandle<()>,
-        tokio::task::JoinHandle<()>,
+        tokio::task::JoinHandle<CloseReason>,
+        tokio::task::JoinHandle<CloseReason>,
+        tokio::task::JoinHandle<CloseReason>,
     ),
-) {
-    tokio::select! {
-        _ = &mut send_task => {},
-        _ = &mut heartbeat_task => {},
-        _ = &mut receive_task => {},
+    state: AppState,
+    conn_id: ConnectionId,
+) -> CloseReason {
+    // A task that panicked or got aborted counts as the send side giving up
+    let (reason, receive_finished) = tokio::select! {
+        reason = &mut send_task => (reason.unwrap_or(CloseReason::SendFailed), false),
+        reason = &mut heartbeat_task => (reason.unwrap_or(CloseReason::SendFailed), false),
+        reason = &mut receive_task => (reason.unwrap_or(CloseReason::SendFailed), true),
+    };
+
+    // The receive side may have queued a close frame on its way out (policy
+    // violations do this). Dropping the registry entry lets the channel drain,
+    // so give the send task a moment to flush it before tearing things down
+    if receive_finished {
+        cleanup_connection(&state, conn_id).await;
+        let _ = tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, &mut send_task).await;
     }
 
     // Abort all tasks when one completes/fails
     send_task.abort();
     heartbeat_task.abort();
     receive_task.abort();
+    reason
 }
 
 // Task 1: Send messages from our app to the client
@@ -174,18 +657,20 @@ async fn wait_for_tasks_completion(
 fn spawn_send_task(
     sender: futures::stream::SplitSink<axum::extract::ws::WebSocket, Message>,
     rx: mpsc::Receiver<Message>,
+    lag: Arc<LagState>,
     conn_id: ConnectionId,
-) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        process_outgoing_messages(sender, rx, conn_id).await;
-    })
+) -> tokio::task::JoinHandle<CloseReason> {
+    tokio::spawn(
+        async move { process_outgoing_messages(sender, rx, lag, conn_id).await }.in_current_span(),
+    )
 }
 
 /// Spawns a task that sends periodic pings to keep the connection alive
-fn spawn_heartbeat_task(state: AppState, conn_id: ConnectionId) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        send_heartbeats(state, conn_id).await;
-    })
+fn spawn_heartbeat_task(
+    state: AppState,
+    conn_id: ConnectionId,
+) -> tokio::task::JoinHandle<CloseReason> {
+    tokio::spawn(async move { send_heartbeats(state, conn_id).await }.in_current_span())
 }
 
 /// Spawns a task that processes incoming messages from the WebSocket
@@ -193,42 +678,74 @@ fn spawn_receive_task(
     receiver: futures::stream::SplitStream<axum::extract::ws::WebSocket>,
     state: AppState,
     conn_id: ConnectionId,
-) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        process_incoming_messages(receiver, state, conn_id).await;
-    })
+) -> tokio::task::JoinHandle<CloseReason> {
+    tokio::spawn(
+        async move { process_incoming_messages(receiver, state, conn_id).await }.in_current_span(),
+    )
 }
 
 /// Process outgoing messages from the channel to the WebSocket
 async fn process_outgoing_messages(
     mut sender: futures::stream::SplitSink<axum::extract::ws::WebSocket, Message>,
     mut rx: mpsc::Receiver<Message>,
+    lag: Arc<LagState>,
     conn_id: ConnectionId,
-) {
-    while let Some(message) = rx.recv().await {
-        if let Err(e) = sender.send(message).await {
+) -> CloseReason {
+    // Disconnecting can't wait for the queue, the socket is what's stuck
+    let disconnect = lag.policy() == LagPolicy::Disconnect;
+    let reason = loop {
+        let message = tokio::select! {
+            message = rx.recv() => message,
+            _ = lag.overflowed(), if disconnect => break CloseReason::TooSlow,
+        };
+        let Some(message) = message else {
+            break CloseReason::SendFailed;
+        };
+        let sent = tokio::select! {
+            sent = sender.send(message) => sent,
+            _ = lag.overflowed(), if disconnect => break CloseReason::TooSlow,
+        };
+        if let Err(e) = sent {
             error!(
                 "Connection {}: Error sending WebSocket message: {}",
                 conn_id, e
             );
-            break;
+            break CloseReason::SendFailed;
         }
+        // Caught up after dropping messages, tell the client to resync
+        if lag.policy() == LagPolicy::Resync && rx.is_empty() && lag.take_lagged() {
+            debug!(
+                "Connection {} caught up after lagging, asking it to resync",
+                conn_id
+            );
+            let lagged = Message::Text(ServerMessage::Lagged.to_json().into());
+            if sender.send(lagged).await.is_err() {
+                break CloseReason::SendFailed;
+            }
+        }
+    };
+    if reason == CloseReason::TooSlow {
+        warn!(
+            "Connection {} fell behind, disconnecting it (server.websocket.on_lag)",
+            conn_id
+        );
     }
     debug!("Send task for connection {} terminated", conn_id);
+    reason
 }
 
 // Keep the connection alive with pings
 // 30 sec interval seems to work well with most clients & proxies
-async fn send_heartbeats(state: AppState, conn_id: ConnectionId) {
+async fn send_heartbeats(state: AppState, conn_id: ConnectionId) -> CloseReason {
     let mut interval = interval(Duration::from_secs(30));
 
-    loop {
+    let reason = loop {
         interval.tick().await;
 
         // Bail out if app is shutting down
         if !state.running.load(std::sync::atomic::Ordering::Relaxed) {
             debug!("Heartbeat task shutting down");
-            break;
+            break CloseReason::ShuttingDown;
         }
 
         // Only ping if client still exists (avoid zombies)
@@ -238,14 +755,15 @@ async fn send_heartbeats(state: AppState, conn_id: ConnectionId) {
                 .await
                 .is_err()
             {
-                break;
+                break CloseReason::SendFailed;
             }
         } else {
-            break;
+            break CloseReason::SendFailed;
         }
-    }
+    };
 
     debug!("Heartbeat task for connection {} terminated", conn_id);
+    reason
 }
 
 // Process stuff coming from the client
@@ -254,19 +772,21 @@ async fn process_incoming_messages(
     mut receiver: futures::stream::SplitStream<axum::extract::ws::WebSocket>,
     state: AppState,
     conn_id: ConnectionId,
-) {
+) -> CloseReason {
     let mut last_pong = Instant::now();
     let timeout = Duration::from_secs(90); // 3x the ping interval seems to work well
 
+    let mut reason = CloseReason::Disconnected;
     while let Some(result) = receiver.next().await {
         match result {
             Ok(Message::Text(text)) => {
-                // No message handling here - that's for the application layer
                 trace!(
                     "Connection {}: Received text message of length {}",
                     conn_id,
                     text.len()
                 );
+                // Text frames carry JSON control messages (rooms etc.)
+                rooms::handle_text_message(&state, conn_id, text.as_str()).await;
             }
             Ok(Message::Binary(data)) => {
                 // Binary messages just get logged - actual handling elsewhere
@@ -278,14 +798,16 @@ async fn process_incoming_messages(
             }
             Ok(Message::Close(_)) => {
                 debug!("Connection {}: Client initiated close", conn_id);
+                reason = CloseReason::ClientClosed;
                 break;
             }
             Ok(Message::Ping(data)) => {
                 // Gotta respond to pings - WS protocol requirement
-                if let Some(sender) = state.ws_connections.get(conn_id).await {
-                    if sender.send(Message::Pong(data)).await.is_err() {
-                        break;
-                    }
+                if let Some(sender) = state.ws_connections.get(conn_id).await
+                    && sender.send(Message::Pong(data)).await.is_err()
+                {
+                    reason = CloseReason::SendFailed;
+                    break;
                 }
             }
             Ok(Message::Pong(_)) => {
@@ -293,8 +815,18 @@ async fn process_incoming_messages(
                 last_pong = Instant::now();
                 // Silently update timestamp, no logging needed
             }
+            Err(e) if is_message_too_large(&e) => {
+                warn!(
+                    "Connection {}: Closing connection, message exceeded size limit: {}",
+                    conn_id, e
+                );
+                close_with_policy_violation(&state, conn_id, "Message too large").await;
+                reason = CloseReason::MessageTooLarge;
+                break;
+            }
             Err(e) => {
                 debug!("Connection {}: WebSocket error: {}", conn_id, e);
+                reason = CloseReason::ProtocolError;
                 break;
             }
         }
@@ -302,14 +834,106 @@ async fn process_incoming_messages(
         // Check if client ghosted us
         if last_pong.elapsed() > timeout {
             debug!("Connection {}: Client timed out", conn_id);
+            reason = CloseReason::TimedOut;
             break;
         }
     }
 
     debug!("Receive task for connection {} terminated", conn_id);
+    reason
+}
+
+// Readiness check - fails while shutting down or when the database doesn't answer
+// A failure should take the instance out of rotation, not restart it (that's /live)
+// Load balancers hit this a lot, so keep it cheap
+async fn get_ready(state: axum::extract::State<AppState>) -> ApiMessage {
+    if !state.running.load(std::sync::atomic::Ordering::Relaxed) {
+        return ApiMessage::new(StatusCode::SERVICE_UNAVAILABLE, "Shutting down");
+    }
+    if state.warming_up.load(std::sync::atomic::Ordering::Relaxed) {
+        return ApiMessage::new(StatusCode::SERVICE_UNAVAILABLE, "Warming up");
+    }
+    // Static-only mode has nothing behind the stand-in database to check
+    if !state.config.lock().await.database.enabled {
+        return ApiMessage::new(StatusCode::OK, "OK");
+    }
+    let db = state.db.lock().await;
+    match db.ping() {
+        // Stays set until a write succeeds again, autosave keeps retrying in the meantime
+        Ok(()) if db.is_disk_full() => ApiMessage::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Disk full, writes are failing",
+        ),
+        Ok(()) => ApiMessage::new(StatusCode::OK, "OK"),
+        Err(e) => {
+            warn!("Health check failed, database did not respond: {}", e);
+            ApiMessage::new(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable")
+        }
+    }
+}
+
+// Static-only mode: no accounts and no rooms to draw in
+async fn require_database(
+    state: axum::extract::State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if !state.config.lock().await.database.enabled {
+        return ApiMessage::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "Not available, this server runs without a database",
+        )
+        .into_response();
+    }
+    next.run(request).await
 }
 
-fn get_index() -> Html<String> {
+// The WS layer reports oversized frames/messages as a capacity error
+// Anything else (resets, protocol errors) is just a normal disconnect
+fn is_message_too_large(err: &axum::Error) -> bool {
+    use std::error::Error;
+    matches!(
+        err.source()
+            .and_then(|source| source.downcast_ref::<tungstenite::Error>()),
+        Some(tungstenite::Error::Capacity(_))
+    )
+}
+
+// Queue a close frame with the policy violation code (1008)
+async fn close_with_policy_violation(state: &AppState, conn_id: ConnectionId, reason: &str) {
+    if let Some(sender) = state.ws_connections.get(conn_id).await {
+        let frame = CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+        };
+        let _ = sender.send(Message::Close(Some(frame))).await;
+    }
+}
+
+// Build info, so it's easy to tell which build a deployed instance runs
+// Filled in by build.rs, nothing in here is sensitive
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    commit: &'static str,
+    // Unix seconds
+    build_timestamp: u64,
+}
+
+fn get_version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("RUSTCANVAS_GIT_COMMIT"),
+        build_timestamp: env!("RUSTCANVAS_BUILD_TIMESTAMP").parse().unwrap_or(0),
+    })
+}
+
+// Lobby listing - same data as the ListRooms WS message
+async fn get_rooms(state: axum::extract::State<AppState>, page: Pagination) -> Paginated<RoomInfo> {
+    page.apply(rooms::list_rooms(&state.0).await)
+}
+
+pub(crate) fn get_index() -> Html<String> {
     include_str!("htmlsrc/index.html").to_string().into()
 }
 
@@ -328,3 +952,89 @@ fn get_stylesheet() -> Css<String> {
 fn get_proto_js() -> JavaScript<String> {
     include_str!("htmlsrc/proto-client.js").to_string().into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serves_on_an_os_picked_port() {
+        let mut config = config::Config::default();
+        config.server.interface = "127.0.0.1".to_string();
+        config.server.port = 0;
+        config.logging.access_log.enabled = false;
+        let state = AppState::new(config, db::DatabaseConnection::in_memory().unwrap());
+        let server = tokio::spawn(start_webserver(state.clone()));
+
+        let addr = loop {
+            if let Some(addr) = state.bound_addr.get() {
+                break *addr;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_ne!(addr.port(), 0);
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_static_only_without_database() {
+        use tower::ServiceExt;
+        let mut config = config::Config::default();
+        config.database.enabled = false;
+        let router = get_router(AppState::new(
+            config,
+            db::DatabaseConnection::in_memory().unwrap(),
+        ));
+        let status = |method: &str, uri: &str| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let router = router.clone();
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+        assert_eq!(status("GET", "/").await, StatusCode::OK);
+        assert_eq!(status("GET", "/live").await, StatusCode::OK);
+        assert_eq!(status("GET", "/ready").await, StatusCode::OK);
+        assert_eq!(
+            status("POST", "/register").await,
+            StatusCode::NOT_IMPLEMENTED
+        );
+        assert_eq!(
+            status("GET", "/setup/needed").await,
+            StatusCode::NOT_IMPLEMENTED
+        );
+        assert_eq!(status("GET", "/ws").await, StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_close_all_connections_skips_full_queues() {
+        let state = AppState::new(
+            config::Config::default(),
+            db::DatabaseConnection::in_memory().unwrap(),
+        );
+        // A client that stopped reading, its queue is full and stays full
+        let (tx, _rx) = mpsc::channel::<Message>(1);
+        let sender = MessageSender::new(tx);
+        sender.send(Message::Text("backlog".into())).await.unwrap();
+        let id = state.ws_connections.register(sender).await;
+        let registry = state.ws_connections.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            registry.unregister(id).await;
+        });
+        tokio::time::timeout(Duration::from_secs(1), close_all_connections(&state))
+            .await
+            .expect("Shutdown waited on a full queue");
+    }
+}
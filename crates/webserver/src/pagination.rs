@@ -0,0 +1,110 @@
+// Shared ?limit=&offset= handling for list endpoints
+// Every list responds with a plain JSON array plus an X-Total-Count header
+use crate::api_message::ApiMessage;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Json, Response};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// `?limit=&offset=` from the query string.
+///
+/// `limit` defaults to 50 and is capped at 500, `offset` defaults to 0.
+/// Anything that isn't a non-negative integer is rejected with a 400.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PaginationParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Pagination {
+    type Rejection = ApiMessage;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<PaginationParams>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| {
+                ApiMessage::new(
+                    StatusCode::BAD_REQUEST,
+                    "limit and offset must be non-negative integers",
+                )
+            })?;
+        Ok(Self {
+            limit: params.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+            offset: params.offset.unwrap_or(0),
+        })
+    }
+}
+
+impl Pagination {
+    /// Cut one page out of a list that is already fully in memory.
+    pub fn apply<T>(&self, items: Vec<T>) -> Paginated<T> {
+        let total = items.len() as u64;
+        let items = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+        Paginated { items, total }
+    }
+}
+
+/// One page of a list, plus how many items there are in total.
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+}
+
+impl<T: Serialize> IntoResponse for Paginated<T> {
+    fn into_response(self) -> Response {
+        (
+            [("x-total-count", self.total.to_string())],
+            Json(self.items),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn extract(uri: &str) -> Result<Pagination, ApiMessage> {
+        let (mut parts, _) = Request::get(uri).body(()).unwrap().into_parts();
+        Pagination::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_pagination_defaults_and_caps() {
+        assert_eq!(extract("/rooms").await.unwrap(), Pagination::default());
+        let capped = extract("/rooms?limit=100000&offset=3").await.unwrap();
+        assert_eq!((capped.limit, capped.offset), (MAX_LIMIT, 3));
+        assert!(extract("/rooms?limit=-1").await.is_err());
+
+        let page = Pagination {
+            limit: 2,
+            offset: 1,
+        }
+        .apply(vec![1, 2, 3, 4]);
+        assert_eq!((page.items, page.total), (vec![2, 3], 4));
+    }
+}
@@ -0,0 +1,41 @@
+// Prometheus exposition for everything recorded through the `metrics` crate
+// The recorder is process wide, so the handle lives in a static instead of AppState
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use tracing::*;
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+// Seconds, tuned for SQLite calls: most are well under a millisecond
+const DURATION_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Install the global metrics recorder that GET /metrics renders.
+///
+/// Call once at startup before anything records metrics, later calls do nothing.
+pub fn install_metrics_recorder() {
+    if HANDLE.get().is_some() {
+        return;
+    }
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            &DURATION_BUCKETS,
+        )
+        .and_then(|builder| builder.install_recorder());
+    match recorder {
+        Ok(handle) => {
+            let _ = HANDLE.set(handle);
+        }
+        Err(e) => warn!("Failed to install the metrics recorder: {}", e),
+    }
+}
+
+// GET /metrics, empty if no recorder got installed
+pub(crate) fn render() -> String {
+    HANDLE
+        .get()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
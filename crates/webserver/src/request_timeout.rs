@@ -0,0 +1,70 @@
+// Upper bound for how long a handler may take, answered with a 504 when exceeded
+// Runs inside the body timeout, so only handler time counts, not a slow upload
+// The handler is dropped at its next await point; a database call that is
+// already running on the thread finishes first, it can't be interrupted
+use crate::api_message::ApiMessage;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::time::Duration;
+use tracing::*;
+
+// Long-lived by design, the upgrade response itself is quick but whatever
+// runs after it must not be cut off
+const EXCLUDED_PATHS: [&str; 1] = ["/ws"];
+
+pub(crate) async fn limit_request_time(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if EXCLUDED_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let path = request.uri().path().to_string();
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(
+                "Request to {} took longer than {:?}, gave up",
+                path, timeout
+            );
+            ApiMessage::new(StatusCode::GATEWAY_TIMEOUT, "The request took too long")
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_slow_handlers_time_out_except_excluded() {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            "done"
+        };
+        let router = Router::new()
+            .route("/slow", get(slow))
+            .route("/ws", get(slow))
+            .layer(axum::middleware::from_fn_with_state(
+                Duration::from_millis(20),
+                limit_request_time,
+            ));
+        let status = |path: &'static str| {
+            let router = router.clone();
+            async move {
+                let request = Request::get(path).body(Body::empty()).unwrap();
+                router.oneshot(request).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/slow").await, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(status("/ws").await, StatusCode::OK);
+    }
+}
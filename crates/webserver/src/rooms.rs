@@ -0,0 +1,542 @@
+// Room handling for WebSocket clients
+// Everything here talks JSON text frames, see protocol::messages
+use appstate::{
+    AddObjectError, AppState, ConnectionId, JoinError, PendingOp, Recipients, RoomId, RoomLimits,
+    UndoError,
+};
+use protocol::messages::{ClientMessage, DrawLimits, DrawOp, RoomInfo, ServerMessage};
+use std::time::Duration;
+use tracing::*;
+
+// Entry point for text frames - parse and dispatch to the right handler
+pub(crate) async fn handle_text_message(state: &AppState, conn_id: ConnectionId, text: &str) {
+    let message = match ClientMessage::from_json(text) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Connection {}: Malformed client message: {}", conn_id, e);
+            send_error(state, conn_id, "Malformed message").await;
+            return;
+        }
+    };
+
+    match message {
+        ClientMessage::JoinRoom { room } => match RoomId::new(room) {
+            Some(room) => join_room(state, conn_id, room).await,
+            None => send_error(state, conn_id, "Invalid room name").await,
+        },
+        ClientMessage::LeaveRoom => {
+            if let Some(room) = leave_room(state, conn_id).await {
+                let left = ServerMessage::RoomLeft {
+                    room: room.to_string(),
+                };
+                send_to(state, conn_id, &left).await;
+            } else {
+                send_error(state, conn_id, "Not in a room").await;
+            }
+        }
+        ClientMessage::ListRooms => {
+            let rooms = ServerMessage::RoomList {
+                rooms: list_rooms(state).await,
+            };
+            send_to(state, conn_id, &rooms).await;
+        }
+        ClientMessage::Draw { op, base_sequence } => draw(state, conn_id, op, base_sequence).await,
+        ClientMessage::Undo { op } => undo_or_redo(state, conn_id, op, false).await,
+        ClientMessage::Redo { op } => undo_or_redo(state, conn_id, op, true).await,
+        ClientMessage::RequestState => match state.ws_connections.room_of(conn_id).await {
+            Some(room) => send_snapshot(state, conn_id, &room).await,
+            None => send_error(state, conn_id, "Not in a room").await,
+        },
+    }
+}
+
+// Apply a draw to the sender's room and pass it on to everyone else there
+async fn draw(state: &AppState, conn_id: ConnectionId, op: DrawOp, base_sequence: Option<u64>) {
+    if state.is_read_only() {
+        send_error(state, conn_id, "Server is in read-only mode").await;
+        return;
+    }
+    let limits = {
+        let config = state.config.lock().await;
+        let canvas = &config.canvas;
+        DrawLimits {
+            width: canvas.width.into(),
+            height: canvas.height.into(),
+            max_points: canvas.max_points_per_draw,
+            max_stroke: canvas.max_stroke_width,
+            palette: canvas.palette.clone(),
+        }
+    };
+    if let Err(e) = op.validate(&limits) {
+        debug!("Connection {}: Rejected draw: {}", conn_id, e);
+        send_error(state, conn_id, &e.to_string()).await;
+        return;
+    }
+    let Some(room) = state.ws_connections.room_of(conn_id).await else {
+        send_error(state, conn_id, "Join a room before drawing").await;
+        return;
+    };
+
+    // The room is normally loaded already, this only hits the database if it
+    // got evicted between our join and now
+    if let Err(e) = state.canvas.ensure_loaded(&room, &state.db).await {
+        error!("Failed to load canvas for room {}: {}", room, e);
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    }
+    let Some(order) = state.canvas.order_lock(&room).await else {
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    };
+    let _order = order.lock().await;
+    let sequence = match state
+        .canvas
+        .add_object(&room, (&op).into(), conn_id, base_sequence)
+        .await
+    {
+        Ok(sequence) => sequence,
+        Err(AddObjectError::StaleBase { current }) => {
+            let conflict = ServerMessage::Conflict {
+                room: room.to_string(),
+                sequence: current,
+            };
+            send_to(state, conn_id, &conflict).await;
+            return;
+        }
+        Err(AddObjectError::NotLoaded) => {
+            send_error(state, conn_id, "Could not load the room canvas").await;
+            return;
+        }
+    };
+
+    let applied = ServerMessage::DrawApplied {
+        room: room.to_string(),
+        sequence,
+    };
+    broadcast_op(state, &room, applied, Recipients::Only(conn_id)).await;
+    let drawn = ServerMessage::Draw {
+        room: room.to_string(),
+        connection_id: conn_id.0,
+        op,
+        sequence,
+    };
+    broadcast_op(state, &room, drawn, Recipients::AllBut(conn_id)).await;
+}
+
+// Undo (redo = false) or redo an operation in the sender's room and tell everyone there
+async fn undo_or_redo(state: &AppState, conn_id: ConnectionId, op: Option<u64>, redo: bool) {
+    if state.is_read_only() {
+        send_error(state, conn_id, "Server is in read-only mode").await;
+        return;
+    }
+    let Some(room) = state.ws_connections.room_of(conn_id).await else {
+        send_error(state, conn_id, "Join a room first").await;
+        return;
+    };
+    if let Err(e) = state.canvas.ensure_loaded(&room, &state.db).await {
+        error!("Failed to load canvas for room {}: {}", room, e);
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    }
+    let Some(order) = state.canvas.order_lock(&room).await else {
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    };
+    let _order = order.lock().await;
+    let result = if redo {
+        state.canvas.redo(&room, conn_id, op).await
+    } else {
+        state.canvas.undo(&room, conn_id, op).await
+    };
+    let (op, sequence) = match result {
+        Ok(done) => done,
+        Err(e) => {
+            let message = match e {
+                UndoError::NotLoaded => "Could not load the room canvas".to_string(),
+                UndoError::NothingToUndo => "Nothing to undo".to_string(),
+                UndoError::NothingToRedo => "Nothing to redo".to_string(),
+                UndoError::UnknownOp(op) => format!("Operation {} is not in the undo history", op),
+                UndoError::NotAuthor(op) => format!("Operation {} is not yours to undo", op),
+                UndoError::AlreadyUndone(op) => format!("Operation {} is already undone", op),
+                UndoError::NotUndone(op) => format!("Operation {} is not undone", op),
+            };
+            send_error(state, conn_id, &message).await;
+            return;
+        }
+    };
+
+    let (room_name, connection_id) = (room.to_string(), conn_id.0);
+    let message = if redo {
+        ServerMessage::Redone {
+            room: room_name,
+            connection_id,
+            op,
+            sequence,
+        }
+    } else {
+        ServerMessage::Undone {
+            room: room_name,
+            connection_id,
+            op,
+            sequence,
+        }
+    };
+    broadcast_op(state, &room, message, Recipients::All).await;
+}
+
+// Send a canvas operation (or its confirmation) within canvas.broadcast_batch_ms
+// Only call this with the room's order lock held, that's what keeps the
+// queue in sequence order
+async fn broadcast_op(state: &AppState, room: &RoomId, message: ServerMessage, to: Recipients) {
+    let window = state.config.lock().await.canvas.broadcast_batch_ms;
+    let first = state.op_batches.push(room, message, to).await;
+    if window == 0 {
+        // Along with whatever is still queued from before a reload
+        flush_ops(state, room).await;
+    } else if first {
+        let (state, room) = (state.clone(), room.clone());
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(Duration::from_millis(window)).await;
+                // Without the order lock, a slow member mustn't hold up drawing
+                flush_ops(&state, &room).await;
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+// Send everything queued for a room, under its delivery lock
+async fn flush_ops(state: &AppState, room: &RoomId) {
+    let delivery = state.canvas.delivery_lock(room).await;
+    let _delivery = match &delivery {
+        Some(delivery) => Some(delivery.lock().await),
+        None => None,
+    };
+    send_queued(state, room).await;
+}
+
+// Only call this with the room's delivery lock held
+async fn send_queued(state: &AppState, room: &RoomId) {
+    let ops = state.op_batches.take(room).await;
+    if !ops.is_empty() {
+        send_op(state, room, ops).await;
+    }
+}
+
+// Each member gets the ones meant for them, a lone one as it is
+async fn send_op(state: &AppState, room: &RoomId, ops: Vec<PendingOp>) {
+    let batch = |messages: Vec<ServerMessage>| match <[ServerMessage; 1]>::try_from(messages) {
+        Ok([message]) => message,
+        Err(ops) => ServerMessage::OpBatch {
+            room: room.to_string(),
+            ops,
+        },
+    };
+    // Most members get all of them, only serialize that once
+    let mut everything = None;
+    for member in state.ws_connections.room_members(room).await {
+        let Some(sender) = state.ws_connections.get(member).await else {
+            continue;
+        };
+        let json = if ops.iter().all(|(_, to)| to.includes(member)) {
+            everything
+                .get_or_insert_with(|| {
+                    batch(ops.iter().map(|(message, _)| message.clone()).collect()).to_json()
+                })
+                .clone()
+        } else {
+            let mine: Vec<ServerMessage> = ops
+                .iter()
+                .filter(|(_, to)| to.includes(member))
+                .map(|(message, _)| message.clone())
+                .collect();
+            if mine.is_empty() {
+                continue;
+            }
+            batch(mine).to_json()
+        };
+        let _ = sender.send_text(json).await;
+    }
+}
+
+// Active rooms and their participant counts - shared by the WS message and GET /rooms
+pub(crate) async fn list_rooms(state: &AppState) -> Vec<RoomInfo> {
+    state
+        .ws_connections
+        .room_occupancy()
+        .await
+        .into_iter()
+        .map(|(room, participants)| RoomInfo {
+            room: room.to_string(),
+            participants,
+        })
+        .collect()
+}
+
+// Move a connection into a room and let everyone involved know
+pub(crate) async fn join_room(state: &AppState, conn_id: ConnectionId, room: RoomId) {
+    let registry = &state.ws_connections;
+    let already_there = registry.room_of(conn_id).await.as_ref() == Some(&room);
+    let limits = {
+        let config = state.config.lock().await;
+        RoomLimits {
+            max_rooms: config.canvas.max_rooms,
+            max_participants: config.canvas.max_participants_per_room,
+        }
+    };
+    // The limits are there to bound what's in memory, so a join they refuse
+    // mustn't load a canvas first
+    if !already_there && let Err(e) = registry.can_join_room(conn_id, &room, limits).await {
+        join_refused(state, conn_id, e).await;
+        return;
+    }
+
+    // Get the canvas in memory first - no point joining a room we can't show
+    if let Err(e) = state.canvas.ensure_loaded(&room, &state.db).await {
+        error!("Failed to load canvas for room {}: {}", room, e);
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    }
+
+    if !already_there {
+        match registry.try_join_room(conn_id, room.clone(), limits).await {
+            Ok(Some(previous)) => {
+                announce_left(state, conn_id, &previous).await;
+                release_if_empty(state, &previous).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                // Someone took the last slot while the canvas loaded, don't keep it around
+                release_if_empty(state, &room).await;
+                join_refused(state, conn_id, e).await;
+                return;
+            }
+        }
+        record_room_metrics(state).await;
+        debug!("Connection {} joined room {}", conn_id, room);
+
+        let joined = ServerMessage::ParticipantJoined {
+            room: room.to_string(),
+            connection_id: conn_id.0,
+        };
+        registry
+            .broadcast_text_to_room(&room, joined.to_json(), Some(conn_id))
+            .await;
+    }
+
+    let participants = registry.room_members(&room).await.len();
+    let confirmation = ServerMessage::RoomJoined {
+        room: room.to_string(),
+        participants,
+    };
+    send_to(state, conn_id, &confirmation).await;
+
+    // Joiners get the current canvas straight away
+    send_snapshot(state, conn_id, &room).await;
+}
+
+// The room's canvas as it is in memory, which already has every applied draw
+async fn send_snapshot(state: &AppState, conn_id: ConnectionId, room: &RoomId) {
+    // Same as for drawing, the canvas may have been evicted since the join
+    if let Err(e) = state.canvas.ensure_loaded(room, &state.db).await {
+        error!("Failed to load canvas for room {}: {}", room, e);
+        send_error(state, conn_id, "Could not load the room canvas").await;
+        return;
+    }
+    // No draw may slip in between taking the snapshot and queueing it
+    let order = state.canvas.order_lock(room).await;
+    let _order = match &order {
+        Some(order) => Some(order.lock().await),
+        None => None,
+    };
+    // Anything still queued is in the snapshot, so it goes out before it
+    let delivery = state.canvas.delivery_lock(room).await;
+    let _delivery = match &delivery {
+        Some(delivery) => Some(delivery.lock().await),
+        None => None,
+    };
+    send_queued(state, room).await;
+    let (objects, ops, sequence) = state.canvas.snapshot(room).await;
+    let snapshot = ServerMessage::StateSnapshot {
+        room: room.to_string(),
+        objects,
+        ops,
+        sequence,
+    };
+    send_to(state, conn_id, &snapshot).await;
+}
+
+// Take a connection out of its room, telling whoever is left
+// Returns the room that was left, None if it wasn't in one
+pub(crate) async fn leave_room(state: &AppState, conn_id: ConnectionId) -> Option<RoomId> {
+    let room = state.ws_connections.leave_room(conn_id).await?;
+    record_room_metrics(state).await;
+    debug!("Connection {} left room {}", conn_id, room);
+    announce_left(state, conn_id, &room).await;
+    release_if_empty(state, &room).await;
+    Some(room)
+}
+
+// Last one out saves the canvas, then it can leave memory
+// For every way out of a room: leaving, disconnecting, moving to another one
+async fn release_if_empty(state: &AppState, room: &RoomId) {
+    if state.ws_connections.room_members(room).await.is_empty() {
+        if let Err(e) = state.canvas.flush_room(room, &state.db).await {
+            error!("Failed to save canvas for room {}: {}", room, e);
+        }
+        state.canvas.evict_if_clean(room).await;
+    }
+}
+
+// Current totals for /metrics, refreshed whenever someone joins or leaves a room
+async fn record_room_metrics(state: &AppState) {
+    let occupancy = state.ws_connections.room_occupancy().await;
+    let participants: usize = occupancy.iter().map(|(_, count)| count).sum();
+    metrics::gauge!("rooms_active").set(occupancy.len() as f64);
+    metrics::gauge!("room_participants").set(participants as f64);
+}
+
+async fn announce_left(state: &AppState, conn_id: ConnectionId, room: &RoomId) {
+    let left = ServerMessage::ParticipantLeft {
+        room: room.to_string(),
+        connection_id: conn_id.0,
+    };
+    state
+        .ws_connections
+        .broadcast_text_to_room(room, left.to_json(), None)
+        .await;
+}
+
+async fn join_refused(state: &AppState, conn_id: ConnectionId, reason: JoinError) {
+    let message = match reason {
+        JoinError::RoomFull => "Room is full",
+        JoinError::TooManyRooms => "Room limit reached, join an existing room",
+    };
+    send_error(state, conn_id, message).await;
+}
+
+async fn send_error(state: &AppState, conn_id: ConnectionId, message: &str) {
+    let error = ServerMessage::Error {
+        message: message.to_string(),
+    };
+    send_to(state, conn_id, &error).await;
+}
+
+// Send to a single connection - it's fine if they already went away
+pub(crate) async fn send_to(state: &AppState, conn_id: ConnectionId, message: &ServerMessage) {
+    if let Some(sender) = state.ws_connections.get(conn_id).await {
+        let _ = sender.send_text(message.to_json()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{TestServer, TestSocket};
+    use protocol::messages::{ClientMessage, DrawOp, ServerMessage, Shape};
+
+    async fn join(socket: &mut TestSocket, room: &str) {
+        socket
+            .send(&ClientMessage::JoinRoom {
+                room: room.to_string(),
+            })
+            .await;
+        assert!(matches!(
+            socket.recv().await,
+            ServerMessage::RoomJoined { .. }
+        ));
+        assert!(matches!(
+            socket.recv().await,
+            ServerMessage::StateSnapshot { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_moving_out_saves_the_emptied_room() {
+        let server = TestServer::start().await;
+        let mut socket = server.client().ws("/ws").await;
+        join(&mut socket, "red").await;
+        socket
+            .send(&ClientMessage::Draw {
+                op: DrawOp {
+                    shape: Shape::Line,
+                    coords: vec![(0.0, 0.0), (1.0, 1.0)],
+                    color: (0, 0, 0),
+                    stroke: 1.0,
+                },
+                base_sequence: None,
+            })
+            .await;
+        assert!(matches!(
+            socket.recv().await,
+            ServerMessage::DrawApplied { .. }
+        ));
+
+        join(&mut socket, "blue").await;
+        let saved = server.state.db.lock().await.load_room_snapshot("red");
+        assert!(saved.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refused_join_loads_nothing() {
+        let mut config = config::Config::default();
+        config.canvas.max_rooms = Some(1);
+        let server = TestServer::with_config(config).await;
+        let client = server.client();
+        let mut alice = client.ws("/ws").await;
+        join(&mut alice, "red").await;
+
+        let mut bob = client.ws("/ws").await;
+        bob.send(&ClientMessage::JoinRoom {
+            room: "blue".to_string(),
+        })
+        .await;
+        assert!(matches!(bob.recv().await, ServerMessage::Error { .. }));
+        let blue = appstate::RoomId::new("blue").unwrap();
+        assert!(server.state.canvas.order_lock(&blue).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stuck_member_doesnt_hold_up_drawing() {
+        let server = TestServer::start().await;
+        let mut socket = server.client().ws("/ws").await;
+        join(&mut socket, "red").await;
+        let red = appstate::RoomId::new("red").unwrap();
+
+        // A member whose queue is full and never drains, waiting for room
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        tx.try_send(axum::extract::ws::Message::Text("".into()))
+            .unwrap();
+        let registry = &server.state.ws_connections;
+        let stuck = registry.register(appstate::MessageSender::new(tx)).await;
+        let limits = appstate::RoomLimits {
+            max_rooms: None,
+            max_participants: None,
+        };
+        registry
+            .try_join_room(stuck, red.clone(), limits)
+            .await
+            .unwrap();
+
+        let draw = ClientMessage::Draw {
+            op: DrawOp {
+                shape: Shape::Line,
+                coords: vec![(0.0, 0.0), (1.0, 1.0)],
+                color: (0, 0, 0),
+                stroke: 1.0,
+            },
+            base_sequence: None,
+        };
+        socket.send(&draw).await;
+        // Long enough for the first flush to get stuck on that member
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        socket.send(&draw).await;
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while server.state.canvas.snapshot(&red).await.2 < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The second draw wasn't applied");
+    }
+}
@@ -0,0 +1,79 @@
+// X-Content-Type-Options, CSP, X-Frame-Options and Referrer-Policy on every response
+// Built once from server.security_headers, config validation already vetted the values
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use config::SecurityHeadersConfig;
+use std::sync::Arc;
+use tracing::*;
+
+#[derive(Clone)]
+pub(crate) struct SecurityHeaders(Arc<Vec<(HeaderName, HeaderValue)>>);
+
+impl SecurityHeaders {
+    // None when disabled
+    pub(crate) fn from_config(config: &SecurityHeadersConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let configured = [
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &config.content_security_policy,
+            ),
+            (header::X_FRAME_OPTIONS, &config.frame_options),
+            (header::REFERRER_POLICY, &config.referrer_policy),
+        ];
+        let mut headers = vec![(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        )];
+        for (name, value) in configured {
+            let Some(value) = value else { continue };
+            match HeaderValue::from_str(value) {
+                Ok(value) => headers.push((name, value)),
+                Err(_) => warn!("Invalid {} value '{}' in config, skipping", name, value),
+            }
+        }
+        Some(Self(Arc::new(headers)))
+    }
+}
+
+// Layered over the whole router
+pub(crate) async fn set_security_headers(
+    State(headers): State<SecurityHeaders>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    for (name, value) in headers.0.iter() {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unset_headers_are_left_out() {
+        let config = SecurityHeadersConfig {
+            frame_options: None,
+            ..SecurityHeadersConfig::default()
+        };
+        let headers = SecurityHeaders::from_config(&config).unwrap();
+        let names: Vec<&HeaderName> = headers.0.iter().map(|(name, _)| name).collect();
+        assert!(names.contains(&&header::X_CONTENT_TYPE_OPTIONS));
+        assert!(!names.contains(&&header::X_FRAME_OPTIONS));
+
+        let disabled = SecurityHeadersConfig {
+            enabled: false,
+            ..SecurityHeadersConfig::default()
+        };
+        assert!(SecurityHeaders::from_config(&disabled).is_none());
+    }
+}
@@ -0,0 +1,295 @@
+// Our own accept loop instead of axum::serve
+// Same setup as axum's (upgrades, HTTP/2 CONNECT for websockets, ConnectInfo),
+// plus the hyper settings axum doesn't expose, like the header read timeout
+// That one runs through our own timer: hyper starts its clock as soon as it
+// waits for the next request, so on a keep-alive connection the idle time in
+// between would count too and cut every idle connection off long before
+// http_idle_timeout_secs. Ours only starts with the first byte of a request
+use axum::Router;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::serve::Listener;
+use hyper::body::Incoming;
+use hyper::rt::{Sleep, Timer};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tower::ServiceExt;
+use tracing::*;
+
+// hyper just drops the connection when the header timeout fires, we send this before closing
+const HEADER_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+pub(crate) async fn serve<L>(mut listener: L, router: Router, header_read_timeout: Option<Duration>)
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    loop {
+        let (io, remote_addr) = listener.accept().await;
+        // Dual-stack sockets report IPv4 clients as ::ffff:a.b.c.d
+        let remote_addr = match remote_addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(remote_addr, |ip| {
+                SocketAddr::new(IpAddr::V4(ip), remote_addr.port())
+            }),
+            IpAddr::V4(_) => remote_addr,
+        };
+        let router = router.clone();
+        tokio::spawn(
+            async move {
+                let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(remote_addr));
+                    router.clone().oneshot(request.map(Body::new))
+                });
+
+                let mut builder = Builder::new(TokioExecutor::new());
+                // CONNECT protocol needed for HTTP/2 websockets
+                builder.http2().enable_connect_protocol();
+                let request_start = RequestStart::default();
+                if let Some(timeout) = header_read_timeout {
+                    builder
+                        .http1()
+                        .timer(HeaderTimer {
+                            timeout,
+                            request_start: request_start.clone(),
+                            armed: Arc::new(AtomicBool::new(false)),
+                        })
+                        .header_read_timeout(timeout);
+                }
+
+                let io = SharedIo {
+                    io: Arc::new(Mutex::new(io)),
+                    request_start: request_start.clone(),
+                };
+                let result = builder
+                    .serve_connection_with_upgrades(TokioIo::new(io.clone()), service)
+                    .await;
+                let Err(e) = result else { return };
+                // Only answer a client that started a request, anything else
+                // could read the 408 as the response to its next one
+                let timed_out = e
+                    .downcast_ref::<hyper::Error>()
+                    .is_some_and(hyper::Error::is_timeout)
+                    && request_start.get().is_some();
+                // hyper is done with the socket by now, unless it got upgraded
+                if timed_out && let Ok(io) = Arc::try_unwrap(io.io) {
+                    debug!("Timed out reading request headers from {}", remote_addr);
+                    let mut io = io.into_inner().unwrap_or_else(|e| e.into_inner());
+                    let _ = io.write_all(HEADER_TIMEOUT_RESPONSE).await;
+                    let _ = io.shutdown().await;
+                } else {
+                    trace!("Connection from {} ended with an error: {}", remote_addr, e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+// When the first byte of the request hyper is waiting for came in
+// Set by the connection's io, cleared whenever hyper starts waiting for the next request
+#[derive(Clone, Default)]
+struct RequestStart(Arc<Mutex<Option<Instant>>>);
+
+impl RequestStart {
+    fn get(&self) -> Option<Instant> {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn mark(&self) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(Instant::now);
+    }
+
+    fn clear(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+// hyper only uses the HTTP/1 timer for the header read timeout, it arms it
+// each time it starts reading a request head
+#[derive(Clone)]
+struct HeaderTimer {
+    timeout: Duration,
+    request_start: RequestStart,
+    // Whether hyper waited for a request on this connection before
+    armed: Arc<AtomicBool>,
+}
+
+impl Timer for HeaderTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Sleep>> {
+        self.sleep_until(Instant::now() + duration)
+    }
+
+    // hyper's deadline counts from now, ours from the request's first byte
+    fn sleep_until(&self, _deadline: Instant) -> Pin<Box<dyn Sleep>> {
+        // Except for the first request, its start may already be in: the
+        // auto builder reads ahead to tell HTTP/1 from HTTP/2
+        if self.armed.swap(true, Ordering::Relaxed) {
+            self.request_start.clear();
+        }
+        Box::pin(HeaderSleep {
+            timeout: self.timeout,
+            request_start: self.request_start.clone(),
+            sleep: None,
+        })
+    }
+}
+
+struct HeaderSleep {
+    timeout: Duration,
+    request_start: RequestStart,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Future for HeaderSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.sleep.is_none() {
+            // Nothing received yet, idle connections are http_idle_timeout_secs' business
+            // hyper polls this again whenever bytes come in, no need to register a wakeup
+            let Some(start) = self.request_start.get() else {
+                return Poll::Pending;
+            };
+            let deadline = tokio::time::Instant::from_std(start + self.timeout);
+            self.sleep = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        }
+        self.sleep
+            .as_mut()
+            .map_or(Poll::Pending, |sleep| sleep.as_mut().poll(cx))
+    }
+}
+
+impl Sleep for HeaderSleep {}
+
+// Keeps a handle on the connection so we can still answer after hyper gives up on it
+// Only hyper touches it while the connection runs, so the lock is never contended
+struct SharedIo<T> {
+    io: Arc<Mutex<T>>,
+    request_start: RequestStart,
+}
+
+impl<T> Clone for SharedIo<T> {
+    fn clone(&self) -> Self {
+        Self {
+            io: self.io.clone(),
+            request_start: self.request_start.clone(),
+        }
+    }
+}
+
+impl<T> SharedIo<T> {
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.io.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for SharedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = self.with(|io| Pin::new(io).poll_read(cx, buf));
+        if buf.filled().len() > before {
+            self.request_start.mark();
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for SharedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.with(|io| Pin::new(io).poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.with(|io| Pin::new(io).poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.with(|io| io.is_write_vectored())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with(|io| Pin::new(io).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with(|io| Pin::new(io).poll_shutdown(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const HEADER_TIMEOUT: Duration = Duration::from_millis(300);
+
+    async fn start() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "hello" }));
+        tokio::spawn(serve(listener, router, Some(HEADER_TIMEOUT)));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_idle_keep_alive_outlives_header_timeout() {
+        let mut stream = TcpStream::connect(start().await).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let read = stream.read(&mut buf).await.unwrap();
+        let first = String::from_utf8_lossy(&buf[..read]);
+        assert!(first.starts_with("HTTP/1.1 200"), "{}", first);
+
+        // Idle between requests, that's not the header timeout's to judge
+        tokio::time::sleep(HEADER_TIMEOUT * 2).await;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut second = String::new();
+        stream.read_to_string(&mut second).await.unwrap();
+        assert!(second.starts_with("HTTP/1.1 200"), "{}", second);
+    }
+
+    #[tokio::test]
+    async fn test_partial_request_gets_408() {
+        let mut stream = TcpStream::connect(start().await).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: ").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(HEADER_TIMEOUT * 4, stream.read_to_string(&mut response))
+            .await
+            .expect("Header timeout didn't fire")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    }
+}
@@ -0,0 +1,64 @@
+// Cache-Control for the embedded frontend files
+// index.html gets its own (short) policy so a new build is picked up straight
+// away, fingerprinted files never change so they can be cached forever
+use appstate::AppState;
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::*;
+
+pub(crate) async fn set_cache_control(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let policy = {
+        let config = state.config.lock().await;
+        let policies = &config.server.static_cache;
+        if path == "/" || path.ends_with("/index.html") {
+            policies.index.clone()
+        } else if is_fingerprinted(&path) {
+            policies.fingerprinted.clone()
+        } else {
+            policies.assets.clone()
+        }
+    };
+    match HeaderValue::from_str(&policy) {
+        Ok(value) => {
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+        Err(_) => warn!(
+            "Invalid Cache-Control policy '{}' in config, skipping",
+            policy
+        ),
+    }
+    response
+}
+
+// Files named like app.3f9a1c2b.js, where the middle part is a content hash
+fn is_fingerprinted(path: &str) -> bool {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let parts: Vec<&str> = file.split('.').collect();
+    parts.len() >= 3
+        && parts[1..parts.len() - 1]
+            .iter()
+            .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_fingerprinted() {
+        assert!(is_fingerprinted("/assets/app.3f9a1c2b.js"));
+        assert!(!is_fingerprinted("/index.js"));
+        assert!(!is_fingerprinted("/jquery.min.js"));
+    }
+}
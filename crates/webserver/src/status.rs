@@ -0,0 +1,43 @@
+// GET /status, what the frontend polls to show notices
+// The maintenance banner is soft: it only informs users, unlike read-only mode
+// it doesn't refuse anything
+use crate::api_message::ApiMessage;
+use appstate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::Json;
+use serde::Serialize;
+use tracing::*;
+
+/// Settings key of the maintenance banner, a string or null when cleared.
+///
+/// Set with `rustcanvas maintenance set`, the running server picks it up on
+/// the next `/status` request.
+pub const MAINTENANCE_BANNER_SETTING: &str = "maintenance_banner";
+
+#[derive(Serialize)]
+pub(crate) struct Status {
+    read_only: bool,
+    maintenance: Option<String>,
+}
+
+pub(crate) async fn get_status(State(state): State<AppState>) -> Result<Json<Status>, ApiMessage> {
+    let banner = state
+        .db
+        .lock()
+        .await
+        .get_setting::<Option<String>>(MAINTENANCE_BANNER_SETTING);
+    match banner {
+        Ok(banner) => Ok(Json(Status {
+            read_only: state.is_read_only(),
+            maintenance: banner.flatten(),
+        })),
+        Err(e) => {
+            warn!("Failed to read the maintenance banner: {}", e);
+            Err(ApiMessage::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Database unavailable",
+            ))
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// End-to-end test harness: the whole server on an OS-picked port with an in-memory database
+// Available to other crates with the test-util feature. Helpers panic instead
+// of returning errors, a failure to talk to the server is a failed test anyway
+use crate::start_webserver;
+use appstate::AppState;
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, Request, StatusCode, header};
+use futures::{SinkExt, StreamExt};
+use hyper_util::rt::TokioIo;
+use protocol::messages::{ClientMessage, ServerMessage};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
+
+// Longer than anything a test should wait for
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running server, stopped when dropped.
+pub struct TestServer {
+    pub state: AppState,
+    pub addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// The default config, listening on localhost only.
+    pub async fn start() -> Self {
+        Self::with_config(config::Config::default()).await
+    }
+
+    /// `config` with its interface and port replaced, so tests never collide,
+    /// and the access log turned off.
+    pub async fn with_config(mut config: config::Config) -> Self {
+        config.server.interface = "127.0.0.1".to_string();
+        config.server.port = 0;
+        config.logging.access_log.enabled = false;
+        let db = db::DatabaseConnection::in_memory().expect("Failed to open an in-memory database");
+        let state = AppState::new(config, db);
+        let task = tokio::spawn(start_webserver(state.clone()));
+        let addr = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                if let Some(addr) = state.bound_addr.get() {
+                    break *addr;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Test server didn't start listening");
+        Self { state, addr, task }
+    }
+
+    pub fn client(&self) -> TestClient {
+        TestClient { addr: self.addr }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Makes requests to a [`TestServer`], each on a connection of its own.
+#[derive(Clone, Copy)]
+pub struct TestClient {
+    addr: SocketAddr,
+}
+
+/// Everything about a response, with the body read to the end.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl TestResponse {
+    /// The body as JSON, panics if it isn't.
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_str(&self.body)
+            .unwrap_or_else(|e| panic!("Response isn't the expected JSON ({}): {}", e, self.body))
+    }
+}
+
+impl TestClient {
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path, None).await
+    }
+
+    pub async fn post_json(&self, path: &str, body: &impl Serialize) -> TestResponse {
+        let body = serde_json::to_string(body).expect("Failed to serialize request body");
+        self.request(Method::POST, path, Some(body)).await
+    }
+
+    /// `POST /register`, 201 when the account was created.
+    pub async fn register(&self, username: &str, password: &str) -> TestResponse {
+        let body = serde_json::json!({ "username": username, "password": password });
+        self.post_json("/register", &body).await
+    }
+
+    /// Any request, `body` is sent as JSON. Responses are asked for as JSON too.
+    pub async fn request(&self, method: Method, path: &str, body: Option<String>) -> TestResponse {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, self.addr.to_string())
+            .header(header::ACCEPT, "application/json");
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let request = request
+            .body(Body::from(body.unwrap_or_default()))
+            .expect("Invalid test request");
+        tokio::time::timeout(TIMEOUT, async {
+            let stream = TcpStream::connect(self.addr)
+                .await
+                .expect("Failed to connect to the test server");
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .expect("HTTP handshake with the test server failed");
+            tokio::spawn(connection);
+            let response = sender
+                .send_request(request)
+                .await
+                .expect("Test request failed");
+            let (parts, body) = response.into_parts();
+            let body = axum::body::to_bytes(Body::new(body), usize::MAX)
+                .await
+                .expect("Failed to read the response body");
+            TestResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            }
+        })
+        .await
+        .expect("Test server didn't answer")
+    }
+
+    /// A WebSocket connection to `path`, e.g. `/ws` or `/ws?room=lobby`.
+    pub async fn ws(&self, path: &str) -> TestSocket {
+        let url = format!("ws://{}{}", self.addr, path);
+        let (stream, _) = tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url))
+            .await
+            .expect("WebSocket handshake timed out")
+            .expect("WebSocket handshake failed");
+        TestSocket { stream }
+    }
+}
+
+/// One WebSocket connection, speaking the JSON protocol.
+pub struct TestSocket {
+    stream: tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+}
+
+impl TestSocket {
+    pub async fn send(&mut self, message: &ClientMessage) {
+        let text = serde_json::to_string(message).expect("Failed to serialize client message");
+        self.stream
+            .send(tungstenite::Message::Text(text.into()))
+            .await
+            .expect("Failed to send on the test socket");
+    }
+
+    /// The next server message, pings and other control frames are skipped.
+    pub async fn recv(&mut self) -> ServerMessage {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                match self.stream.next().await {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        break serde_json::from_str(&text).unwrap_or_else(|e| {
+                            panic!("Not a server message ({}): {}", e, text.as_str())
+                        });
+                    }
+                    Some(Ok(tungstenite::Message::Close(frame))) => {
+                        panic!("Test socket closed by the server: {:?}", frame)
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => panic!("Test socket failed: {}", e),
+                    None => panic!("Test socket closed"),
+                }
+            }
+        })
+        .await
+        .expect("No server message within the timeout")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::messages::{DrawOp, Shape};
+
+    #[tokio::test]
+    async fn test_register_over_http() {
+        let server = TestServer::start().await;
+        let client = server.client();
+        let needed: serde_json::Value = client.get("/setup/needed").await.json();
+        assert_eq!(needed["setup_needed"], true);
+
+        assert_eq!(
+            client.register("alice", "hunter2").await.status,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            client.register("alice", "hunter2").await.status,
+            StatusCode::CONFLICT
+        );
+        let needed: serde_json::Value = client.get("/setup/needed").await.json();
+        assert_eq!(needed["setup_needed"], false);
+    }
+
+    #[tokio::test]
+    async fn test_draw_reaches_the_room() {
+        let server = TestServer::start().await;
+        let client = server.client();
+        let mut alice = client.ws("/ws").await;
+        let mut bob = client.ws("/ws").await;
+        for socket in [&mut alice, &mut bob] {
+            socket
+                .send(&ClientMessage::JoinRoom {
+                    room: "lobby".to_string(),
+                })
+                .await;
+            assert!(matches!(
+                socket.recv().await,
+                ServerMessage::RoomJoined { .. }
+            ));
+            assert!(matches!(
+                socket.recv().await,
+                ServerMessage::StateSnapshot { .. }
+            ));
+        }
+        assert!(matches!(
+            alice.recv().await,
+            ServerMessage::ParticipantJoined { .. }
+        ));
+
+        let op = DrawOp {
+            shape: Shape::Line,
+            coords: vec![(0.0, 0.0), (10.0, 10.0)],
+            color: (0, 0, 0),
+            stroke: 1.0,
+        };
+        alice
+            .send(&ClientMessage::Draw {
+                op,
+                base_sequence: None,
+            })
+            .await;
+        assert!(matches!(
+            alice.recv().await,
+            ServerMessage::DrawApplied { sequence: 1, .. }
+        ));
+        assert!(matches!(
+            bob.recv().await,
+            ServerMessage::Draw { sequence: 1, .. }
+        ));
+    }
+}